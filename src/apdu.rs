@@ -1,79 +1,148 @@
 // src/apdu.rs
-use pcsc::Card;
+use crate::error::{NfcError, StatusWord};
+use crate::transport::CardTransport;
+
+// Send an APDU and split off the status word.
+// Returns the response data on 0x90 0x00, otherwise the decoded status word.
+fn exchange(
+    card: &dyn CardTransport,
+    command: &'static str,
+    apdu: &[u8],
+) -> Result<Vec<u8>, NfcError> {
+    let resp = card.transmit(apdu)?;
+    let sw = StatusWord::from_response(&resp).ok_or(NfcError::MalformedResponse { command })?;
+    if sw.is_success() {
+        Ok(resp[0..resp.len() - 2].to_vec())
+    } else {
+        Err(NfcError::Status { command, sw })
+    }
+}
 
 // Load Authentication Keys into Reader Memory (Location 0x00 or 0x20)
 // ACR122U standard: FF 82 00 key_num 06 [KEY]
-pub fn load_key(card: &Card, key: &[u8; 6]) -> Result<(), String> {
+pub fn load_key(card: &dyn CardTransport, key: &[u8; 6]) -> Result<(), NfcError> {
     let mut apdu = vec![0xFF, 0x82, 0x00, 0x00, 0x06];
     apdu.extend_from_slice(key);
 
-    let mut recv_buffer = [0u8; 256];
-    match card.transmit(&apdu, &mut recv_buffer) {
-        Ok(resp) => {
-            // 0x90 0x00 is Success
-            if resp.len() >= 2 && resp[resp.len() - 2] == 0x90 && resp[resp.len() - 1] == 0x00 {
-                Ok(())
-            } else {
-                Err(format!("Load Key Failed: {:02X?}", resp))
-            }
-        }
-        Err(e) => Err(format!("Transmit Error: {}", e)),
-    }
+    exchange(card, "Load Key", &apdu).map(|_| ())
 }
 
 // Authenticate Block
 // CMD: FF 86 00 00 05 01 00 Block KeyType KeyNumber
 // KeyType: 0x60 (A), 0x61 (B)
-pub fn authenticate(card: &Card, block: u8, key_type: u8) -> Result<(), String> {
+pub fn authenticate(card: &dyn CardTransport, block: u8, key_type: u8) -> Result<(), NfcError> {
     let apdu = [
         0xFF, 0x86, 0x00, 0x00, 0x05, 0x01, 0x00, block, key_type, 0x00,
     ];
 
-    let mut recv_buffer = [0u8; 256];
-    match card.transmit(&apdu, &mut recv_buffer) {
-        Ok(resp) => {
-            if resp.len() >= 2 && resp[resp.len() - 2] == 0x90 && resp[resp.len() - 1] == 0x00 {
-                Ok(())
-            } else {
-                Err("Auth Failed".to_string())
-            }
-        }
-        Err(e) => Err(e.to_string()),
-    }
+    exchange(card, "Auth", &apdu).map(|_| ())
 }
 
-pub fn read_binary(card: &Card, block: u8, length: u8) -> Result<Vec<u8>, String> {
+pub fn read_binary(card: &dyn CardTransport, block: u8, length: u8) -> Result<Vec<u8>, NfcError> {
     // Read: FF B0 00 Block Len
     let apdu = [0xFF, 0xB0, 0x00, block, length];
-    let mut recv_buffer = [0u8; 256];
-
-    match card.transmit(&apdu, &mut recv_buffer) {
-        Ok(resp) => {
-            if resp.len() >= 2 && resp[resp.len() - 2] == 0x90 && resp[resp.len() - 1] == 0x00 {
-                // Return data without status word
-                Ok(resp[0..resp.len() - 2].to_vec())
-            } else {
-                Err("Read Failed".to_string())
-            }
-        }
-        Err(e) => Err(e.to_string()),
-    }
+
+    // Return data without status word
+    exchange(card, "Read", &apdu)
 }
 
-pub fn update_binary(card: &Card, block: u8, data: &[u8]) -> Result<(), String> {
+pub fn update_binary(card: &dyn CardTransport, block: u8, data: &[u8]) -> Result<(), NfcError> {
     // Write: FF D6 00 Block Len [Data]
     let mut apdu = vec![0xFF, 0xD6, 0x00, block, data.len() as u8];
     apdu.extend_from_slice(data);
 
-    let mut recv_buffer = [0u8; 256];
-    match card.transmit(&apdu, &mut recv_buffer) {
-        Ok(resp) => {
-            if resp.len() >= 2 && resp[resp.len() - 2] == 0x90 && resp[resp.len() - 1] == 0x00 {
-                Ok(())
-            } else {
-                Err("Write Failed".to_string())
-            }
-        }
-        Err(e) => Err(e.to_string()),
+    exchange(card, "Write", &apdu).map(|_| ())
+}
+
+// Get UID of the card in the field
+// ACR122U pseudo-APDU: FF CA 00 00 00 (Le = 00 returns the full UID)
+pub fn get_uid(card: &dyn CardTransport) -> Result<Vec<u8>, NfcError> {
+    let apdu = [0xFF, 0xCA, 0x00, 0x00, 0x00];
+
+    exchange(card, "Get UID", &apdu)
+}
+
+// Pass a native tag command through the reader's PN532 (ACR122U direct transmit)
+// CMD: FF 00 00 00 Lc D4 42 [Tag Command]   (D4 42 = InCommunicateThru)
+// Response: D5 43 Status [Tag Response] 90 00, Status 00 = tag answered
+pub fn direct_transmit(
+    card: &dyn CardTransport,
+    command: &'static str,
+    tag_cmd: &[u8],
+) -> Result<Vec<u8>, NfcError> {
+    let mut apdu = vec![
+        0xFF,
+        0x00,
+        0x00,
+        0x00,
+        (tag_cmd.len() + 2) as u8,
+        0xD4,
+        0x42,
+    ];
+    apdu.extend_from_slice(tag_cmd);
+
+    let resp = exchange(card, command, &apdu)?;
+    if resp.len() < 3 || resp[0] != 0xD5 || resp[1] != 0x43 {
+        return Err(NfcError::MalformedResponse { command });
+    }
+    if resp[2] != 0x00 {
+        return Err(NfcError::TagRejected {
+            command,
+            status: resp[2],
+        });
+    }
+    Ok(resp[3..].to_vec())
+}
+
+// NTAG21x / Ultralight EV1 GET_VERSION (0x60), returns the 8 version bytes
+pub fn get_version(card: &dyn CardTransport) -> Result<Vec<u8>, NfcError> {
+    direct_transmit(card, "Get Version", &[0x60])
+}
+
+// NTAG21x / Ultralight EV1 FAST_READ (0x3A): pages `start` to `end` inclusive in one exchange
+pub fn fast_read(card: &dyn CardTransport, start: u8, end: u8) -> Result<Vec<u8>, NfcError> {
+    direct_transmit(card, "Fast Read", &[0x3A, start, end])
+}
+
+// NTAG21x / Ultralight EV1 PWD_AUTH (0x1B): returns the 2-byte PACK, NAK on a wrong password
+pub fn pwd_auth(card: &dyn CardTransport, pwd: &[u8; 4]) -> Result<Vec<u8>, NfcError> {
+    let mut tag_cmd = vec![0x1B];
+    tag_cmd.extend_from_slice(pwd);
+    direct_transmit(card, "Password Auth", &tag_cmd)
+}
+
+// Re-activate the tag after a NAK left it halted
+// CMD: FF 00 00 00 04 D4 4A 01 00 (InListPassiveTarget, 1 target, 106 kbps type A)
+pub fn reselect(card: &dyn CardTransport) -> Result<(), NfcError> {
+    let apdu = [0xFF, 0x00, 0x00, 0x00, 0x04, 0xD4, 0x4A, 0x01, 0x00];
+
+    let resp = exchange(card, "Reselect", &apdu)?;
+    // D5 4B NbTg ...: NbTg = 0 means the tag did not come back
+    if resp.len() < 3 || resp[0] != 0xD5 || resp[1] != 0x4B {
+        return Err(NfcError::MalformedResponse {
+            command: "Reselect",
+        });
+    }
+    if resp[2] == 0 {
+        return Err(NfcError::Transport(
+            crate::error::TransportError::CardRemoved,
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{self, SimModel, SimulatedCard, TestResult};
+
+    #[test]
+    fn get_uid_from_card() -> TestResult {
+        let _serial = sim::serial();
+        let classic = SimulatedCard::mifare_classic_1k();
+        assert_eq!(get_uid(&classic)?, vec![0xDE, 0xAD, 0xBE, 0xEF]);
+        let ntag = SimulatedCard::ntag(SimModel::Ntag215);
+        assert_eq!(get_uid(&ntag)?.len(), 7);
+        Ok(())
     }
 }
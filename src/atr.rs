@@ -0,0 +1,175 @@
+// src/atr.rs
+// Card identification from the ATR the reader synthesises for contactless cards.
+//
+// PC/SC Part 3 storage-card ATR:
+//   3B 8F 80 01 80 4F 0C | A0 00 00 03 06 | SS | C0 C1 | 00 00 00 00 | TCK
+//                          RID (PC/SC)      std  card name  RFU
+// ISO 14443-4 cards (DESFire etc.) instead carry their ATS historical bytes:
+//   3B 8n 80 01 [n historical bytes] TCK
+use std::fmt;
+
+const PCSC_RID: [u8; 5] = [0xA0, 0x00, 0x00, 0x03, 0x06];
+
+// Standard byte (SS)
+const SS_ISO15693_3: u8 = 0x0B;
+const SS_FELICA: u8 = 0x11;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CardKind {
+    MifareClassic1K,
+    MifareClassic4K,
+    MifareMini,
+    // MIFARE Ultralight, Ultralight EV1 and NTAG21x all report card name 00 03
+    Ultralight,
+    UltralightC,
+    Desfire,
+    Felica,
+    Iso15693,
+    Topaz,
+    Unknown,
+}
+
+impl CardKind {
+    // MIFARE Classic family, read and written sector by sector after authentication
+    pub fn is_mifare_classic(self) -> bool {
+        matches!(
+            self,
+            CardKind::MifareClassic1K | CardKind::MifareClassic4K | CardKind::MifareMini
+        )
+    }
+
+    // NFC Forum Type 2 tags read and written page by page
+    pub fn is_type2(self) -> bool {
+        matches!(self, CardKind::Ultralight | CardKind::UltralightC)
+    }
+}
+
+impl fmt::Display for CardKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            CardKind::MifareClassic1K => "MIFARE Classic 1K",
+            CardKind::MifareClassic4K => "MIFARE Classic 4K",
+            CardKind::MifareMini => "MIFARE Mini",
+            CardKind::Ultralight => "MIFARE Ultralight / NTAG",
+            CardKind::UltralightC => "MIFARE Ultralight C",
+            CardKind::Desfire => "MIFARE DESFire",
+            CardKind::Felica => "FeliCa",
+            CardKind::Iso15693 => "ISO 15693",
+            CardKind::Topaz => "Topaz/Jewel",
+            CardKind::Unknown => "unknown card",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+pub fn parse_atr(atr: &[u8]) -> CardKind {
+    // TS = 3B, T0 = 8n (TD1 present, n historical bytes), TD1 = 80, TD2 = 01
+    if atr.len() < 5 || atr[0] != 0x3B || atr[1] & 0xF0 != 0x80 || atr[2] != 0x80 || atr[3] != 0x01
+    {
+        return CardKind::Unknown;
+    }
+    let hist_len = (atr[1] & 0x0F) as usize;
+    let historical = match atr.get(4..4 + hist_len) {
+        Some(h) => h,
+        None => return CardKind::Unknown,
+    };
+
+    // Storage card: 80 4F 0C RID SS C0 C1 ...
+    if historical.len() >= 11
+        && historical[0] == 0x80
+        && historical[1] == 0x4F
+        && historical[3..8] == PCSC_RID
+    {
+        let standard = historical[8];
+        let name = (historical[9], historical[10]);
+        return match (standard, name) {
+            (_, (0x00, 0x01)) => CardKind::MifareClassic1K,
+            (_, (0x00, 0x02)) => CardKind::MifareClassic4K,
+            (_, (0x00, 0x03)) => CardKind::Ultralight,
+            (_, (0x00, 0x26)) => CardKind::MifareMini,
+            (_, (0x00, 0x3A)) => CardKind::UltralightC,
+            (_, (0xF0, 0x04)) => CardKind::Topaz,
+            (SS_FELICA, _) => CardKind::Felica,
+            (SS_ISO15693_3, _) => CardKind::Iso15693,
+            _ => CardKind::Unknown,
+        };
+    }
+
+    // ISO 14443-4: DESFire (EV1/EV2/EV3) ATS historical bytes are the single byte 80
+    if historical == [0x80] {
+        return CardKind::Desfire;
+    }
+    CardKind::Unknown
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{self, SimModel, SimulatedCard, TestResult};
+
+    #[test]
+    fn atr_card_kinds() -> TestResult {
+        let _serial = sim::serial();
+        let storage = |ss: u8, c0: u8, c1: u8| {
+            vec![
+                0x3B, 0x8F, 0x80, 0x01, 0x80, 0x4F, 0x0C, 0xA0, 0x00, 0x00, 0x03, 0x06, ss, c0, c1,
+                0x00, 0x00, 0x00, 0x00, 0x00,
+            ]
+        };
+        let cases = [
+            (storage(0x03, 0x00, 0x01), CardKind::MifareClassic1K),
+            (storage(0x03, 0x00, 0x02), CardKind::MifareClassic4K),
+            (storage(0x03, 0x00, 0x26), CardKind::MifareMini),
+            (storage(0x03, 0x00, 0x03), CardKind::Ultralight),
+            (storage(0x03, 0x00, 0x3A), CardKind::UltralightC),
+            (storage(0x03, 0xF0, 0x04), CardKind::Topaz),
+            (storage(0x11, 0x00, 0x3B), CardKind::Felica),
+            (storage(0x0B, 0x00, 0x00), CardKind::Iso15693),
+            (storage(0x03, 0x00, 0x99), CardKind::Unknown),
+            (vec![0x3B, 0x81, 0x80, 0x01, 0x80, 0x80], CardKind::Desfire),
+            (vec![0x3B, 0x02, 0x14, 0x50], CardKind::Unknown),
+            (vec![], CardKind::Unknown),
+        ];
+        for (atr_bytes, kind) in cases {
+            assert_eq!(parse_atr(&atr_bytes), kind);
+        }
+
+        assert_eq!(
+            parse_atr(&SimulatedCard::mifare_classic_1k().atr()),
+            CardKind::MifareClassic1K,
+        );
+        assert_eq!(
+            parse_atr(&SimulatedCard::ntag(SimModel::Ntag215).atr()),
+            CardKind::Ultralight,
+        );
+
+        for model in [
+            SimModel::MifareMini,
+            SimModel::MifareClassic1K,
+            SimModel::MifareClassic4K,
+        ] {
+            let kind = parse_atr(&SimulatedCard::mifare_classic(model).atr());
+            assert_eq!(kind, model.kind());
+            assert!(kind.is_mifare_classic() && !kind.is_type2());
+        }
+        for model in [
+            SimModel::Ntag213,
+            SimModel::Ultralight,
+            SimModel::UltralightC,
+            SimModel::UltralightEv1,
+        ] {
+            let kind = parse_atr(&SimulatedCard::ntag(model).atr());
+            assert_eq!(kind, model.kind());
+            assert!(kind.is_type2() && !kind.is_mifare_classic());
+        }
+        for kind in [
+            CardKind::Desfire,
+            CardKind::Felica,
+            CardKind::Topaz,
+            CardKind::Unknown,
+        ] {
+            assert!(!kind.is_mifare_classic());
+        }
+        Ok(())
+    }
+}
@@ -1,138 +1,1694 @@
 // src/cards.rs
 use crate::apdu;
-use pcsc::Card;
-
-// Keys from the JS file
-pub const COMMON_KEYS: [[u8; 6]; 8] = [
-    [0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF],
-    [0xA0, 0xA1, 0xA2, 0xA3, 0xA4, 0xA5],
-    [0xD3, 0xF7, 0xD3, 0xF7, 0xD3, 0xF7],
-    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
-    [0xB0, 0xB1, 0xB2, 0xB3, 0xB4, 0xB5],
-    [0x4D, 0x3A, 0x99, 0xC3, 0x51, 0xDD],
-    [0x1A, 0x98, 0x2C, 0x7E, 0x45, 0x9A],
-    [0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0xFF],
-];
-
-// Blocks to read (skipping trailers)
-pub const MIFARE_BLOCKS: [u8; 45] = [
-    4, 5, 6, 8, 9, 10, 12, 13, 14, 16, 17, 18, 20, 21, 22, 24, 25, 26, 28, 29, 30, 32, 33, 34, 36,
-    37, 38, 40, 41, 42, 44, 45, 46, 48, 49, 50, 52, 53, 54, 56, 57, 58, 60, 61, 62,
-];
-
-pub fn read_mifare(card: &Card) -> Result<Vec<u8>, String> {
-    let mut full_data = Vec::new();
+use crate::atr::CardKind;
+use crate::dictionary;
+use crate::error::NfcError;
+use crate::keys::{self, KEY_A, KEY_B, KeyMap};
+use crate::mad;
+use crate::ndef;
+use crate::password;
+use crate::trailer::{self, SectorTrailer};
+use crate::transport::CardTransport;
+use crate::types::WriteStage;
+use log::{info, warn};
+use std::hash::{BuildHasher, RandomState};
+use std::time::{Duration, Instant};
 
-    for &block in MIFARE_BLOCKS.iter() {
-        // Authenticate new sector (every 4th block starting at 0, 4, 8...)
-        if block % 4 == 0 {
-            let mut auth_success = false;
-            // Try to find a working key
-            for key in COMMON_KEYS.iter() {
-                if apdu::load_key(card, key).is_ok() {
-                    // Try Key A (0x60)
-                    if apdu::authenticate(card, block, 0x60).is_ok() {
-                        auth_success = true;
-                        break;
-                    }
-                    // Try Key B (0x61)
-                    if apdu::authenticate(card, block, 0x61).is_ok() {
-                        auth_success = true;
-                        break;
-                    }
-                }
-            }
-            if !auth_success {
-                return Err(format!("Auth failed for sector {}", block / 4));
-            }
+// MIFARE Classic sector geometry.
+// Sectors 0-31 have 4 blocks; sectors 32-39 (4K only) have 16 blocks.
+// The last block of every sector is its trailer (keys + access bits).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClassicLayout {
+    pub sectors: u8,
+}
+
+impl ClassicLayout {
+    pub const MINI: ClassicLayout = ClassicLayout { sectors: 5 };
+    pub const CLASSIC_1K: ClassicLayout = ClassicLayout { sectors: 16 };
+    pub const CLASSIC_4K: ClassicLayout = ClassicLayout { sectors: 40 };
+
+    pub fn for_kind(kind: CardKind) -> Option<Self> {
+        if !kind.is_mifare_classic() {
+            return None;
         }
+        Some(match kind {
+            CardKind::MifareMini => Self::MINI,
+            CardKind::MifareClassic4K => Self::CLASSIC_4K,
+            _ => Self::CLASSIC_1K,
+        })
+    }
 
-        match apdu::read_binary(card, block, 16) {
-            Ok(data) => {
-                // Check if empty (JS logic: if empty, stop)
-                if data.iter().all(|&b| b == 0x00) {
-                    break;
+    pub fn first_block(&self, sector: u8) -> u8 {
+        if sector < 32 {
+            sector * 4
+        } else {
+            128 + (sector - 32) * 16
+        }
+    }
+
+    pub fn blocks_in_sector(&self, sector: u8) -> u8 {
+        if sector < 32 { 4 } else { 16 }
+    }
+
+    pub fn trailer_block(&self, sector: u8) -> u8 {
+        self.first_block(sector) + (self.blocks_in_sector(sector) - 1)
+    }
+
+    pub fn sector_of(&self, block: u8) -> u8 {
+        if block < 128 {
+            block / 4
+        } else {
+            32 + (block - 128) / 16
+        }
+    }
+
+    pub fn is_trailer(&self, block: u8) -> bool {
+        block == self.trailer_block(self.sector_of(block))
+    }
+
+    // Data blocks (trailers skipped) of the sectors in `sectors`
+    pub fn data_blocks(&self, sectors: std::ops::Range<u8>) -> Vec<u8> {
+        sectors
+            .flat_map(|sector| {
+                let first = self.first_block(sector);
+                first..self.trailer_block(sector)
+            })
+            .collect()
+    }
+
+    // Data blocks used for our TLV: everything after the manufacturer sector 0
+    pub fn user_blocks(&self) -> Vec<u8> {
+        self.data_blocks(1..self.sectors)
+    }
+}
+
+// Collapse a card-level rejection into `false`, but keep transport failures
+// (card pulled away, reader gone) as errors so they are not mistaken for a wrong key
+fn card_accepted(res: Result<(), NfcError>) -> Result<bool, NfcError> {
+    match res {
+        Ok(()) => Ok(true),
+        Err(e) if e.is_transport() => Err(e),
+        Err(_) => Ok(false),
+    }
+}
+
+// Open the sector holding `block`, trying each key type in order: first with the
+// key recorded in `keys`, then with the dictionary's candidates. The key that works is recorded.
+pub fn authenticate_sector(
+    card: &dyn CardTransport,
+    layout: &ClassicLayout,
+    keys: &mut KeyMap,
+    block: u8,
+    key_types: &[u8],
+) -> Result<(), NfcError> {
+    let sector = layout.sector_of(block);
+    // Only the preferred remembered key: a Key B that authenticates may still be
+    // refused everything, so it must not jump ahead of a Key A the dictionary would find
+    let remembered = key_types
+        .iter()
+        .find_map(|&key_type| keys.get(sector, key_type).map(|key| (key_type, key)));
+    if let Some((key_type, key)) = remembered {
+        if card_accepted(apdu::load_key(card, &key))?
+            && card_accepted(apdu::authenticate(card, block, key_type))?
+        {
+            return Ok(());
+        }
+        keys.forget(sector, key_type);
+    }
+
+    for key in dictionary::current().candidates(sector) {
+        if card_accepted(apdu::load_key(card, &key))? {
+            for &key_type in key_types {
+                if card_accepted(apdu::authenticate(card, block, key_type))? {
+                    keys.record(sector, key_type, key);
+                    return Ok(());
                 }
-                full_data.extend(data);
             }
-            Err(_) => break, // Stop reading on error
         }
     }
-    Ok(full_data)
+    Err(NfcError::AuthFailed { sector })
 }
 
-pub fn read_ntag(card: &Card) -> Result<Vec<u8>, String> {
+pub fn read_mifare(
+    card: &dyn CardTransport,
+    layout: &ClassicLayout,
+    keys: &mut KeyMap,
+) -> Result<Vec<u8>, NfcError> {
+    read_mifare_blocks(card, layout, keys, &layout.user_blocks())
+}
+
+// Read data blocks in order, authenticating each sector on the way
+pub fn read_mifare_blocks(
+    card: &dyn CardTransport,
+    layout: &ClassicLayout,
+    keys: &mut KeyMap,
+    blocks: &[u8],
+) -> Result<Vec<u8>, NfcError> {
     let mut full_data = Vec::new();
-    // JS reads block 4 to 225
-    for block in 4..226 {
-        // NTAG Read sends 16 bytes usually (4 pages)
+    let mut current_sector = None;
+
+    for &block in blocks {
+        // Authenticate on entering each new sector: Key A, then Key B
+        let sector = layout.sector_of(block);
+        if current_sector != Some(sector) {
+            authenticate_sector(card, layout, keys, block, &[KEY_A, KEY_B])?;
+            current_sector = Some(sector);
+        }
+
         match apdu::read_binary(card, block, 16) {
-            Ok(data) => {
-                if data.iter().all(|&b| b == 0x00) {
-                    break;
-                }
-                full_data.extend(data);
-            }
-            Err(_) => break,
+            Ok(data) => full_data.extend(data),
+            // A vanished card must not look like a short read
+            Err(e) if e.is_transport() => return Err(e),
+            Err(_) => break, // Stop reading on error
+        }
+        // Stop once the TLVs say where the data ends
+        if ndef::tlv_area_end(&full_data).is_some_and(|end| full_data.len() >= end) {
+            break;
         }
     }
     Ok(full_data)
 }
 
-pub fn write_mifare(card: &Card, data: &[u8]) -> Result<(), String> {
-    let mut offset = 0;
-    let mut current_block = 4;
+// Write `data` across data blocks in order, zero-padding the last block
+pub fn write_mifare_blocks(
+    card: &dyn CardTransport,
+    layout: &ClassicLayout,
+    keys: &mut KeyMap,
+    blocks: &[u8],
+    data: &[u8],
+) -> Result<(), NfcError> {
+    let capacity = blocks.len() * 16;
+    if data.len() > capacity {
+        return Err(NfcError::DataTooLarge {
+            needed: data.len(),
+            capacity,
+        });
+    }
+
+    let mut session = WriteSession::default();
+    // Chunking 16 bytes per block, trailers already skipped
+    for (&block, chunk) in blocks.iter().zip(data.chunks(16)) {
+        let mut padded = [0u8; 16]; // Pad with 0s
+        padded[..chunk.len()].copy_from_slice(chunk);
+
+        write_block_in(card, layout, keys, &mut session, block, &padded)?;
+    }
+    Ok(())
+}
 
-    while offset < data.len() {
-        // Skip trailers
-        if (current_block + 1) % 4 == 0 {
-            current_block += 1;
-            continue;
+// Write a single block (data or trailer) with whichever key the access conditions allow
+pub fn write_block_any_key(
+    card: &dyn CardTransport,
+    layout: &ClassicLayout,
+    keys: &mut KeyMap,
+    block: u8,
+    data: &[u8; 16],
+) -> Result<(), NfcError> {
+    write_block_in(
+        card,
+        layout,
+        keys,
+        &mut WriteSession::default(),
+        block,
+        data,
+    )
+}
+
+// Read `blocks` back and check they hold `data` (zero-padded like the write)
+pub fn verify_mifare_blocks(
+    card: &dyn CardTransport,
+    layout: &ClassicLayout,
+    keys: &mut KeyMap,
+    blocks: &[u8],
+    data: &[u8],
+) -> Result<(), NfcError> {
+    let mut current_sector = None;
+    for (&block, chunk) in blocks.iter().zip(data.chunks(16)) {
+        let sector = layout.sector_of(block);
+        if current_sector != Some(sector) {
+            authenticate_sector(card, layout, keys, block, &[KEY_A, KEY_B])?;
+            current_sector = Some(sector);
+        }
+        let mut expected = [0u8; 16];
+        expected[..chunk.len()].copy_from_slice(chunk);
+        if apdu::read_binary(card, block, 16)? != expected {
+            return Err(NfcError::VerifyFailed(format!(
+                "block {} does not hold the written data",
+                block
+            )));
         }
+    }
+    Ok(())
+}
 
-        // Authenticate Sector
-        if current_block % 4 == 0 {
-            let mut auth_success = false;
-            for key in COMMON_KEYS.iter() {
-                if apdu::load_key(card, key).is_ok() {
-                    // We default to trying Key A for write auth usually, or same logic as read
-                    if apdu::authenticate(card, current_block, 0x60).is_ok() {
-                        auth_success = true;
-                        break;
-                    }
-                }
+// What a run of writes knows about the sector it is in
+#[derive(Default)]
+struct WriteSession {
+    sector: Option<u8>,
+    // None when the trailer could not be read
+    trailer: Option<SectorTrailer>,
+    // Key type the card is currently authenticated with
+    authenticated: Option<u8>,
+}
+
+// Key types allowed to write `block` under the sector's access conditions
+fn write_key_types(layout: &ClassicLayout, trailer: &SectorTrailer, block: u8) -> Vec<u8> {
+    let condition = trailer.access.for_block(layout, block);
+    let allowed = |key_type: u8| {
+        if layout.is_trailer(block) {
+            condition.trailer_keys_write().allows(key_type)
+                || condition.trailer_access_write().allows(key_type)
+        } else {
+            condition.data_write().allows(key_type)
+        }
+    };
+    // A Key B that can be read back is plain data and grants nothing
+    let key_b_usable = !trailer.access.trailer().trailer_key_b_readable();
+    [KEY_A, KEY_B]
+        .into_iter()
+        .filter(|&key_type| allowed(key_type) && (key_type == KEY_A || key_b_usable))
+        .collect()
+}
+
+// Authenticate with a key type the access conditions allow (from the trailer, or
+// Key A then Key B when the trailer is unreadable) and write the block.
+// A write refused with 63 00 moves on to the next key type. The sector is only
+// read-only if the card refused the write after authenticating; no working key
+// at all is AuthFailed.
+fn write_block_in(
+    card: &dyn CardTransport,
+    layout: &ClassicLayout,
+    keys: &mut KeyMap,
+    session: &mut WriteSession,
+    block: u8,
+    data: &[u8; 16],
+) -> Result<(), NfcError> {
+    let sector = layout.sector_of(block);
+    if session.sector != Some(sector) {
+        session.trailer = match trailer::read_trailer(card, layout, keys, sector) {
+            Ok(trailer) => Some(trailer),
+            Err(e) if e.is_transport() => return Err(e),
+            Err(_) => None,
+        };
+        session.sector = Some(sector);
+        session.authenticated = None;
+    }
+
+    let mut key_types = match &session.trailer {
+        Some(trailer) => write_key_types(layout, trailer, block),
+        None => vec![KEY_A, KEY_B],
+    };
+    if key_types.is_empty() {
+        return Err(NfcError::SectorReadOnly { sector });
+    }
+    // Stay with the key type already in use when it qualifies
+    if let Some(current) = session.authenticated {
+        key_types.sort_by_key(|&key_type| key_type != current);
+    }
+
+    let mut refused = false;
+    for key_type in key_types {
+        if session.authenticated != Some(key_type) {
+            match authenticate_sector(card, layout, keys, block, &[key_type]) {
+                Ok(()) => session.authenticated = Some(key_type),
+                Err(NfcError::AuthFailed { .. }) => continue,
+                Err(e) => return Err(e),
             }
-            if !auth_success {
-                return Err("Write Auth Failed".to_string());
+        }
+        match apdu::update_binary(card, block, data) {
+            Ok(()) => return Ok(()),
+            // The card drops the authentication after refusing an operation
+            Err(NfcError::Status { .. }) => {
+                session.authenticated = None;
+                refused = true;
             }
+            Err(e) => return Err(e),
         }
+    }
+    if refused {
+        Err(NfcError::SectorReadOnly { sector })
+    } else {
+        Err(NfcError::AuthFailed { sector })
+    }
+}
 
-        // Chunking 16 bytes
-        let bytes_left = data.len() - offset;
-        let copy_len = std::cmp::min(16, bytes_left);
-        let mut chunk = vec![0u8; 16]; // Pad with 0s
-        chunk[0..copy_len].copy_from_slice(&data[offset..offset + copy_len]);
+// NTAG / Ultralight family members we can tell apart
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NtagModel {
+    Ntag213,
+    Ntag215,
+    Ntag216,
+    Ultralight,
+    UltralightEv1,
+    UltralightC,
+    // Answered GET_VERSION with something we do not know; sized from the CC
+    Unknown,
+}
 
-        apdu::update_binary(card, current_block, &chunk)?;
+// First user-memory page on every Type 2 tag (pages 0-3 are UID, lock and CC)
+pub const NTAG_USER_START: u8 = 4;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct NtagInfo {
+    pub model: NtagModel,
+    // Number of 4-byte user-memory pages starting at NTAG_USER_START
+    pub user_pages: u16,
+    // Capability container (page 3)
+    pub cc: [u8; 4],
+}
 
-        offset += 16;
-        current_block += 1;
+impl NtagInfo {
+    pub fn capacity(&self) -> usize {
+        self.user_pages as usize * 4
+    }
+
+    pub fn last_user_page(&self) -> u8 {
+        (NTAG_USER_START as u16 + self.user_pages - 1) as u8
+    }
+
+    // CFG0 page (then CFG1, PWD and PACK) on chips with password protection.
+    // NTAG21x and the larger Ultralight EV1 keep dynamic lock bytes in between.
+    pub fn config_page(&self) -> Option<u8> {
+        match self.model {
+            NtagModel::Ntag213 | NtagModel::Ntag215 | NtagModel::Ntag216 => {
+                Some(self.last_user_page() + 2)
+            }
+            NtagModel::UltralightEv1 if self.user_pages == 12 => Some(self.last_user_page() + 1),
+            NtagModel::UltralightEv1 => Some(self.last_user_page() + 2),
+            _ => None,
+        }
+    }
+
+    // CC size byte (NDEF area / 8) for a formatted tag, as NXP programs it
+    pub fn cc_size(&self) -> u8 {
+        match self.model {
+            NtagModel::Ntag213 => 0x12,
+            NtagModel::Ntag215 => 0x3E,
+            NtagModel::Ntag216 => 0x6D,
+            _ => (self.capacity() / 8).min(0xFF) as u8,
+        }
+    }
+
+    // Dynamic lock bytes (page and the value locking all user pages past page 15).
+    // None on tags whose user memory ends at page 15, or whose layout we do not know:
+    // Ultralight C interleaves block-lock and page-lock bits at page 0x28, so a mask
+    // of consecutive bits would not lock what it should.
+    pub fn dynamic_lock(&self) -> Option<(u8, [u8; 4])> {
+        let pages_per_bit: u32 = match self.model {
+            NtagModel::Ntag213 => 2,
+            NtagModel::Ntag215 | NtagModel::Ntag216 => 16,
+            NtagModel::UltralightEv1 if self.user_pages > 12 => 2,
+            _ => return None,
+        };
+        let bits = (self.last_user_page() as u32 - 15).div_ceil(pages_per_bit);
+        let mask = (1u32 << bits) - 1;
+        Some((
+            self.last_user_page() + 1,
+            [mask as u8, (mask >> 8) as u8, 0x00, 0x00],
+        ))
+    }
+
+    // Pages of the whole chip, configuration pages included
+    pub fn total_pages(&self) -> u16 {
+        match (self.config_page(), self.model) {
+            (Some(cfg0), _) => cfg0 as u16 + 4,
+            // Lock bytes, counter and the 3DES key in pages 0x28-0x2F
+            (None, NtagModel::UltralightC) => 48,
+            (None, _) => self.last_user_page() as u16 + 1,
+        }
+    }
+
+    // NDEF area declared by the CC, if the tag is formatted (magic 0xE1)
+    pub fn cc_capacity(&self) -> Option<usize> {
+        (self.cc[0] == 0xE1).then(|| self.cc[2] as usize * 8)
+    }
+}
+
+// Map GET_VERSION bytes (vendor, type, subtype, major, minor, storage size, protocol)
+// to a model and its user-memory page count
+fn identify_version(version: &[u8]) -> (NtagModel, Option<u16>) {
+    if version.len() < 8 || version[1] != 0x04 {
+        return (NtagModel::Unknown, None);
+    }
+    match (version[2], version[6]) {
+        (0x04, 0x0F) => (NtagModel::Ntag213, Some(36)),
+        (0x04, 0x11) => (NtagModel::Ntag215, Some(126)),
+        (0x04, 0x13) => (NtagModel::Ntag216, Some(222)),
+        // MF0UL11 (48 bytes) and MF0UL21 (128 bytes)
+        (0x03, 0x0B) => (NtagModel::UltralightEv1, Some(12)),
+        (0x03, 0x0E) => (NtagModel::UltralightEv1, Some(32)),
+        _ => (NtagModel::Unknown, None),
     }
-    Ok(())
 }
 
-pub fn write_ntag(card: &Card, data: &[u8]) -> Result<(), String> {
+// Identify the tag with GET_VERSION, falling back to the Ultralight C AUTHENTICATE
+// probe for chips that predate GET_VERSION, then cross-check with the CC in page 3.
+pub fn detect_ntag(card: &dyn CardTransport) -> Result<NtagInfo, NfcError> {
+    let (model, chip_pages) = match apdu::get_version(card) {
+        Ok(version) => identify_version(&version),
+        Err(e) if e.is_transport() => return Err(e),
+        Err(_) => {
+            // Original Ultralight / Ultralight C: the NAK halted the tag, wake it up again
+            apdu::reselect(card)?;
+            // Ultralight C answers AUTHENTICATE part 1 with AF + ek(RndB)
+            let is_ultralight_c = match apdu::direct_transmit(card, "Authenticate", &[0x1A, 0x00]) {
+                Ok(resp) => resp.first() == Some(&0xAF),
+                Err(e) if e.is_transport() => return Err(e),
+                Err(_) => false,
+            };
+            // Either the NAK or the half-finished authentication needs another reselect
+            apdu::reselect(card)?;
+            if is_ultralight_c {
+                (NtagModel::UltralightC, Some(36))
+            } else {
+                (NtagModel::Ultralight, Some(12))
+            }
+        }
+    };
+
+    let page3 = apdu::read_binary(card, 3, 16)?;
+    if page3.len() < 4 {
+        return Err(NfcError::MalformedResponse { command: "Read" });
+    }
+    let cc = [page3[0], page3[1], page3[2], page3[3]];
+
+    let mut info = NtagInfo {
+        model,
+        user_pages: chip_pages.unwrap_or(0),
+        cc,
+    };
+
+    match (chip_pages, info.cc_capacity()) {
+        (None, Some(cc_bytes)) => info.user_pages = (cc_bytes / 4) as u16,
+        (None, None) => {
+            return Err(NfcError::UnsupportedCard(
+                "unknown Type 2 tag without a capability container".into(),
+            ));
+        }
+        (Some(_), Some(cc_bytes)) if cc_bytes > info.capacity() => {
+            // A CC claiming more than the chip has is wrong; the chip size wins
+            warn!(
+                "{:?}: CC declares {} bytes but the chip has {}",
+                model,
+                cc_bytes,
+                info.capacity()
+            );
+        }
+        _ => {}
+    }
+
+    info!("Detected {:?} ({} user bytes)", info.model, info.capacity());
+    Ok(info)
+}
+
+// Pages per FAST_READ, kept well inside the reader's frame size
+const FAST_READ_PAGES: u8 = 32;
+
+pub fn read_ntag(card: &dyn CardTransport) -> Result<Vec<u8>, NfcError> {
+    let tag = detect_ntag(card)?;
+    password::unlock(card, &tag, false)?;
+    let last_page = tag.last_user_page();
+    let mut full_data = Vec::new();
+    // FAST_READ exists on NTAG21x and Ultralight EV1 only
+    let mut fast_read = matches!(
+        tag.model,
+        NtagModel::Ntag213 | NtagModel::Ntag215 | NtagModel::Ntag216 | NtagModel::UltralightEv1
+    );
+    let mut page = NTAG_USER_START;
+
+    while page <= last_page {
+        let chunk = if fast_read {
+            let end = page.saturating_add(FAST_READ_PAGES - 1).min(last_page);
+            match apdu::fast_read(card, page, end) {
+                Ok(data) => data,
+                Err(e) if e.is_transport() => return Err(e),
+                Err(e) => {
+                    // The NAK halted the tag: wake it up and continue with READ
+                    warn!("FAST_READ failed ({}), falling back to READ", e);
+                    apdu::reselect(card)?;
+                    fast_read = false;
+                    continue;
+                }
+            }
+        } else {
+            // NTAG Read returns 16 bytes (4 pages)
+            match apdu::read_binary(card, page, 16) {
+                Ok(data) => data,
+                Err(e) if e.is_transport() => return Err(e),
+                Err(_) => break,
+            }
+        };
+        if chunk.is_empty() || !chunk.len().is_multiple_of(4) {
+            return Err(NfcError::MalformedResponse { command: "Read" });
+        }
+        page = page.saturating_add((chunk.len() / 4) as u8);
+        full_data.extend(chunk);
+        if ndef::tlv_area_end(&full_data).is_some_and(|end| full_data.len() >= end) {
+            break;
+        }
+    }
+    // The last READ may run into config pages (or roll over to page 0)
+    full_data.truncate(tag.capacity());
+    Ok(full_data)
+}
+
+pub fn write_ntag(card: &dyn CardTransport, data: &[u8]) -> Result<(), NfcError> {
+    let tag = detect_ntag(card)?;
+    if data.len() > tag.capacity() {
+        return Err(NfcError::DataTooLarge {
+            needed: data.len(),
+            capacity: tag.capacity(),
+        });
+    }
+    password::unlock(card, &tag, true)?;
+
     // NTAG writes 4 bytes (1 page) at a time
     // Pad to multiple of 4
     let mut padded_data = data.to_vec();
-    while padded_data.len() % 4 != 0 {
+    while !padded_data.len().is_multiple_of(4) {
         padded_data.push(0x00);
     }
 
-    let mut current_block = 4;
-    for chunk in padded_data.chunks(4) {
-        apdu::update_binary(card, current_block, chunk)?;
-        current_block += 1;
+    // Tearing protection (NFC Forum Type 2 Tag): write the NDEF TLV with length 0
+    // first, then the body, and the real length last. A tag pulled away early
+    // holds an empty message instead of a truncated one.
+    let length_size = match ndef::read_tlv_length(&padded_data, 1) {
+        Some((_, size)) if padded_data[0] == ndef::TLV_NDEF => size,
+        _ => {
+            for (page, chunk) in (NTAG_USER_START..).zip(padded_data.chunks(4)) {
+                apdu::update_binary(card, page, chunk)?;
+            }
+            return Ok(());
+        }
+    };
+    let first_page = &padded_data[0..4];
+    let mut empty_first_page = first_page.to_vec();
+    if length_size == 1 {
+        empty_first_page[1] = 0x00;
+    } else {
+        // 3-byte form: FF 00 00
+        empty_first_page[2..4].fill(0x00);
+    }
+
+    apdu::update_binary(card, NTAG_USER_START, &empty_first_page)?;
+    for (page, chunk) in (NTAG_USER_START + 1..).zip(padded_data[4..].chunks(4)) {
+        apdu::update_binary(card, page, chunk)?;
+    }
+    apdu::update_binary(card, NTAG_USER_START, first_page)
+}
+
+// Read the user pages back and check they hold `data` (zero-padded like the write)
+pub fn verify_ntag(card: &dyn CardTransport, data: &[u8]) -> Result<(), NfcError> {
+    for (page, chunk) in (NTAG_USER_START..).step_by(4).zip(data.chunks(16)) {
+        let stored = apdu::read_binary(card, page, 16)?;
+        let mut expected = chunk.to_vec();
+        expected.resize(chunk.len().next_multiple_of(4), 0x00);
+        if stored.get(..expected.len()) != Some(expected.as_slice()) {
+            return Err(NfcError::VerifyFailed(format!(
+                "pages from {} do not hold the written data",
+                page
+            )));
+        }
+    }
+    Ok(())
+}
+
+// How a write should treat the card
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WriteOptions {
+    // Format MIFARE Classic cards without a MAD per the NFC Forum mapping first
+    pub nfc_forum: bool,
+    // Read the written blocks/pages back and compare them with what was sent
+    pub verify: bool,
+}
+
+// Run a MIFARE Classic operation with the key map remembered for this card's UID,
+// saving whatever was learnt even when the operation fails
+pub fn with_card_keys<T>(
+    card: &dyn CardTransport,
+    op: impl FnOnce(&mut KeyMap) -> Result<T, NfcError>,
+) -> Result<T, NfcError> {
+    let uid = apdu::get_uid(card)?;
+    let mut card_keys = keys::cached(&uid);
+    let result = op(&mut card_keys);
+    keys::remember(&uid, card_keys);
+    result
+}
+
+// Read the raw user memory of whatever card kind the ATR identified.
+// Classic cards carrying a MAD are read through their NDEF sectors.
+pub fn read_card(card: &dyn CardTransport, kind: CardKind) -> Result<Vec<u8>, NfcError> {
+    if let Some(layout) = ClassicLayout::for_kind(kind) {
+        with_card_keys(card, |keys| match mad::read_ndef(card, &layout, keys)? {
+            Some(data) => Ok(data),
+            None => read_mifare(card, &layout, keys),
+        })
+    } else if kind.is_type2() {
+        read_ntag(card)
+    } else {
+        Err(NfcError::UnsupportedCard(kind.to_string()))
+    }
+}
+
+// Write `data` (a TLV) to the card, telling `progress` when the writing and the
+// verifying start
+pub fn write_card_reporting(
+    card: &dyn CardTransport,
+    kind: CardKind,
+    data: &[u8],
+    options: &WriteOptions,
+    progress: &dyn Fn(WriteStage),
+) -> Result<(), NfcError> {
+    if let Some(layout) = ClassicLayout::for_kind(kind) {
+        progress(WriteStage::Writing);
+        with_card_keys(card, |keys| {
+            let aids = match mad::read_mad(card, &layout, keys)? {
+                Some(aids) => Some(aids),
+                None if options.nfc_forum => {
+                    info!("Formatting {} for NDEF (MAD)", kind);
+                    mad::format(card, &layout, keys)?;
+                    mad::read_mad(card, &layout, keys)?
+                }
+                None => None,
+            };
+            let blocks = match aids {
+                Some(aids) => mad::ndef_blocks(&layout, &aids),
+                None => layout.user_blocks(),
+            };
+            write_mifare_blocks(card, &layout, keys, &blocks, data)?;
+            if options.verify {
+                progress(WriteStage::Verifying);
+                verify_mifare_blocks(card, &layout, keys, &blocks, data)?;
+            }
+            Ok(())
+        })
+    } else if kind.is_type2() {
+        progress(WriteStage::Writing);
+        write_ntag(card, data)?;
+        if options.verify {
+            progress(WriteStage::Verifying);
+            verify_ntag(card, data)?;
+        }
+        Ok(())
+    } else {
+        Err(NfcError::UnsupportedCard(kind.to_string()))
+    }
+}
+
+// Blocks (MIFARE Classic) or pages (Type 2 tags) a format or erase wrote
+#[derive(Debug, Clone, PartialEq)]
+pub enum Touched {
+    Blocks(Vec<u8>),
+    Pages(Vec<u8>),
+}
+
+// NFC Forum format: capability container for this chip (if it differs) and an
+// empty NDEF message in page 4. Returns the pages written.
+pub fn format_ntag(card: &dyn CardTransport) -> Result<Vec<u8>, NfcError> {
+    let tag = detect_ntag(card)?;
+    password::unlock(card, &tag, true)?;
+    let mut touched = Vec::new();
+
+    // Magic E1, version 1.0, size, read/write access
+    let cc = [0xE1, 0x10, tag.cc_size(), 0x00];
+    if tag.cc != cc {
+        // CC bits are one-time programmable: they can be set but never cleared
+        if tag.cc.iter().zip(&cc).any(|(have, want)| have & !want != 0) {
+            return Err(NfcError::UnsupportedCard(format!(
+                "capability container {} cannot be rewritten",
+                hex::encode_upper(tag.cc)
+            )));
+        }
+        apdu::update_binary(card, 3, &cc)?;
+        touched.push(3);
+    }
+    apdu::update_binary(
+        card,
+        NTAG_USER_START,
+        &[ndef::TLV_NDEF, 0x00, ndef::TLV_TERMINATOR, 0x00],
+    )?;
+    touched.push(NTAG_USER_START);
+    Ok(touched)
+}
+
+// Zero every user page; CC, lock and configuration pages are left alone.
+// Returns the pages written.
+pub fn erase_ntag(card: &dyn CardTransport) -> Result<Vec<u8>, NfcError> {
+    let tag = detect_ntag(card)?;
+    password::unlock(card, &tag, true)?;
+    let pages: Vec<u8> = (NTAG_USER_START..=tag.last_user_page()).collect();
+    for &page in &pages {
+        apdu::update_binary(card, page, &[0x00; 4])?;
+    }
+    Ok(pages)
+}
+
+// Zero the data blocks of sectors 1 and up, keeping trailers (keys and access
+// bits) and the MAD. Returns the blocks written.
+pub fn erase_mifare(
+    card: &dyn CardTransport,
+    layout: &ClassicLayout,
+    keys: &mut KeyMap,
+) -> Result<Vec<u8>, NfcError> {
+    let mut blocks = layout.user_blocks();
+    if mad::read_mad(card, layout, keys)?.is_some_and(|aids| aids.len() > mad::MAD2_SECTOR as usize)
+    {
+        blocks.retain(|&block| layout.sector_of(block) != mad::MAD2_SECTOR);
+    }
+    write_mifare_blocks(card, layout, keys, &blocks, &vec![0x00; blocks.len() * 16])?;
+    Ok(blocks)
+}
+
+// FORMAT_CARD: MAD and NDEF trailers (Classic) or CC (Type 2), plus an empty NDEF message
+pub fn format_card(card: &dyn CardTransport, kind: CardKind) -> Result<Touched, NfcError> {
+    if let Some(layout) = ClassicLayout::for_kind(kind) {
+        with_card_keys(card, |keys| mad::format(card, &layout, keys)).map(Touched::Blocks)
+    } else if kind.is_type2() {
+        format_ntag(card).map(Touched::Pages)
+    } else {
+        Err(NfcError::UnsupportedCard(kind.to_string()))
+    }
+}
+
+// ERASE_CARD: zero user memory, keeping configuration
+pub fn erase_card(card: &dyn CardTransport, kind: CardKind) -> Result<Touched, NfcError> {
+    if let Some(layout) = ClassicLayout::for_kind(kind) {
+        with_card_keys(card, |keys| erase_mifare(card, &layout, keys)).map(Touched::Blocks)
+    } else if kind.is_type2() {
+        erase_ntag(card).map(Touched::Pages)
+    } else {
+        Err(NfcError::UnsupportedCard(kind.to_string()))
+    }
+}
+
+// Make a Type 2 tag read-only for good: CC write access first, then the dynamic
+// lock bytes, and the static lock bytes (which also freeze the CC) last
+pub fn lock_ntag(card: &dyn CardTransport) -> Result<(), NfcError> {
+    let tag = detect_ntag(card)?;
+    let dynamic = tag.dynamic_lock();
+    if dynamic.is_none() && tag.last_user_page() > 15 {
+        return Err(NfcError::UnsupportedCard(format!(
+            "{:?}: dynamic lock bytes unknown",
+            tag.model
+        )));
     }
+    password::unlock(card, &tag, true)?;
+
+    // CC byte 3 = 0F: no write access for NFC Forum readers
+    apdu::update_binary(card, 3, &[0x00, 0x00, 0x00, 0x0F])?;
+    if let Some((page, lock_bytes)) = dynamic {
+        apdu::update_binary(card, page, &lock_bytes)?;
+    }
+    // Page 2 bytes 2-3: lock and block-lock bits of pages 3-15 (bytes 0-1 are not written)
+    apdu::update_binary(card, 2, &[0x00, 0x00, 0xFF, 0xFF])?;
+
+    let pages = apdu::read_binary(card, 2, 16)?;
+    if pages.get(2..4) != Some(&[0xFF, 0xFF][..]) || pages.get(7).is_none_or(|cc| cc & 0x0F != 0x0F)
+    {
+        return Err(NfcError::LockFailed("lock bytes did not change".into()));
+    }
+    if let Some((page, lock_bytes)) = dynamic {
+        let stored = apdu::read_binary(card, page, 16)?;
+        if stored.get(..2) != Some(&lock_bytes[..2]) {
+            return Err(NfcError::LockFailed(
+                "dynamic lock bytes did not change".into(),
+            ));
+        }
+    }
+    info!("{:?} locked read-only", tag.model);
     Ok(())
 }
+
+// Give every sector READ_ONLY access bits. All trailers are checked, and the keys
+// each rewrite needs found, first so a sector that cannot be locked stops the
+// operation before anything changes.
+pub fn lock_mifare(
+    card: &dyn CardTransport,
+    layout: &ClassicLayout,
+    keys: &mut KeyMap,
+) -> Result<(), NfcError> {
+    for sector in 0..layout.sectors {
+        let current = trailer::read_trailer(card, layout, keys, sector)?;
+        if let Some(key_type) = trailer::lock_key_type(&current, sector)? {
+            // The key that authenticates the rewrite, then the keys it writes back
+            trailer::known_key(card, layout, keys, sector, key_type)?;
+            trailer::lock_keys(card, layout, keys, sector, &current, key_type)?;
+        }
+    }
+    for sector in 0..layout.sectors {
+        trailer::lock_sector(card, layout, keys, sector)?;
+    }
+    info!("{} sectors locked read-only", layout.sectors);
+    Ok(())
+}
+
+// Permanently lock the card read-only. Irreversible: only run it for a request
+// confirmed with a LockConfirmation token.
+pub fn lock_card(card: &dyn CardTransport, kind: CardKind) -> Result<(), NfcError> {
+    if let Some(layout) = ClassicLayout::for_kind(kind) {
+        with_card_keys(card, |keys| lock_mifare(card, &layout, keys))
+    } else if kind.is_type2() {
+        lock_ntag(card)
+    } else {
+        Err(NfcError::UnsupportedCard(kind.to_string()))
+    }
+}
+
+// Confirmation for one LOCK_TAG: the token handed out must come back, for the
+// same card, before it expires
+pub struct LockConfirmation {
+    pub token: String,
+    uid: Vec<u8>,
+    issued: Instant,
+}
+
+impl LockConfirmation {
+    pub const VALID_FOR: Duration = Duration::from_secs(60);
+
+    pub fn issue(uid: &[u8]) -> Self {
+        // RandomState is seeded per instance, which is all a one-off token needs
+        let token = RandomState::new().hash_one((uid, Instant::now()));
+        LockConfirmation {
+            token: format!("{:016X}", token),
+            uid: uid.to_vec(),
+            issued: Instant::now(),
+        }
+    }
+
+    pub fn expired(&self) -> bool {
+        self.issued.elapsed() >= Self::VALID_FOR
+    }
+
+    pub fn confirms(&self, token: &str, uid: &[u8]) -> bool {
+        !self.expired() && self.token.eq_ignore_ascii_case(token) && self.uid == uid
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::apdu;
+    use crate::atr::{self, CardKind};
+    use crate::error::{NfcError, TransportError};
+    use crate::keys::KeyMap;
+    use crate::mad;
+    use crate::ndef;
+    use crate::password::{self, ProtectMode};
+    use crate::sim::{
+        self, SimModel, SimulatedCard, TestResult, provision_sector_1, with_secret, write_card,
+        write_spec,
+    };
+    use crate::trailer::{self, AccessBits, AccessCondition, SectorTrailer};
+    use crate::types::{IncomingMessage, OutgoingMessage};
+
+    #[test]
+    fn unsupported_card() -> TestResult {
+        let _serial = sim::serial();
+        let card = SimulatedCard::ntag(SimModel::Ntag215);
+        for kind in [CardKind::Desfire, CardKind::Felica, CardKind::Unknown] {
+            match read_card(&card, kind) {
+                Ok(_) => return Err(format!("{} read as if supported", kind).into()),
+                Err(e) => assert_eq!(e.code(), "UNSUPPORTED_CARD"),
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn mifare_write_read() -> TestResult {
+        let _serial = sim::serial();
+        let (card, kind) = sim::card_with_text(SimModel::MifareClassic1K, "EMP-0001")?;
+        let raw = read_card(&card, kind)?;
+        assert_eq!(ndef::decode_ndef_content(&raw)?, "EMP-0001".to_string());
+        Ok(())
+    }
+
+    #[test]
+    fn mifare_multi_sector_write_read() -> TestResult {
+        let _serial = sim::serial();
+        // Long enough to cross the sector 1 trailer at block 7
+        let card = SimulatedCard::mifare_classic_1k();
+        let layout = ClassicLayout::CLASSIC_1K;
+        let mut keys = KeyMap::default();
+        let user_id = "a-much-longer-user-identifier-spanning-sectors";
+        let tlv = sim::text_tlv(user_id);
+        write_mifare_blocks(&card, &layout, &mut keys, &layout.user_blocks(), &tlv)?;
+        let raw = read_mifare(&card, &layout, &mut keys)?;
+        assert_eq!(ndef::decode_ndef_content(&raw)?, user_id.to_string());
+        Ok(())
+    }
+
+    #[test]
+    fn mifare_unknown_key() -> TestResult {
+        let _serial = sim::serial();
+        let card = SimulatedCard::mifare_classic_1k();
+        let layout = ClassicLayout::CLASSIC_1K;
+        let mut keys = KeyMap::default();
+        let tlv = sim::text_tlv("x");
+        write_mifare_blocks(&card, &layout, &mut keys, &layout.user_blocks(), &tlv)?;
+        // Sector 1 with keys outside the dictionary must refuse to authenticate
+        let mut trailer = [0x13u8; 16];
+        trailer[6..10].copy_from_slice(&[0xFF, 0x07, 0x80, 0x69]);
+        apdu::load_key(&card, &[0xFF; 6])?;
+        apdu::authenticate(&card, 7, KEY_A)?;
+        apdu::update_binary(&card, 7, &trailer)?;
+        match read_mifare(&card, &layout, &mut keys) {
+            Ok(_) => return Err("read succeeded without a valid key".into()),
+            Err(e) => assert_eq!(e.code(), "WRONG_KEY"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn mifare_unknown_key_write() -> TestResult {
+        let _serial = sim::serial();
+        let card = SimulatedCard::mifare_classic_1k();
+        let layout = ClassicLayout::CLASSIC_1K;
+        let mut keys = KeyMap::default();
+        // Sector 1 with keys outside the dictionary: the write cannot authenticate,
+        // which is not the same as a read-only sector
+        let mut trailer = [0x13u8; 16];
+        trailer[6..10].copy_from_slice(&[0xFF, 0x07, 0x80, 0x69]);
+        apdu::load_key(&card, &[0xFF; 6])?;
+        apdu::authenticate(&card, 7, KEY_A)?;
+        apdu::update_binary(&card, 7, &trailer)?;
+        let tlv = sim::text_tlv(&"w".repeat(40));
+        assert_eq!(
+            write_mifare_blocks(&card, &layout, &mut keys, &layout.user_blocks(), &tlv),
+            Err(NfcError::AuthFailed { sector: 1 }),
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn mifare_geometry() -> TestResult {
+        let k4 = ClassicLayout::CLASSIC_4K;
+        assert_eq!(k4.first_block(31), 124);
+        assert_eq!(k4.first_block(32), 128);
+        assert_eq!(k4.trailer_block(32), 143);
+        assert_eq!(k4.trailer_block(39), 255);
+        assert_eq!(k4.sector_of(200), 36);
+        assert!(k4.is_trailer(143));
+        assert!(!k4.is_trailer(139));
+        // 31 small sectors * 3 + 8 large sectors * 15 data blocks
+        assert_eq!(k4.user_blocks().len(), 31 * 3 + 8 * 15);
+        assert_eq!(ClassicLayout::CLASSIC_1K.user_blocks().len(), 45);
+        assert_eq!(ClassicLayout::MINI.user_blocks().len(), 12);
+        Ok(())
+    }
+
+    #[test]
+    fn mifare_4k_write_read() -> TestResult {
+        let _serial = sim::serial();
+        // Long enough to spill into the 16-block sectors above sector 31
+        let card = SimulatedCard::mifare_classic(SimModel::MifareClassic4K);
+        let kind = atr::parse_atr(&card.atr());
+        assert_eq!(kind, CardKind::MifareClassic4K);
+        let user_id = "4".repeat(2000);
+        sim::write_text(&card, &user_id)?;
+        assert_eq!(
+            ndef::decode_ndef_content(&read_card(&card, kind)?)?,
+            user_id
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn mifare_mini_write_read() -> TestResult {
+        let _serial = sim::serial();
+        let card = SimulatedCard::mifare_classic(SimModel::MifareMini);
+        let kind = atr::parse_atr(&card.atr());
+        assert_eq!(kind, CardKind::MifareMini);
+        sim::write_text(&card, &"m".repeat(150))?;
+        assert_eq!(
+            ndef::decode_ndef_content(&read_card(&card, kind)?)?,
+            "m".repeat(150),
+        );
+
+        let tlv = sim::text_tlv(&"m".repeat(250));
+        match write_card(&card, kind, &tlv, &WriteOptions::default()) {
+            Ok(()) => return Err("oversized write accepted on Mini".into()),
+            Err(e) => assert_eq!(e.code(), "DATA_TOO_LARGE"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn mifare_card_removed() -> TestResult {
+        let _serial = sim::serial();
+        let card = SimulatedCard::mifare_classic_1k();
+        let mut keys = KeyMap::default();
+        card.remove();
+        match read_mifare(&card, &ClassicLayout::CLASSIC_1K, &mut keys) {
+            Ok(_) => return Err("read succeeded with no card present".into()),
+            Err(e) => assert_eq!(e, NfcError::Transport(TransportError::CardRemoved)),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn mifare_key_b_write() -> TestResult {
+        let _serial = sim::serial();
+        // Typical provisioned badge: Key A reads, only Key B writes
+        let card = SimulatedCard::new(SimModel::MifareClassic1K, &[0x15, 0x00, 0x00, 0x01]);
+        let layout = ClassicLayout::CLASSIC_1K;
+        let key_a = [0xA0, 0xA1, 0xA2, 0xA3, 0xA4, 0xA5];
+        let key_b = [0xB0, 0xB1, 0xB2, 0xB3, 0xB4, 0xB5];
+        provision_sector_1(&card, key_a, 0b100, 0b011, key_b)?;
+
+        let mut keys = KeyMap::default();
+        let tlv = sim::text_tlv("key-b");
+        write_mifare_blocks(&card, &layout, &mut keys, &layout.user_blocks(), &tlv)?;
+        assert_eq!(keys.get(1, KEY_B), Some(key_b));
+        let raw = read_mifare(&card, &layout, &mut keys)?;
+        assert_eq!(ndef::decode_ndef_content(&raw)?, "key-b".to_string());
+
+        // Key A alone cannot write here
+        apdu::load_key(&card, &key_a)?;
+        apdu::authenticate(&card, 4, KEY_A)?;
+        if apdu::update_binary(&card, 4, &[0u8; 16]).is_ok() {
+            return Err("Key A write accepted".into());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn mifare_read_only_sectors() -> TestResult {
+        let _serial = sim::serial();
+        let layout = ClassicLayout::CLASSIC_1K;
+        let tlv = sim::text_tlv("read-only");
+        let read_only = NfcError::SectorReadOnly { sector: 1 };
+        let no_key = NfcError::AuthFailed { sector: 1 };
+        let cases = [
+            // Data blocks that no key may write
+            (
+                [0xFF; 6],
+                0b010,
+                0b011,
+                [0xB0, 0xB1, 0xB2, 0xB3, 0xB4, 0xB5],
+                read_only,
+            ),
+            // Writable with Key B, but Key B is not in the dictionary
+            ([0xFF; 6], 0b100, 0b011, [0x42; 6], no_key.clone()),
+            // Key A unknown and Key B readable, so Key B grants nothing
+            ([0x42; 6], 0b000, 0b001, [0xFF; 6], no_key),
+        ];
+        for (i, (key_a, data, trailer_bits, key_b, expected)) in cases.into_iter().enumerate() {
+            let card = SimulatedCard::new(SimModel::MifareClassic1K, &[0x15, 0x00, 0x01, i as u8]);
+            provision_sector_1(&card, key_a, data, trailer_bits, key_b)?;
+            let mut keys = KeyMap::default();
+            match write_mifare_blocks(&card, &layout, &mut keys, &layout.user_blocks(), &tlv) {
+                Ok(()) => return Err(format!("case {}: write accepted", i).into()),
+                Err(e) => assert_eq!(e, expected, "case {}", i),
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn zero_blocks_in_payload() -> TestResult {
+        let _serial = sim::serial();
+        let mut payload = b"before".to_vec();
+        payload.extend([0u8; 20]);
+        payload.extend(b"after");
+        let message = ndef::encode_ndef_records(&[ndef::NdefRecord::mime("a/b", payload.clone())]);
+        let tlv = ndef::wrap_in_tlv(&message);
+        let cards_under_test = [
+            SimulatedCard::new(SimModel::MifareClassic1K, &[0x18, 0x00, 0x00, 0x01]),
+            SimulatedCard::ntag(SimModel::Ntag213),
+            SimulatedCard::ntag(SimModel::Ultralight),
+        ];
+        for card in cards_under_test {
+            let kind = atr::parse_atr(&card.atr());
+            write_card(&card, kind, &tlv, &WriteOptions::default())?;
+            let raw = read_card(&card, kind)?;
+            let records = ndef::parse_ndef_message(ndef::find_ndef_tlv(&raw)?)?;
+            assert_eq!(&records[0].payload, &payload);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn reads_stop_at_tlv_end() -> TestResult {
+        let _serial = sim::serial();
+        // A short message over a longer stale one: the read ends with the new TLV
+        let short = sim::text_tlv("new");
+        let stale = sim::text_tlv(&"stale".repeat(30));
+        let cards_under_test = [
+            SimulatedCard::new(SimModel::MifareClassic1K, &[0x18, 0x00, 0x00, 0x02]),
+            SimulatedCard::ntag(SimModel::Ntag215),
+        ];
+        for card in cards_under_test {
+            let kind = atr::parse_atr(&card.atr());
+            write_card(&card, kind, &stale, &WriteOptions::default())?;
+            write_card(&card, kind, &short, &WriteOptions::default())?;
+            let raw = read_card(&card, kind)?;
+            assert_eq!(ndef::decode_ndef_content(&raw)?, "new".to_string());
+            if raw.len() >= stale.len() {
+                return Err(format!(
+                    "{}: read {} bytes for a {}-byte TLV",
+                    kind,
+                    raw.len(),
+                    short.len()
+                )
+                .into());
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn ntag216_fast_read() -> TestResult {
+        let _serial = sim::serial();
+        let card = SimulatedCard::ntag(SimModel::Ntag216);
+        let user_id = "F".repeat(850);
+        let tlv = sim::text_tlv(&user_id);
+        write_ntag(&card, &tlv)?;
+        let before = card.exchanges();
+        let raw = read_ntag(&card)?;
+        let exchanges = card.exchanges() - before;
+        assert_eq!(ndef::decode_ndef_content(&raw)?, user_id);
+        // Detection plus 7 FAST_READs instead of 56 READs
+        if exchanges > 12 {
+            return Err(format!("{} exchanges to read {} bytes", exchanges, tlv.len()).into());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn ntag_torn_write() -> TestResult {
+        let _serial = sim::serial();
+        // Short (1-byte length) and long (3-byte length) TLVs
+        for new_text in ["replacement".repeat(3), "L".repeat(300)] {
+            let tlv = sim::text_tlv(&new_text);
+            let pages = tlv.len().div_ceil(4);
+            for kept in 1..=pages {
+                let card = SimulatedCard::ntag(SimModel::Ntag215);
+                let old = sim::text_tlv(&"old".repeat(40));
+                write_ntag(&card, &old)?;
+
+                // The tag leaves after `kept` page writes, before the real length lands
+                card.lose_writes_after(kept);
+                write_ntag(&card, &tlv)?;
+                let raw = read_ntag(&card)?;
+                if !ndef::find_ndef_tlv(&raw)?.is_empty() {
+                    return Err(format!(
+                        "{} of {} pages left a non-empty message",
+                        kept,
+                        pages + 1
+                    )
+                    .into());
+                }
+            }
+
+            // All pages plus the final length write: the complete new message
+            let card = SimulatedCard::ntag(SimModel::Ntag215);
+            card.lose_writes_after(pages + 1);
+            write_ntag(&card, &tlv)?;
+            assert_eq!(ndef::decode_ndef_content(&read_ntag(&card)?)?, new_text);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn write_verification() -> TestResult {
+        let _serial = sim::serial();
+        let options = WriteOptions {
+            verify: true,
+            ..WriteOptions::default()
+        };
+        let spec = write_spec(
+            r#"{"type":"WRITE_DATA","data_type":"text","user_id":"EMP-0042","verify":true}"#,
+        )?;
+        assert!(spec.verify);
+        let message = spec.to_ndef_message()?;
+        let tlv = ndef::wrap_in_tlv(&message);
+        let cards_under_test = [
+            SimulatedCard::mifare_classic_1k(),
+            SimulatedCard::mifare_classic(SimModel::MifareClassic4K),
+            SimulatedCard::ntag(SimModel::Ntag213),
+            SimulatedCard::ntag(SimModel::Ultralight),
+        ];
+        for card in cards_under_test {
+            let kind = atr::parse_atr(&card.atr());
+            write_card(&card, kind, &tlv, &options)?;
+            spec.check_read_back(&read_card(&card, kind)?, &message)?;
+        }
+
+        // Read-back checks against the request itself
+        let other = sim::text_tlv("EMP-0043");
+        match spec.check_read_back(&other, &message) {
+            Ok(()) => return Err("different message passed the read-back check".into()),
+            Err(e) => assert_eq!(e.code(), "WRITE_VERIFY_FAILED"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn write_verification_lost_writes() -> TestResult {
+        let _serial = sim::serial();
+        let options = WriteOptions {
+            verify: true,
+            ..WriteOptions::default()
+        };
+        let tlv = sim::text_tlv(&"v".repeat(60));
+        let cards_under_test = [
+            SimulatedCard::new(SimModel::MifareClassic1K, &[0x16, 0x00, 0x00, 0x01]),
+            SimulatedCard::ntag(SimModel::Ntag215),
+        ];
+        for card in cards_under_test {
+            let kind = atr::parse_atr(&card.atr());
+            card.lose_writes_after(2);
+            match write_card(&card, kind, &tlv, &options) {
+                Ok(()) => return Err(format!("{}: lost writes went unnoticed", kind).into()),
+                Err(e) => assert_eq!(e.code(), "WRITE_VERIFY_FAILED"),
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn ntag_model_detection() -> TestResult {
+        let _serial = sim::serial();
+        let cases = [
+            (SimModel::Ntag213, NtagModel::Ntag213, 144),
+            (SimModel::Ntag215, NtagModel::Ntag215, 504),
+            (SimModel::Ntag216, NtagModel::Ntag216, 888),
+            (SimModel::Ultralight, NtagModel::Ultralight, 48),
+            (SimModel::UltralightC, NtagModel::UltralightC, 144),
+            (SimModel::UltralightEv1, NtagModel::UltralightEv1, 48),
+        ];
+        for (sim_model, model, capacity) in cases {
+            let card = SimulatedCard::ntag(sim_model);
+            let tag = detect_ntag(&card)?;
+            assert_eq!(tag.model, model);
+            assert_eq!(tag.capacity(), capacity);
+            // Detection must leave the tag usable (Ultralight probes NAK and halt it)
+            assert_eq!(tag.cc[0], 0xE1);
+            let tlv = sim::text_tlv("fits");
+            write_ntag(&card, &tlv)?;
+            assert_eq!(
+                ndef::decode_ndef_content(&read_ntag(&card)?)?,
+                "fits".to_string()
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn ntag213_too_large() -> TestResult {
+        let _serial = sim::serial();
+        let card = SimulatedCard::ntag(SimModel::Ntag213);
+        let tlv = sim::text_tlv(&"y".repeat(200));
+        match write_ntag(&card, &tlv) {
+            Ok(()) => return Err("oversized write accepted".into()),
+            Err(e) => assert_eq!(e.code(), "DATA_TOO_LARGE"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn ntag_write_read() -> TestResult {
+        let _serial = sim::serial();
+        for model in [SimModel::Ntag213, SimModel::Ntag215, SimModel::Ntag216] {
+            let card = SimulatedCard::ntag(model);
+            let tlv = sim::text_tlv("EMP-0002");
+            write_ntag(&card, &tlv)?;
+            let raw = read_ntag(&card)?;
+            assert_eq!(ndef::decode_ndef_content(&raw)?, "EMP-0002".to_string());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn ntag_long_write_read() -> TestResult {
+        let _serial = sim::serial();
+        let card = SimulatedCard::ntag(SimModel::Ntag215);
+        let user_id = "x".repeat(100);
+        let tlv = sim::text_tlv(&user_id);
+        write_ntag(&card, &tlv)?;
+        let raw = read_ntag(&card)?;
+        assert_eq!(ndef::decode_ndef_content(&raw)?, user_id);
+        Ok(())
+    }
+
+    // Writes to a locked card fail; reads still return `expected`
+    fn expect_locked(card: &SimulatedCard, expected: &str) -> TestResult {
+        let kind = atr::parse_atr(&card.atr());
+        assert_eq!(
+            ndef::decode_ndef_content(&read_card(card, kind)?)?,
+            expected.to_string()
+        );
+        apdu::reselect(card)?;
+        let tlv = sim::text_tlv("overwritten");
+        if write_card(card, kind, &tlv, &WriteOptions::default()).is_ok() {
+            return Err(format!("{}: write accepted after locking", kind).into());
+        }
+        apdu::reselect(card)?;
+        assert_eq!(
+            ndef::decode_ndef_content(&read_card(card, kind)?)?,
+            expected.to_string()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn ntag_lock() -> TestResult {
+        let _serial = sim::serial();
+        for model in [
+            SimModel::Ntag213,
+            SimModel::Ntag216,
+            SimModel::UltralightEv1,
+        ] {
+            let card = SimulatedCard::ntag(model);
+            let kind = atr::parse_atr(&card.atr());
+            // Long enough to reach the dynamically locked pages
+            let user_id = "L".repeat(if model == SimModel::UltralightEv1 {
+                20
+            } else {
+                100
+            });
+            sim::write_text(&card, &user_id)?;
+            lock_card(&card, kind)?;
+            // CC now says read-only
+            assert_eq!(
+                apdu::read_binary(&card, 3, 4)?,
+                vec![0xE1, 0x10, detect_ntag(&card)?.cc[2], 0x0F]
+            );
+            expect_locked(&card, &user_id)?;
+            // Pages past 15 are covered by the dynamic lock bits
+            apdu::reselect(&card)?;
+            if model != SimModel::UltralightEv1
+                && apdu::update_binary(&card, 20, &[0x00; 4]).is_ok()
+            {
+                return Err(format!("{:?}: page 20 still writable", model).into());
+            }
+        }
+
+        // Ultralight C lock bits are not modelled: refused before anything is written
+        let card = SimulatedCard::ntag(SimModel::UltralightC);
+        let kind = atr::parse_atr(&card.atr());
+        let cc = apdu::read_binary(&card, 3, 4)?;
+        assert_eq!(
+            lock_card(&card, kind).map_err(|e| e.code()),
+            Err("UNSUPPORTED_CARD")
+        );
+        assert_eq!(apdu::read_binary(&card, 3, 4)?, cc);
+        assert_eq!(apdu::read_binary(&card, 2, 4)?[2..], [0x00, 0x00]);
+        Ok(())
+    }
+
+    #[test]
+    fn ntag_lock_protected() -> TestResult {
+        let _serial = sim::serial();
+        let card = SimulatedCard::ntag(SimModel::Ntag215);
+        let kind = atr::parse_atr(&card.atr());
+        with_secret(b"deployment", || {
+            sim::write_text(&card, "protected")?;
+            password::set_protection(&card, ProtectMode::Write, 4)?;
+            Ok(())
+        })?;
+        assert_eq!(lock_card(&card, kind), Err(NfcError::PasswordRequired));
+        with_secret(b"deployment", || lock_card(&card, kind).map_err(Into::into))?;
+        with_secret(b"deployment", || expect_locked(&card, "protected"))
+    }
+
+    #[test]
+    fn mifare_lock() -> TestResult {
+        let _serial = sim::serial();
+        let card = SimulatedCard::new(SimModel::MifareClassic1K, &[0x20, 0x00, 0x00, 0x01]);
+        let kind = atr::parse_atr(&card.atr());
+        let layout = ClassicLayout::CLASSIC_1K;
+        // Sector 1 only lets Key B change its access bits
+        let key_a = [0xA0, 0xA1, 0xA2, 0xA3, 0xA4, 0xA5];
+        let key_b = [0xB0, 0xB1, 0xB2, 0xB3, 0xB4, 0xB5];
+        provision_sector_1(&card, key_a, 0b100, 0b011, key_b)?;
+        sim::write_text(&card, &"m".repeat(80))?;
+
+        lock_card(&card, kind)?;
+        let mut keys = KeyMap::default();
+        for sector in 0..layout.sectors {
+            assert_eq!(
+                trailer::read_trailer(&card, &layout, &mut keys, sector)?.access,
+                AccessBits::READ_ONLY
+            );
+        }
+        // The keys did not change
+        apdu::load_key(&card, &key_a)?;
+        apdu::authenticate(&card, 4, KEY_A)?;
+        apdu::load_key(&card, &key_b)?;
+        apdu::authenticate(&card, 4, KEY_B)?;
+        expect_locked(&card, &"m".repeat(80))?;
+        // Locking again is a no-op
+        lock_card(&card, kind)?;
+        Ok(())
+    }
+
+    #[test]
+    fn mifare_lock_frozen_sector() -> TestResult {
+        let _serial = sim::serial();
+        let card = SimulatedCard::new(SimModel::MifareClassic1K, &[0x20, 0x00, 0x00, 0x02]);
+        let kind = atr::parse_atr(&card.atr());
+        let layout = ClassicLayout::CLASSIC_1K;
+        // Sector 5: data writable, access bits frozen (written directly, past the lock guard)
+        let frozen = SectorTrailer {
+            key_a: [0xFF; 6],
+            access: AccessBits([
+                AccessCondition(0b000),
+                AccessCondition(0b000),
+                AccessCondition(0b000),
+                AccessCondition(0b100),
+            ]),
+            gpb: 0x69,
+            key_b: [0xFF; 6],
+        };
+        apdu::load_key(&card, &[0xFF; 6])?;
+        apdu::authenticate(&card, 23, KEY_A)?;
+        apdu::update_binary(&card, 23, &frozen.to_bytes())?;
+
+        match lock_card(&card, kind) {
+            Err(NfcError::LockFailed(_)) => {}
+            other => return Err(format!("expected LOCK_FAILED, got {:?}", other).into()),
+        }
+        // Nothing was locked
+        let mut keys = KeyMap::default();
+        assert_eq!(
+            trailer::read_trailer(&card, &layout, &mut keys, 1)?.access,
+            sim::TRANSPORT_ACCESS
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn mifare_lock_unknown_key() -> TestResult {
+        let _serial = sim::serial();
+        let card = SimulatedCard::new(SimModel::MifareClassic1K, &[0x20, 0x00, 0x00, 0x03]);
+        let kind = atr::parse_atr(&card.atr());
+        let layout = ClassicLayout::CLASSIC_1K;
+        // Sector 5: Key B rewrites the trailer, Key A is in no dictionary but must be written back
+        let trailer = SectorTrailer {
+            key_a: [0x42; 6],
+            access: AccessBits([
+                AccessCondition(0b000),
+                AccessCondition(0b000),
+                AccessCondition(0b000),
+                AccessCondition(0b011),
+            ]),
+            gpb: 0x69,
+            key_b: [0xFF; 6],
+        };
+        apdu::load_key(&card, &[0xFF; 6])?;
+        apdu::authenticate(&card, 23, KEY_A)?;
+        apdu::update_binary(&card, 23, &trailer.to_bytes())?;
+
+        assert_eq!(
+            lock_card(&card, kind),
+            Err(NfcError::AuthFailed { sector: 5 })
+        );
+        // Nothing was locked
+        let mut keys = KeyMap::default();
+        for sector in [0, 1, 4] {
+            assert_eq!(
+                trailer::read_trailer(&card, &layout, &mut keys, sector)?.access,
+                sim::TRANSPORT_ACCESS
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn mifare_lock_unknown_auth_key() -> TestResult {
+        let _serial = sim::serial();
+        let card = SimulatedCard::new(SimModel::MifareClassic1K, &[0x20, 0x00, 0x00, 0x04]);
+        let kind = atr::parse_atr(&card.atr());
+        let layout = ClassicLayout::CLASSIC_1K;
+        // Sector 5: only Key B may change the access bits, keys are never written,
+        // and Key B is in no dictionary
+        let trailer = SectorTrailer {
+            key_a: [0xFF; 6],
+            access: AccessBits([
+                AccessCondition(0b000),
+                AccessCondition(0b000),
+                AccessCondition(0b000),
+                AccessCondition(0b101),
+            ]),
+            gpb: 0x69,
+            key_b: [0x42; 6],
+        };
+        apdu::load_key(&card, &[0xFF; 6])?;
+        apdu::authenticate(&card, 23, KEY_A)?;
+        apdu::update_binary(&card, 23, &trailer.to_bytes())?;
+
+        assert_eq!(
+            lock_card(&card, kind),
+            Err(NfcError::AuthFailed { sector: 5 })
+        );
+        // Nothing was locked
+        let mut keys = KeyMap::default();
+        for sector in [0, 1, 4] {
+            assert_eq!(
+                trailer::read_trailer(&card, &layout, &mut keys, sector)?.access,
+                sim::TRANSPORT_ACCESS
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn lock_confirmation() -> TestResult {
+        let uid = [0x04, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66];
+        let confirmation = LockConfirmation::issue(&uid);
+        assert_eq!(confirmation.token.len(), 16);
+        assert!(confirmation.confirms(&confirmation.token, &uid));
+        assert!(confirmation.confirms(&confirmation.token.to_lowercase(), &uid));
+        assert!(!confirmation.confirms("0000000000000000", &uid));
+        assert!(!confirmation.confirms(&confirmation.token, &uid[..4]));
+        if LockConfirmation::issue(&uid).token == confirmation.token {
+            return Err("tokens repeat".into());
+        }
+        let json = r#"{"type":"LOCK_TAG","confirm":"0123456789ABCDEF"}"#;
+        match serde_json::from_str::<IncomingMessage>(json)? {
+            IncomingMessage::LOCK_TAG(spec) => {
+                assert_eq!(spec.confirm.as_deref(), Some("0123456789ABCDEF"))
+            }
+            other => return Err(format!("parsed as {:?}", other).into()),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn ntag_format() -> TestResult {
+        let _serial = sim::serial();
+        let card = SimulatedCard::ntag_unformatted(SimModel::Ntag215);
+        let kind = atr::parse_atr(&card.atr());
+        assert_eq!(format_card(&card, kind)?, Touched::Pages(vec![3, 4]));
+        assert_eq!(
+            apdu::read_binary(&card, 3, 4)?,
+            vec![0xE1, 0x10, 0x3E, 0x00]
+        );
+        let raw = read_card(&card, kind)?;
+        assert_eq!(ndef::find_ndef_tlv(&raw)?, &[0u8; 0][..]);
+
+        // A formatted tag with data only needs the empty message again
+        sim::write_text(&card, "guest 42")?;
+        assert_eq!(format_card(&card, kind)?, Touched::Pages(vec![4]));
+        assert_eq!(
+            ndef::find_ndef_tlv(&read_card(&card, kind)?)?,
+            &[0u8; 0][..]
+        );
+
+        // CC bits that are already set cannot be cleared
+        let card = SimulatedCard::ntag_unformatted(SimModel::Ntag215);
+        apdu::update_binary(&card, 3, &[0xE1, 0x10, 0x6D, 0x00])?;
+        let res = format_card(&card, kind);
+        assert_eq!(res.map_err(|e| e.code()), Err("UNSUPPORTED_CARD"));
+        Ok(())
+    }
+
+    #[test]
+    fn ntag_erase() -> TestResult {
+        let _serial = sim::serial();
+        let (card, kind) = sim::card_with_text(SimModel::Ntag213, &"E".repeat(120))?;
+
+        let touched = erase_card(&card, kind)?;
+        assert_eq!(touched.clone(), Touched::Pages((4..=39).collect()));
+        if ndef::find_ndef_tlv(&read_card(&card, kind)?).is_ok() {
+            return Err("NDEF message survived the erase".into());
+        }
+        // CC and configuration pages are untouched
+        assert_eq!(
+            apdu::read_binary(&card, 3, 4)?,
+            vec![0xE1, 0x10, 0x12, 0x00]
+        );
+        let tag = detect_ntag(&card)?;
+        assert_eq!(
+            password::read_protection(&card, &tag)?.map(|p| p.auth0),
+            Some(0xFF)
+        );
+
+        let uid = Some("04112233445566".to_string());
+        let msg = serde_json::to_value(OutgoingMessage::format_success(true, uid, kind, touched))?;
+        assert_eq!(msg["type"].as_str(), Some("CARD_ERASE_SUCCESS"));
+        assert_eq!(msg["pages"].as_array().map(|pages| pages.len()), Some(36));
+        assert_eq!(msg.get("blocks"), None);
+        Ok(())
+    }
+
+    #[test]
+    fn mifare_format_erase() -> TestResult {
+        let _serial = sim::serial();
+        let card = SimulatedCard::new(SimModel::MifareClassic1K, &[0x21, 0x00, 0x00, 0x01]);
+        let kind = atr::parse_atr(&card.atr());
+        let layout = ClassicLayout::CLASSIC_1K;
+        let Touched::Blocks(formatted) = format_card(&card, kind)? else {
+            return Err("format reported pages".into());
+        };
+        // MAD blocks, every trailer and the empty message
+        let mut expected = vec![1, 2, 4];
+        expected.extend((0..16).map(|sector| layout.trailer_block(sector)));
+        expected.sort_unstable();
+        assert_eq!(formatted, expected);
+        assert_eq!(
+            ndef::find_ndef_tlv(&read_card(&card, kind)?)?,
+            &[0u8; 0][..]
+        );
+
+        sim::write_text(&card, &"badge".repeat(40))?;
+        assert_eq!(
+            erase_card(&card, kind)?,
+            Touched::Blocks(layout.user_blocks())
+        );
+        if ndef::find_ndef_tlv(&read_card(&card, kind)?).is_ok() {
+            return Err("NDEF message survived the erase".into());
+        }
+        // MAD and NDEF trailers survive, so the badge takes a new message directly
+        let mut keys = KeyMap::default();
+        assert_eq!(
+            mad::read_mad(&card, &layout, &mut keys)?.map(|aids| aids[1]),
+            Some(mad::NDEF_AID)
+        );
+        sim::write_text(&card, "reissued")?;
+        assert_eq!(
+            ndef::decode_ndef_content(&read_card(&card, kind)?)?,
+            "reissued".to_string()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn mifare_4k_erase() -> TestResult {
+        let _serial = sim::serial();
+        let card = SimulatedCard::new(SimModel::MifareClassic4K, &[0x21, 0x00, 0x00, 0x02]);
+        let kind = atr::parse_atr(&card.atr());
+        let layout = ClassicLayout::CLASSIC_4K;
+        sim::write_text(&card, &"4".repeat(900))?;
+        // Without a MAD, sector 16 is ordinary user memory
+        let Touched::Blocks(blocks) = erase_card(&card, kind)? else {
+            return Err("erase reported pages".into());
+        };
+        assert_eq!(blocks.len(), layout.user_blocks().len());
+        assert!(blocks.contains(&layout.first_block(16)));
+        assert!(read_card(&card, kind)?.iter().all(|&b| b == 0));
+        Ok(())
+    }
+}
@@ -0,0 +1,306 @@
+// src/dictionary.rs
+// MIFARE Classic key dictionary. Loaded from the file named by NFC_KEY_FILE:
+//   *.toml - general `keys` plus per-sector Key A / Key B assignments
+//   other  - `.dic` format, one 12-digit hex key per line, `#` starts a comment
+// The built-in keys are always tried after the file's keys.
+//
+//   keys = ["A0A1A2A3A4A5"]
+//   [[sector]]
+//   sector = 1
+//   key_a = "112233445566"
+//   key_b = "665544332211"
+use crate::keys::SectorKeys;
+use log::{error, info};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+pub const KEY_FILE_ENV: &str = "NFC_KEY_FILE";
+
+// Well-known default keys, tried after any loaded dictionary
+pub const BUILTIN_KEYS: [[u8; 6]; 8] = [
+    [0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF],
+    [0xA0, 0xA1, 0xA2, 0xA3, 0xA4, 0xA5],
+    [0xD3, 0xF7, 0xD3, 0xF7, 0xD3, 0xF7],
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+    [0xB0, 0xB1, 0xB2, 0xB3, 0xB4, 0xB5],
+    [0x4D, 0x3A, 0x99, 0xC3, 0x51, 0xDD],
+    [0x1A, 0x98, 0x2C, 0x7E, 0x45, 0x9A],
+    [0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0xFF],
+];
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct KeyDictionary {
+    // Tried on every sector
+    pub keys: Vec<[u8; 6]>,
+    // Tried first on their own sector
+    pub sectors: BTreeMap<u8, SectorKeys>,
+}
+
+impl KeyDictionary {
+    pub fn builtin() -> Self {
+        KeyDictionary {
+            keys: BUILTIN_KEYS.to_vec(),
+            sectors: BTreeMap::new(),
+        }
+    }
+
+    // Keys worth trying on `sector`: its assigned keys, then the general keys,
+    // then the built-in keys, without repeats
+    pub fn candidates(&self, sector: u8) -> Vec<[u8; 6]> {
+        let assigned = self
+            .sectors
+            .get(&sector)
+            .map(|keys| [keys.key_a, keys.key_b])
+            .unwrap_or_default();
+        let mut out: Vec<[u8; 6]> = Vec::new();
+        for key in assigned
+            .into_iter()
+            .flatten()
+            .chain(self.keys.iter().copied())
+            .chain(BUILTIN_KEYS)
+        {
+            if !out.contains(&key) {
+                out.push(key);
+            }
+        }
+        out
+    }
+}
+
+fn parse_key(text: &str) -> Result<[u8; 6], String> {
+    let bytes = hex::decode(text.trim()).map_err(|e| format!("'{}': {}", text, e))?;
+    bytes
+        .try_into()
+        .map_err(|_| format!("'{}': a key is 6 bytes (12 hex digits)", text))
+}
+
+// One key per line; blank lines and `#` comments are ignored
+pub fn parse_dic(text: &str) -> Result<KeyDictionary, String> {
+    let mut dict = KeyDictionary::default();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let key = parse_key(line).map_err(|e| format!("line {}: {}", n + 1, e))?;
+        if !dict.keys.contains(&key) {
+            dict.keys.push(key);
+        }
+    }
+    Ok(dict)
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TomlDictionary {
+    #[serde(default)]
+    keys: Vec<String>,
+    #[serde(default)]
+    sector: Vec<TomlSector>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TomlSector {
+    sector: u8,
+    key_a: Option<String>,
+    key_b: Option<String>,
+}
+
+pub fn parse_toml(text: &str) -> Result<KeyDictionary, String> {
+    let parsed: TomlDictionary = toml::from_str(text).map_err(|e| e.to_string())?;
+    let mut dict = KeyDictionary::default();
+    for key in &parsed.keys {
+        dict.keys.push(parse_key(key)?);
+    }
+    for entry in &parsed.sector {
+        if entry.sector >= 40 {
+            return Err(format!("sector {} does not exist", entry.sector));
+        }
+        let keys = SectorKeys {
+            key_a: entry.key_a.as_deref().map(parse_key).transpose()?,
+            key_b: entry.key_b.as_deref().map(parse_key).transpose()?,
+        };
+        dict.sectors.insert(entry.sector, keys);
+    }
+    Ok(dict)
+}
+
+pub fn load(path: &Path) -> Result<KeyDictionary, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let is_toml = path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("toml"));
+    if is_toml {
+        parse_toml(&text)
+    } else {
+        parse_dic(&text)
+    }
+}
+
+static ACTIVE: RwLock<Option<Arc<KeyDictionary>>> = RwLock::new(None);
+
+// The dictionary in use; the built-in keys until a file has been loaded
+pub fn current() -> Arc<KeyDictionary> {
+    let active = ACTIVE.read().unwrap_or_else(|e| e.into_inner());
+    active
+        .clone()
+        .unwrap_or_else(|| Arc::new(KeyDictionary::builtin()))
+}
+
+pub fn install(dict: KeyDictionary) {
+    *ACTIVE.write().unwrap_or_else(|e| e.into_inner()) = Some(Arc::new(dict));
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn load_and_install(path: &Path) {
+    match load(path) {
+        Ok(dict) => {
+            info!(
+                "Loaded {} keys and {} sector assignments from {}",
+                dict.keys.len(),
+                dict.sectors.len(),
+                path.display()
+            );
+            install(dict);
+        }
+        // Keep whatever was working before (built-in keys at startup)
+        Err(e) => error!("Failed to load key dictionary {}: {}", path.display(), e),
+    }
+}
+
+// Load the file named by NFC_KEY_FILE, if any, and reload it whenever it changes
+pub fn init_from_env() {
+    let path = match std::env::var_os(KEY_FILE_ENV) {
+        Some(path) => PathBuf::from(path),
+        None => {
+            info!("{} not set, using built-in MIFARE keys", KEY_FILE_ENV);
+            return;
+        }
+    };
+    load_and_install(&path);
+
+    std::thread::spawn(move || {
+        let mut last = modified(&path);
+        loop {
+            std::thread::sleep(Duration::from_secs(2));
+            let now = modified(&path);
+            if now.is_some() && now != last {
+                info!("Key dictionary {} changed, reloading", path.display());
+                load_and_install(&path);
+                last = now;
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::apdu;
+    use crate::cards;
+    use crate::keys::{self, KeyMap};
+    use crate::ndef;
+    use crate::sim::{self, SimModel, SimulatedCard, TestResult, rekey_all_sectors};
+
+    #[test]
+    fn dictionary_dic() -> TestResult {
+        let _serial = sim::serial();
+        let dict =
+            parse_dic("# site keys\n\n112233445566\n  a0a1a2a3a4a5  # MAD key\n112233445566\n")?;
+        assert_eq!(
+            dict.keys.clone(),
+            vec![
+                [0x11, 0x22, 0x33, 0x44, 0x55, 0x66],
+                [0xA0, 0xA1, 0xA2, 0xA3, 0xA4, 0xA5]
+            ],
+        );
+        // File keys come first, the built-in keys stay as a fallback
+        let candidates = dict.candidates(3);
+        assert_eq!(candidates[0], [0x11, 0x22, 0x33, 0x44, 0x55, 0x66]);
+        assert_eq!(candidates.len(), 1 + BUILTIN_KEYS.len());
+
+        match parse_dic("FFFFFFFFFFFF\nFFFF\n") {
+            Ok(_) => Err("short key accepted".into()),
+            Err(e) if e.starts_with("line 2") => Ok(()),
+            Err(e) => Err(format!("unexpected error: {}", e).into()),
+        }
+    }
+
+    #[test]
+    fn dictionary_toml() -> TestResult {
+        let _serial = sim::serial();
+        let dict = parse_toml(
+            r#"
+            keys = ["0102030405FF"]
+
+            [[sector]]
+            sector = 2
+            key_a = "112233445566"
+
+            [[sector]]
+            sector = 5
+            key_a = "AAAAAAAAAAAA"
+            key_b = "BBBBBBBBBBBB"
+            "#,
+        )?;
+        assert_eq!(
+            dict.keys.clone(),
+            vec![[0x01, 0x02, 0x03, 0x04, 0x05, 0xFF]]
+        );
+        assert_eq!(dict.sectors.len(), 2);
+        assert_eq!(
+            dict.candidates(5)[0..2].to_vec(),
+            vec![[0xAA; 6], [0xBB; 6]]
+        );
+        assert_eq!(dict.candidates(2)[0], [0x11, 0x22, 0x33, 0x44, 0x55, 0x66]);
+        // Sector assignments do not leak into other sectors
+        assert_eq!(dict.candidates(1)[0], [0x01, 0x02, 0x03, 0x04, 0x05, 0xFF]);
+
+        for bad in [
+            "keys = [\"0102\"]",
+            "[[sector]]\nsector = 40\nkey_a = \"FFFFFFFFFFFF\"",
+            "key = [\"FFFFFFFFFFFF\"]",
+        ] {
+            if parse_toml(bad).is_ok() {
+                return Err(format!("accepted {:?}", bad).into());
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn dictionary_file_keys() -> TestResult {
+        let _serial = sim::serial();
+        let site_key = [0x5A, 0x17, 0xE0, 0x00, 0x00, 0x01];
+        let card = SimulatedCard::new(SimModel::MifareClassic1K, &[0x14, 0x00, 0x00, 0x01]);
+        let kind = sim::write_text(&card, "site")?;
+        rekey_all_sectors(&card, site_key)?;
+        // Forget what the rekeying taught the cache so only the dictionary can help
+        keys::remember(&apdu::get_uid(&card)?, KeyMap::default());
+        match cards::read_card(&card, kind) {
+            Ok(_) => return Err("read succeeded without the site key".into()),
+            Err(e) => assert_eq!(e.code(), "WRONG_KEY"),
+        }
+
+        let path = std::env::temp_dir().join(format!("nfc-test-{}.dic", std::process::id()));
+        std::fs::write(&path, "# site\n5A17E0000001\n")?;
+        let loaded = load(&path);
+        std::fs::remove_file(&path)?;
+        install(loaded?);
+        let result = cards::read_card(&card, kind);
+        install(KeyDictionary::builtin());
+        assert_eq!(ndef::decode_ndef_content(&result?)?, "site".to_string());
+
+        if load(&path).is_ok() {
+            return Err("missing dictionary file loaded".into());
+        }
+        Ok(())
+    }
+}
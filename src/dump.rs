@@ -0,0 +1,661 @@
+// src/dump.rs
+// Raw card dumps for diagnosing badges: every block (MIFARE Classic) or page
+// (Type 2) that could be read, the keys that opened each sector, and gaps
+// where nothing could be read. Saved as JSON, or as the plain `.mfd` / `.bin`
+// memory image other NFC tools use (gaps become zeros, Classic trailers carry
+// the keys that were found).
+use crate::apdu;
+use crate::atr::{self, CardKind};
+use crate::cards::{self, ClassicLayout, NTAG_USER_START, Touched};
+use crate::error::NfcError;
+use crate::keys::{self, KEY_A, KEY_B, KeyMap};
+use crate::password;
+use crate::trailer::{self, SectorTrailer};
+use crate::transport::CardTransport;
+use crate::types::{self, SectorKeyReport};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DumpUnit {
+    // 16-byte MIFARE Classic block
+    Block,
+    // 4-byte Type 2 page
+    Page,
+}
+
+impl DumpUnit {
+    pub fn size(self) -> usize {
+        match self {
+            DumpUnit::Block => 16,
+            DumpUnit::Page => 4,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CardDump {
+    pub uid: String,
+    // Empty for dumps loaded from a memory image
+    #[serde(default)]
+    pub atr: String,
+    pub card_type: String,
+    pub unit: DumpUnit,
+    // Hex of every block / page in order; null where it could not be read
+    pub data: Vec<Option<String>>,
+    // Keys that opened each sector (MIFARE Classic)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub keys: Vec<SectorKeyReport>,
+}
+
+// Memory images of MIFARE Mini, Classic 1K and Classic 4K
+const CLASSIC_IMAGE_SIZES: [(usize, CardKind); 3] = [
+    (320, CardKind::MifareMini),
+    (1024, CardKind::MifareClassic1K),
+    (4096, CardKind::MifareClassic4K),
+];
+
+// Whole-chip page counts of the NTAG21x; other Type 2 images keep the family name
+const NTAG_IMAGE_PAGES: [(usize, &str); 3] = [(45, "NTAG213"), (135, "NTAG215"), (231, "NTAG216")];
+
+impl CardDump {
+    // Bytes of unit `index`; None if it was not read (or the dump is corrupt)
+    pub fn unit(&self, index: usize) -> Option<Vec<u8>> {
+        let bytes = hex::decode(self.data.get(index)?.as_ref()?).ok()?;
+        (bytes.len() == self.unit.size()).then_some(bytes)
+    }
+
+    // `.mfd` / `.bin` memory image; unread units are zero-filled
+    pub fn to_image(&self) -> Vec<u8> {
+        (0..self.data.len())
+            .flat_map(|index| {
+                self.unit(index)
+                    .unwrap_or_else(|| vec![0x00; self.unit.size()])
+            })
+            .collect()
+    }
+
+    // Classic images are recognised by their size, anything else is taken as Type 2 pages
+    pub fn from_image(image: &[u8]) -> Result<CardDump, String> {
+        if let Some(&(_, kind)) = CLASSIC_IMAGE_SIZES
+            .iter()
+            .find(|(size, _)| *size == image.len())
+        {
+            let layout = ClassicLayout::for_kind(kind).ok_or("not a MIFARE Classic layout")?;
+            let mut keys = Vec::new();
+            for sector in 0..layout.sectors {
+                let trailer = layout.trailer_block(sector) as usize * 16;
+                keys.push(SectorKeyReport {
+                    sector,
+                    key_a: Some(hex::encode_upper(&image[trailer..trailer + 6])),
+                    key_b: Some(hex::encode_upper(&image[trailer + 10..trailer + 16])),
+                });
+            }
+            // Block 0 starts UID0-3 | BCC for 4-byte UIDs, or the 7-byte UID with no check byte
+            let bcc = image[0..4].iter().fold(0u8, |acc, b| acc ^ b);
+            let uid_len = if image[4] == bcc { 4 } else { 7 };
+            return Ok(CardDump {
+                uid: hex::encode_upper(&image[0..uid_len]),
+                atr: String::new(),
+                card_type: kind.to_string(),
+                unit: DumpUnit::Block,
+                data: image
+                    .chunks(16)
+                    .map(|block| Some(hex::encode_upper(block)))
+                    .collect(),
+                keys,
+            });
+        }
+        if image.len() < 16 || !image.len().is_multiple_of(4) {
+            return Err(format!(
+                "{} bytes is neither a MIFARE Classic nor a Type 2 image",
+                image.len()
+            ));
+        }
+        // UID0-2 | BCC0 | UID3-6
+        let mut uid = image[0..3].to_vec();
+        uid.extend_from_slice(&image[4..8]);
+        let pages = image.len() / 4;
+        let card_type = match NTAG_IMAGE_PAGES.iter().find(|(size, _)| *size == pages) {
+            Some((_, model)) => model.to_string(),
+            None => CardKind::Ultralight.to_string(),
+        };
+        Ok(CardDump {
+            uid: hex::encode_upper(uid),
+            atr: String::new(),
+            card_type,
+            unit: DumpUnit::Page,
+            data: image
+                .chunks(4)
+                .map(|page| Some(hex::encode_upper(page)))
+                .collect(),
+            keys: Vec::new(),
+        })
+    }
+}
+
+// Read one sector block by block, with whichever key type the access bits allow
+fn dump_sector(
+    card: &dyn CardTransport,
+    layout: &ClassicLayout,
+    keys: &mut KeyMap,
+    sector: u8,
+) -> Result<Vec<Option<Vec<u8>>>, NfcError> {
+    let mut blocks = Vec::new();
+    let mut authenticated = None;
+    for block in layout.first_block(sector)..=layout.trailer_block(sector) {
+        let mut data = None;
+        for key_type in [KEY_A, KEY_B] {
+            if authenticated != Some(key_type) {
+                match cards::authenticate_sector(card, layout, keys, block, &[key_type]) {
+                    Ok(()) => authenticated = Some(key_type),
+                    Err(e) if e.is_transport() => return Err(e),
+                    Err(_) => continue,
+                }
+            }
+            match apdu::read_binary(card, block, 16) {
+                Ok(bytes) if bytes.len() == 16 => {
+                    data = Some(bytes);
+                    break;
+                }
+                Err(e) if e.is_transport() => return Err(e),
+                // Refused: the card dropped the authentication
+                _ => authenticated = None,
+            }
+        }
+        blocks.push(data);
+    }
+
+    // Trailers read back without Key A (and usually Key B); put in the keys that worked
+    if let Some(Some(trailer)) = blocks.last_mut() {
+        if let Some(key_a) = keys.get(sector, KEY_A) {
+            trailer[0..6].copy_from_slice(&key_a);
+        }
+        if let Some(key_b) = keys.get(sector, KEY_B) {
+            trailer[10..16].copy_from_slice(&key_b);
+        }
+    }
+    Ok(blocks)
+}
+
+fn dump_mifare(
+    card: &dyn CardTransport,
+    layout: &ClassicLayout,
+    keys: &mut KeyMap,
+) -> Result<Vec<Option<Vec<u8>>>, NfcError> {
+    let mut blocks = Vec::new();
+    for sector in 0..layout.sectors {
+        blocks.extend(dump_sector(card, layout, keys, sector)?);
+    }
+    Ok(blocks)
+}
+
+// Every page of the chip, four at a time; pages behind a password we do not have stay empty
+fn dump_ntag(card: &dyn CardTransport) -> Result<Vec<Option<Vec<u8>>>, NfcError> {
+    let tag = cards::detect_ntag(card)?;
+    match password::unlock(card, &tag, false) {
+        Ok(()) => {}
+        Err(e) if e.is_transport() => return Err(e),
+        Err(e) => warn!(
+            "{:?}: protected pages will be missing from the dump ({})",
+            tag.model, e
+        ),
+    }
+    let total = tag.total_pages() as usize;
+    let mut pages = Vec::new();
+    while pages.len() < total {
+        match apdu::read_binary(card, pages.len() as u8, 16) {
+            Ok(bytes) if bytes.len() == 16 => {
+                pages.extend(bytes.chunks(4).map(|page| Some(page.to_vec())))
+            }
+            Err(e) if e.is_transport() => return Err(e),
+            _ => {
+                // A NAK halts the tag
+                apdu::reselect(card)?;
+                pages.extend([None, None, None, None]);
+            }
+        }
+    }
+    // The last READ rolls over to page 0
+    pages.truncate(total);
+    Ok(pages)
+}
+
+pub fn dump_card(card: &dyn CardTransport, atr: &[u8]) -> Result<CardDump, NfcError> {
+    let kind = atr::parse_atr(atr);
+    let uid = apdu::get_uid(card)?;
+    let (unit, units, keys) = if let Some(layout) = ClassicLayout::for_kind(kind) {
+        let blocks = cards::with_card_keys(card, |keys| dump_mifare(card, &layout, keys))?;
+        (
+            DumpUnit::Block,
+            blocks,
+            types::sector_key_reports(&keys::cached(&uid)),
+        )
+    } else if kind.is_type2() {
+        (DumpUnit::Page, dump_ntag(card)?, Vec::new())
+    } else {
+        return Err(NfcError::UnsupportedCard(kind.to_string()));
+    };
+
+    Ok(CardDump {
+        uid: hex::encode_upper(&uid),
+        atr: hex::encode_upper(atr),
+        card_type: kind.to_string(),
+        unit,
+        data: units
+            .into_iter()
+            .map(|data| data.map(hex::encode_upper))
+            .collect(),
+        keys,
+    })
+}
+
+fn restore_mifare(
+    card: &dyn CardTransport,
+    layout: &ClassicLayout,
+    keys: &mut KeyMap,
+    dump: &CardDump,
+) -> Result<Vec<u8>, NfcError> {
+    let mut touched = Vec::new();
+    for sector in 0..layout.sectors {
+        let trailer_block = layout.trailer_block(sector);
+        // Block 0 is the manufacturer block and cannot be written
+        for block in layout.first_block(sector).max(1)..trailer_block {
+            if let Some(bytes) = dump.unit(block as usize) {
+                let mut data = [0u8; 16];
+                data.copy_from_slice(&bytes);
+                cards::write_block_any_key(card, layout, keys, block, &data)?;
+                touched.push(block);
+            }
+        }
+
+        // Trailers read back without their keys: only restore those whose keys the
+        // dump knows, anything else would re-key the sector with zeros.
+        // write_trailer refuses trailers that would lock the sector for good.
+        let Some(bytes) = dump.unit(trailer_block as usize) else {
+            continue;
+        };
+        let mut block = [0u8; 16];
+        block.copy_from_slice(&bytes);
+        let trailer =
+            SectorTrailer::from_bytes(&block).ok_or(NfcError::InvalidAccessBits { sector })?;
+        let known = dump.keys.iter().find(|k| k.sector == sector);
+        let key_a = known.and_then(|k| dump_key(k.key_a.as_deref()));
+        let key_b = known.and_then(|k| dump_key(k.key_b.as_deref()));
+        let key_b_readable = trailer.access.trailer().trailer_key_b_readable();
+        let (Some(key_a), true) = (key_a, key_b.is_some() || key_b_readable) else {
+            continue;
+        };
+        let trailer = SectorTrailer {
+            key_a,
+            key_b: key_b.unwrap_or(trailer.key_b),
+            ..trailer
+        };
+        trailer::write_trailer(card, layout, keys, sector, &trailer)?;
+        touched.push(trailer_block);
+    }
+    Ok(touched)
+}
+
+// User pages and the CC; lock bytes and configuration pages are left alone so a
+// restore can never lock or password-protect the new tag
+fn restore_ntag(card: &dyn CardTransport, dump: &CardDump) -> Result<Vec<u8>, NfcError> {
+    let tag = cards::detect_ntag(card)?;
+    if dump.data.len() != tag.total_pages() as usize {
+        return Err(invalid(format!(
+            "dump has {} pages, the {:?} on the reader has {}",
+            dump.data.len(),
+            tag.model,
+            tag.total_pages()
+        )));
+    }
+    password::unlock(card, &tag, true)?;
+    let mut touched = Vec::new();
+
+    if let Some(mut cc) = dump.unit(3) {
+        // Byte 3 (write access) stays the target's: a dump of a read-only tag
+        // (0F) would otherwise lock the new one for good
+        cc[3] = tag.cc[3];
+        // CC bits are one-time programmable
+        if tag.cc.iter().zip(&cc).any(|(have, want)| have & !want != 0) {
+            return Err(invalid(format!(
+                "capability container {} cannot become {}",
+                hex::encode_upper(tag.cc),
+                hex::encode_upper(&cc)
+            )));
+        }
+        if cc != tag.cc {
+            apdu::update_binary(card, 3, &cc)?;
+            touched.push(3);
+        }
+    }
+    for page in NTAG_USER_START..=tag.last_user_page() {
+        if let Some(bytes) = dump.unit(page as usize) {
+            apdu::update_binary(card, page, &bytes)?;
+            touched.push(page);
+        }
+    }
+    Ok(touched)
+}
+
+fn dump_key(key: Option<&str>) -> Option<[u8; 6]> {
+    hex::decode(key?).ok()?.try_into().ok()
+}
+
+fn invalid(msg: String) -> NfcError {
+    NfcError::InvalidDump(msg)
+}
+
+// Write `dump` to a card of the same type and size. The UID, manufacturer block,
+// lock bytes and configuration pages of the target card stay as they are.
+pub fn restore_card(
+    card: &dyn CardTransport,
+    kind: CardKind,
+    dump: &CardDump,
+) -> Result<Touched, NfcError> {
+    if let Some(layout) = ClassicLayout::for_kind(kind) {
+        let blocks = layout.trailer_block(layout.sectors - 1) as usize + 1;
+        if dump.unit != DumpUnit::Block || dump.data.len() != blocks {
+            return Err(invalid(format!(
+                "dump of a {} does not fit a {}",
+                dump.card_type, kind
+            )));
+        }
+        cards::with_card_keys(card, |keys| restore_mifare(card, &layout, keys, dump))
+            .map(Touched::Blocks)
+    } else if kind.is_type2() {
+        if dump.unit != DumpUnit::Page {
+            return Err(invalid(format!(
+                "dump of a {} does not fit a {}",
+                dump.card_type, kind
+            )));
+        }
+        restore_ntag(card, dump).map(Touched::Pages)
+    } else {
+        Err(NfcError::UnsupportedCard(kind.to_string()))
+    }
+}
+
+fn is_json(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("json"))
+}
+
+// `*.json` as a CardDump, anything else (`.mfd`, `.bin`) as a memory image
+pub fn save(dump: &CardDump, path: &Path) -> Result<(), String> {
+    let bytes = if is_json(path) {
+        serde_json::to_vec_pretty(dump).map_err(|e| e.to_string())?
+    } else {
+        dump.to_image()
+    };
+    std::fs::write(path, bytes).map_err(|e| e.to_string())
+}
+
+pub fn load(path: &Path) -> Result<CardDump, String> {
+    let bytes = std::fs::read(path).map_err(|e| e.to_string())?;
+    if is_json(path) {
+        serde_json::from_slice(&bytes).map_err(|e| e.to_string())
+    } else {
+        CardDump::from_image(&bytes)
+    }
+}
+
+// `dump <file>` / `restore <file>` from the command line, against the first card found
+pub fn cli(command: &str, path: Option<&str>) -> Result<(), String> {
+    let path = Path::new(path.ok_or(format!("usage: {} <file.json|file.mfd|file.bin>", command))?);
+    let (card, atr) = crate::nfc_service::connect_first_card()?;
+    if command == "dump" {
+        let dump = dump_card(&card, &atr).map_err(|e| e.to_string())?;
+        let missing = dump.data.iter().filter(|unit| unit.is_none()).count();
+        save(&dump, path)?;
+        println!(
+            "Dumped {} {} to {} ({} {}s, {} unreadable)",
+            dump.card_type,
+            dump.uid,
+            path.display(),
+            dump.data.len(),
+            if dump.unit == DumpUnit::Block {
+                "block"
+            } else {
+                "page"
+            },
+            missing
+        );
+    } else {
+        let dump = load(path)?;
+        let kind = atr::parse_atr(&atr);
+        let touched = restore_card(&card, kind, &dump).map_err(|e| e.to_string())?;
+        let written = match touched {
+            Touched::Blocks(units) | Touched::Pages(units) => units.len(),
+        };
+        println!(
+            "Restored {} onto {} ({} written)",
+            path.display(),
+            kind,
+            written
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::apdu;
+    use crate::atr::{self, CardKind};
+    use crate::cards::{self, ClassicLayout, Touched};
+    use crate::keys::{self, KeyMap};
+    use crate::ndef;
+    use crate::password::{self, ProtectMode};
+    use crate::sim::{self, SimModel, SimulatedCard, TestResult, provision_sector_1, with_secret};
+    use crate::trailer::{self, SectorTrailer};
+    use crate::types::IncomingMessage;
+
+    // 1K badge: sector 1 re-keyed with keys the service learnt earlier,
+    // sector 2 with keys nobody knows
+    fn provisioned_badge() -> Result<SimulatedCard, Box<dyn std::error::Error>> {
+        let card = SimulatedCard::new(SimModel::MifareClassic1K, &[0x22, 0x00, 0x00, 0x01]);
+        sim::write_text(&card, "badge 0042")?;
+        let key_a = [0xA0, 0xA1, 0xA2, 0xA3, 0xA4, 0xA5];
+        let key_b = [0xB0, 0xB1, 0xB2, 0xB3, 0xB4, 0xB5];
+        provision_sector_1(&card, key_a, 0b100, 0b011, key_b)?;
+        let mut learnt = KeyMap::default();
+        learnt.record(1, KEY_A, key_a);
+        learnt.record(1, KEY_B, key_b);
+        keys::remember(&apdu::get_uid(&card)?, learnt);
+
+        let lost = SectorTrailer {
+            key_a: [0x42; 6],
+            access: sim::TRANSPORT_ACCESS,
+            gpb: 0x69,
+            key_b: [0x43; 6],
+        };
+        trailer::write_trailer(
+            &card,
+            &ClassicLayout::CLASSIC_1K,
+            &mut KeyMap::default(),
+            2,
+            &lost,
+        )?;
+        Ok(card)
+    }
+
+    #[test]
+    fn mifare_dump_restore() -> TestResult {
+        let _serial = sim::serial();
+        let card = provisioned_badge()?;
+        let dump = dump_card(&card, &card.atr())?;
+        assert_eq!((dump.unit, dump.data.len()), (DumpUnit::Block, 64));
+        assert_eq!(dump.uid.as_str(), "22000001");
+        assert_eq!(dump.atr, hex::encode_upper(card.atr()));
+        // Sector 2 is a gap, the rest is there
+        let missing: Vec<usize> = (0..64)
+            .filter(|&block| dump.unit(block).is_none())
+            .collect();
+        assert_eq!(missing, vec![8, 9, 10, 11]);
+        assert_eq!(
+            dump.unit(0).map(|block| block[..4].to_vec()),
+            Some(vec![0x22, 0x00, 0x00, 0x01])
+        );
+        // Trailers carry the keys that opened them
+        let trailer = dump.unit(7).ok_or("no sector 1 trailer")?;
+        assert_eq!(hex::encode_upper(&trailer[0..6]), "A0A1A2A3A4A5");
+        assert_eq!(hex::encode_upper(&trailer[10..16]), "B0B1B2B3B4B5");
+        let sector_1 = dump
+            .keys
+            .iter()
+            .find(|k| k.sector == 1)
+            .ok_or("no sector 1 keys")?;
+        assert_eq!(sector_1.key_b.as_deref(), Some("B0B1B2B3B4B5"));
+        assert!(!dump.keys.iter().any(|k| k.sector == 2));
+
+        // Onto a blank badge: data, sector 1 keys and access bits come along
+        let blank = SimulatedCard::new(SimModel::MifareClassic1K, &[0x22, 0x00, 0x00, 0x02]);
+        let kind = atr::parse_atr(&blank.atr());
+        let Touched::Blocks(written) = restore_card(&blank, kind, &dump)? else {
+            return Err("restore reported pages".into());
+        };
+        assert!(!written.contains(&0));
+        assert!(!written.iter().any(|block| (8..12).contains(block)));
+        assert_eq!(
+            ndef::decode_ndef_content(&cards::read_card(&blank, kind)?)?,
+            "badge 0042"
+        );
+        let restored = dump_card(&blank, &blank.atr())?;
+        assert_eq!(restored.unit(7), dump.unit(7));
+        assert_eq!(restored.unit(4), dump.unit(4));
+
+        // Wrong size
+        let mini = SimulatedCard::new(SimModel::MifareMini, &[0x22, 0x00, 0x00, 0x03]);
+        let res = restore_card(&mini, CardKind::MifareMini, &dump);
+        assert_eq!(res.map_err(|e| e.code()), Err("INVALID_DUMP"));
+        Ok(())
+    }
+
+    #[test]
+    fn ntag_dump_restore() -> TestResult {
+        let _serial = sim::serial();
+        let card = SimulatedCard::ntag(SimModel::Ntag213);
+        let kind = with_secret(b"deployment", || {
+            let kind = sim::write_text(&card, &"N".repeat(60))?;
+            password::set_protection(&card, ProtectMode::ReadWrite, 8)?;
+            Ok(kind)
+        })?;
+
+        // Without the secret everything from AUTH0 on is a gap
+        let dump = dump_card(&card, &card.atr())?;
+        assert_eq!((dump.unit, dump.data.len()), (DumpUnit::Page, 45));
+        assert_eq!((0..45).filter(|&page| dump.unit(page).is_some()).count(), 8);
+        password::set_secret(Some(b"deployment".to_vec()));
+        let dump = dump_card(&card, &card.atr());
+        password::set_secret(None);
+        let dump = dump?;
+        assert!(dump.data.iter().all(Option::is_some));
+        // PWD reads back as zeros
+        assert_eq!(dump.unit(0x2B), Some(vec![0x00; 4]));
+
+        let blank = SimulatedCard::ntag_unformatted(SimModel::Ntag213);
+        let Touched::Pages(written) = restore_card(&blank, kind, &dump)? else {
+            return Err("restore reported blocks".into());
+        };
+        assert_eq!(written.first(), Some(&3));
+        assert_eq!(written.last(), Some(&0x27));
+        assert_eq!(
+            ndef::decode_ndef_content(&cards::read_card(&blank, kind)?)?,
+            "N".repeat(60)
+        );
+        // Configuration stays as it was: the copy is not password-protected
+        let tag = cards::detect_ntag(&blank)?;
+        assert_eq!(
+            password::read_protection(&blank, &tag)?.map(|p| p.auth0),
+            Some(0xFF)
+        );
+
+        // A dump of a read-only tag restores onto a tag that stays writable
+        let mut read_only = dump.clone();
+        read_only.data[3] = Some("E110120F".into());
+        let blank = SimulatedCard::ntag_unformatted(SimModel::Ntag213);
+        restore_card(&blank, kind, &read_only)?;
+        assert_eq!(
+            apdu::read_binary(&blank, 3, 4)?,
+            vec![0xE1, 0x10, 0x12, 0x00]
+        );
+        sim::write_text(&blank, "still writable")?;
+
+        // Other chip, other card family
+        let ntag215 = SimulatedCard::ntag(SimModel::Ntag215);
+        let res = restore_card(&ntag215, kind, &dump);
+        assert_eq!(res.map_err(|e| e.code()), Err("INVALID_DUMP"));
+        let classic = SimulatedCard::mifare_classic_1k();
+        let res = restore_card(&classic, CardKind::MifareClassic1K, &dump);
+        assert_eq!(res.map_err(|e| e.code()), Err("INVALID_DUMP"));
+        Ok(())
+    }
+
+    #[test]
+    fn dump_files() -> TestResult {
+        let _serial = sim::serial();
+        let card = provisioned_badge()?;
+        let dump = dump_card(&card, &card.atr())?;
+        let dir = std::env::temp_dir();
+
+        let json = dir.join(format!("nfc-test-{}.json", std::process::id()));
+        save(&dump, &json)?;
+        let loaded = load(&json);
+        let _ = std::fs::remove_file(&json);
+        assert_eq!(loaded?, dump);
+
+        // .mfd: gaps become zeros, keys come from the trailers
+        let mfd = dir.join(format!("nfc-test-{}.mfd", std::process::id()));
+        save(&dump, &mfd)?;
+        let loaded = load(&mfd);
+        let _ = std::fs::remove_file(&mfd);
+        let loaded = loaded?;
+        assert_eq!(
+            (loaded.uid.as_str(), loaded.card_type.as_str()),
+            ("22000001", "MIFARE Classic 1K")
+        );
+        assert_eq!(loaded.unit(4), dump.unit(4));
+        assert_eq!(loaded.unit(8), Some(vec![0x00; 16]));
+        let sector_1 = loaded
+            .keys
+            .iter()
+            .find(|k| k.sector == 1)
+            .ok_or("no sector 1 keys")?;
+        assert_eq!(sector_1.key_a.as_deref(), Some("A0A1A2A3A4A5"));
+
+        // Block 0 of a 7-byte UID has no check byte after the first four
+        let mut image = dump.to_image();
+        image[0..8].copy_from_slice(&[0x04, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x08]);
+        assert_eq!(CardDump::from_image(&image)?.uid, "04112233445566");
+
+        // Type 2 images: UID without the check byte
+        let tag = SimulatedCard::ntag(SimModel::Ntag216);
+        let dump = dump_card(&tag, &tag.atr())?;
+        let image = CardDump::from_image(&dump.to_image())?;
+        assert_eq!((image.unit, image.data.len()), (DumpUnit::Page, 231));
+        assert_eq!(&image.uid, &dump.uid);
+        assert_eq!(image.card_type, "NTAG216");
+        assert_eq!(
+            CardDump::from_image(&[0u8; 48 * 4])?.card_type,
+            "MIFARE Ultralight / NTAG"
+        );
+        assert!(CardDump::from_image(&[0u8; 30]).is_err());
+
+        // RESTORE_CARD takes either form
+        let msg =
+            serde_json::json!({ "type": "RESTORE_CARD", "image": hex::encode(dump.to_image()) });
+        let IncomingMessage::RESTORE_CARD(spec) = serde_json::from_value(msg)? else {
+            return Err("RESTORE_CARD not parsed".into());
+        };
+        assert_eq!(spec.to_dump()?.data.len(), 231);
+        let msg = serde_json::json!({ "type": "RESTORE_CARD", "dump": dump });
+        let IncomingMessage::RESTORE_CARD(spec) = serde_json::from_value(msg)? else {
+            return Err("RESTORE_CARD not parsed".into());
+        };
+        assert_eq!(spec.to_dump()?, dump);
+        Ok(())
+    }
+}
@@ -0,0 +1,317 @@
+// src/error.rs
+use std::fmt;
+
+// Failures talking to the reader itself, before the card could answer
+#[derive(Debug, Clone, PartialEq)]
+pub enum TransportError {
+    // Card left the field (or was reset) mid-operation
+    CardRemoved,
+    // Reader unplugged or PC/SC service gone
+    ReaderUnavailable,
+    Other(String),
+}
+
+impl From<pcsc::Error> for TransportError {
+    fn from(err: pcsc::Error) -> Self {
+        match err {
+            pcsc::Error::RemovedCard
+            | pcsc::Error::ResetCard
+            | pcsc::Error::NoSmartcard
+            | pcsc::Error::UnpoweredCard
+            | pcsc::Error::UnresponsiveCard => TransportError::CardRemoved,
+            pcsc::Error::ReaderUnavailable
+            | pcsc::Error::NoReadersAvailable
+            | pcsc::Error::NoService
+            | pcsc::Error::ServiceStopped => TransportError::ReaderUnavailable,
+            other => TransportError::Other(other.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for TransportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransportError::CardRemoved => write!(f, "Card was removed from the reader"),
+            TransportError::ReaderUnavailable => write!(f, "Reader is not available"),
+            TransportError::Other(msg) => write!(f, "Transmit Error: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for TransportError {}
+
+// Raw SW1 SW2 status word returned by the card or the reader
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StatusWord {
+    pub sw1: u8,
+    pub sw2: u8,
+}
+
+// Decoded meaning of the ISO 7816-4 / ACR122U status words we care about
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StatusKind {
+    Success,
+    // 63 00: ACR122U generic "operation failed" (bad key, NAK, write refused)
+    OperationFailed,
+    // 63 CX: verification failed, X retries left
+    RetriesLeft(u8),
+    // 67 00
+    WrongLength,
+    // 69 81
+    CommandIncompatible,
+    // 69 82: block not authenticated / access conditions not met
+    SecurityNotSatisfied,
+    // 69 83
+    AuthBlocked,
+    // 69 86
+    CommandNotAllowed,
+    // 6A 81
+    FunctionNotSupported,
+    // 6A 82: block or page does not exist
+    AddressNotFound,
+    // 6B 00
+    WrongParameters,
+    // 6C XX: wrong Le, XX is the exact length available
+    WrongLe(u8),
+    // 6D 00
+    InsNotSupported,
+    // 6E 00
+    ClaNotSupported,
+    Unknown,
+}
+
+impl StatusWord {
+    pub fn from_response(resp: &[u8]) -> Option<Self> {
+        if resp.len() < 2 {
+            return None;
+        }
+        Some(Self {
+            sw1: resp[resp.len() - 2],
+            sw2: resp[resp.len() - 1],
+        })
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.kind() == StatusKind::Success
+    }
+
+    pub fn kind(&self) -> StatusKind {
+        match (self.sw1, self.sw2) {
+            (0x90, 0x00) => StatusKind::Success,
+            (0x63, 0x00) => StatusKind::OperationFailed,
+            (0x63, x) if x & 0xF0 == 0xC0 => StatusKind::RetriesLeft(x & 0x0F),
+            (0x67, 0x00) => StatusKind::WrongLength,
+            (0x69, 0x81) => StatusKind::CommandIncompatible,
+            (0x69, 0x82) => StatusKind::SecurityNotSatisfied,
+            (0x69, 0x83) => StatusKind::AuthBlocked,
+            (0x69, 0x86) => StatusKind::CommandNotAllowed,
+            (0x6A, 0x81) => StatusKind::FunctionNotSupported,
+            (0x6A, 0x82) => StatusKind::AddressNotFound,
+            (0x6B, 0x00) => StatusKind::WrongParameters,
+            (0x6C, x) => StatusKind::WrongLe(x),
+            (0x6D, 0x00) => StatusKind::InsNotSupported,
+            (0x6E, 0x00) => StatusKind::ClaNotSupported,
+            _ => StatusKind::Unknown,
+        }
+    }
+
+    pub fn description(&self) -> String {
+        match self.kind() {
+            StatusKind::Success => "Success".into(),
+            StatusKind::OperationFailed => "Operation failed".into(),
+            StatusKind::RetriesLeft(n) => format!("Verification failed, {} retries left", n),
+            StatusKind::WrongLength => "Wrong length".into(),
+            StatusKind::CommandIncompatible => "Command incompatible with card".into(),
+            StatusKind::SecurityNotSatisfied => "Security status not satisfied".into(),
+            StatusKind::AuthBlocked => "Authentication method blocked".into(),
+            StatusKind::CommandNotAllowed => "Command not allowed".into(),
+            StatusKind::FunctionNotSupported => "Function not supported".into(),
+            StatusKind::AddressNotFound => "Block or page not found".into(),
+            StatusKind::WrongParameters => "Wrong parameters P1-P2".into(),
+            StatusKind::WrongLe(n) => format!("Wrong Le, {} bytes available", n),
+            StatusKind::InsNotSupported => "Instruction not supported".into(),
+            StatusKind::ClaNotSupported => "Class not supported".into(),
+            StatusKind::Unknown => "Unknown status".into(),
+        }
+    }
+}
+
+impl fmt::Display for StatusWord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02X}{:02X}", self.sw1, self.sw2)
+    }
+}
+
+// Every failure on the card read/write path
+#[derive(Debug, Clone, PartialEq)]
+pub enum NfcError {
+    Transport(TransportError),
+    // No reader has a card we could connect to
+    NoCard,
+    // The card (or reader) answered a command with a non-9000 status word
+    Status {
+        command: &'static str,
+        sw: StatusWord,
+    },
+    // Response too short to hold a status word
+    MalformedResponse {
+        command: &'static str,
+    },
+    // The tag itself NAKed or timed out a direct-transmit command (PN532 status byte)
+    TagRejected {
+        command: &'static str,
+        status: u8,
+    },
+    // Card type we cannot read or write
+    UnsupportedCard(String),
+    // Message does not fit in the tag's user memory
+    DataTooLarge {
+        needed: usize,
+        capacity: usize,
+    },
+    // No key in the dictionary opened this sector
+    AuthFailed {
+        sector: u8,
+    },
+    // WRITE_DATA with a data_type we do not know how to encode
+    UnsupportedDataType(String),
+    // WRITE_DATA fields that cannot be turned into an NDEF message
+    InvalidWriteData(String),
+    // Sector trailer whose access bits disagree with their inverted copies
+    InvalidAccessBits {
+        sector: u8,
+    },
+    // No known key is allowed to write this sector
+    SectorReadOnly {
+        sector: u8,
+    },
+    // The card does not hold what was just written to it
+    VerifyFailed(String),
+    // Refused to write a trailer that would leave the sector permanently locked
+    WouldLockSector {
+        sector: u8,
+        reason: &'static str,
+    },
+    // The tag is password-protected and no NFC_TAG_SECRET is configured
+    PasswordRequired,
+    // PWD_AUTH was NAKed: the tag has some other password
+    PasswordRejected,
+    // PWD_AUTH passed but the tag answered with the wrong PACK
+    PackMismatch,
+    // LOCK_TAG could not (fully) make the card read-only
+    LockFailed(String),
+    // A dump to restore does not fit the card on the reader, or is corrupt
+    InvalidDump(String),
+}
+
+impl NfcError {
+    // The card or reader went away: never to be taken for a card-level refusal
+    pub fn is_transport(&self) -> bool {
+        matches!(self, NfcError::Transport(_))
+    }
+
+    // Stable machine-readable code for the frontend
+    pub fn code(&self) -> &'static str {
+        match self {
+            NfcError::Transport(TransportError::CardRemoved) => "CARD_REMOVED",
+            NfcError::Transport(TransportError::ReaderUnavailable) => "READER_UNAVAILABLE",
+            NfcError::Transport(TransportError::Other(_)) => "TRANSPORT_ERROR",
+            NfcError::Status { sw, .. } => match sw.kind() {
+                StatusKind::SecurityNotSatisfied | StatusKind::AuthBlocked => "ACCESS_DENIED",
+                StatusKind::AddressNotFound => "ADDRESS_OUT_OF_RANGE",
+                StatusKind::WrongLength | StatusKind::WrongLe(_) => "WRONG_LENGTH",
+                StatusKind::InsNotSupported
+                | StatusKind::ClaNotSupported
+                | StatusKind::FunctionNotSupported
+                | StatusKind::CommandIncompatible => "UNSUPPORTED_COMMAND",
+                _ => "CARD_ERROR",
+            },
+            NfcError::NoCard => "NO_CARD",
+            NfcError::MalformedResponse { .. } => "MALFORMED_RESPONSE",
+            NfcError::TagRejected { .. } => "TAG_REJECTED",
+            NfcError::UnsupportedCard(_) => "UNSUPPORTED_CARD",
+            NfcError::DataTooLarge { .. } => "DATA_TOO_LARGE",
+            NfcError::AuthFailed { .. } => "WRONG_KEY",
+            NfcError::UnsupportedDataType(_) => "UNSUPPORTED_DATA_TYPE",
+            NfcError::InvalidWriteData(_) => "INVALID_WRITE_DATA",
+            NfcError::InvalidAccessBits { .. } => "INVALID_ACCESS_BITS",
+            NfcError::SectorReadOnly { .. } => "SECTOR_READ_ONLY",
+            NfcError::VerifyFailed(_) => "WRITE_VERIFY_FAILED",
+            NfcError::WouldLockSector { .. } => "WOULD_LOCK_SECTOR",
+            NfcError::PasswordRequired => "PASSWORD_REQUIRED",
+            NfcError::PasswordRejected => "WRONG_PASSWORD",
+            NfcError::PackMismatch => "PACK_MISMATCH",
+            NfcError::LockFailed(_) => "LOCK_FAILED",
+            NfcError::InvalidDump(_) => "INVALID_DUMP",
+        }
+    }
+
+    pub fn status_word(&self) -> Option<StatusWord> {
+        match self {
+            NfcError::Status { sw, .. } => Some(*sw),
+            _ => None,
+        }
+    }
+}
+
+impl From<TransportError> for NfcError {
+    fn from(err: TransportError) -> Self {
+        NfcError::Transport(err)
+    }
+}
+
+impl fmt::Display for NfcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NfcError::Transport(e) => write!(f, "{}", e),
+            NfcError::NoCard => write!(f, "No card found on reader"),
+            NfcError::Status { command, sw } => {
+                write!(f, "{} Failed: {} ({})", command, sw.description(), sw)
+            }
+            NfcError::MalformedResponse { command } => {
+                write!(f, "{} Failed: malformed response", command)
+            }
+            NfcError::TagRejected { command, status } => {
+                write!(
+                    f,
+                    "{} Failed: tag rejected command (status {:02X})",
+                    command, status
+                )
+            }
+            NfcError::UnsupportedCard(card) => write!(f, "Unsupported card: {}", card),
+            NfcError::DataTooLarge { needed, capacity } => write!(
+                f,
+                "Data too large for tag: {} bytes needed, {} bytes available",
+                needed, capacity
+            ),
+            NfcError::AuthFailed { sector } => {
+                write!(f, "Auth failed for sector {}: no known key", sector)
+            }
+            NfcError::UnsupportedDataType(data_type) => {
+                write!(f, "Unsupported data_type '{}'", data_type)
+            }
+            NfcError::InvalidWriteData(msg) => write!(f, "Invalid write data: {}", msg),
+            NfcError::InvalidAccessBits { sector } => {
+                write!(f, "Sector {} has inconsistent access bits", sector)
+            }
+            NfcError::SectorReadOnly { sector } => {
+                write!(f, "Sector {} is read-only with the known keys", sector)
+            }
+            NfcError::VerifyFailed(msg) => write!(f, "Write verification failed: {}", msg),
+            NfcError::WouldLockSector { sector, reason } => {
+                write!(f, "Refusing to write sector {} trailer: {}", sector, reason)
+            }
+            NfcError::PasswordRequired => {
+                write!(f, "Tag is password-protected and no secret is configured")
+            }
+            NfcError::PasswordRejected => write!(f, "Tag rejected the password"),
+            NfcError::PackMismatch => {
+                write!(f, "Tag answered the password with an unexpected PACK")
+            }
+            NfcError::LockFailed(msg) => write!(f, "Lock failed: {}", msg),
+            NfcError::InvalidDump(msg) => write!(f, "Invalid dump: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for NfcError {}
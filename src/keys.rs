@@ -0,0 +1,165 @@
+// src/keys.rs
+// MIFARE Classic key map: which key opened which sector, remembered per card UID
+// so repeat reads and writes of a card skip the dictionary search.
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Mutex;
+
+// Key types as used by GENERAL AUTHENTICATE
+pub const KEY_A: u8 = 0x60;
+pub const KEY_B: u8 = 0x61;
+
+// Keys known to open one sector, by key type
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SectorKeys {
+    pub key_a: Option<[u8; 6]>,
+    pub key_b: Option<[u8; 6]>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct KeyMap {
+    sectors: BTreeMap<u8, SectorKeys>,
+}
+
+impl KeyMap {
+    fn slot(keys: &mut SectorKeys, key_type: u8) -> &mut Option<[u8; 6]> {
+        if key_type == KEY_B {
+            &mut keys.key_b
+        } else {
+            &mut keys.key_a
+        }
+    }
+
+    pub fn get(&self, sector: u8, key_type: u8) -> Option<[u8; 6]> {
+        let keys = self.sectors.get(&sector)?;
+        if key_type == KEY_B {
+            keys.key_b
+        } else {
+            keys.key_a
+        }
+    }
+
+    pub fn record(&mut self, sector: u8, key_type: u8, key: [u8; 6]) {
+        *Self::slot(self.sectors.entry(sector).or_default(), key_type) = Some(key);
+    }
+
+    // Drop a key that stopped working (the sector was re-keyed)
+    pub fn forget(&mut self, sector: u8, key_type: u8) {
+        if let Some(keys) = self.sectors.get_mut(&sector) {
+            *Self::slot(keys, key_type) = None;
+            if keys.key_a.is_none() && keys.key_b.is_none() {
+                self.sectors.remove(&sector);
+            }
+        }
+    }
+
+    pub fn sectors(&self) -> impl Iterator<Item = (u8, SectorKeys)> + '_ {
+        self.sectors.iter().map(|(&sector, &keys)| (sector, keys))
+    }
+}
+
+// Cards whose key maps are kept; the least recently used one is dropped beyond this
+const CACHED_CARDS: usize = 256;
+
+// Key maps of recently seen cards, most recently used last
+static CACHE: Mutex<VecDeque<(Vec<u8>, KeyMap)>> = Mutex::new(VecDeque::new());
+
+// Key map for the card with `uid`; empty if the card has not been seen lately
+pub fn cached(uid: &[u8]) -> KeyMap {
+    let mut cache = CACHE.lock().unwrap_or_else(|e| e.into_inner());
+    let Some(entry) = cache
+        .iter()
+        .position(|(seen, _)| seen == uid)
+        .and_then(|pos| cache.remove(pos))
+    else {
+        return KeyMap::default();
+    };
+    let keys = entry.1.clone();
+    cache.push_back(entry);
+    keys
+}
+
+pub fn remember(uid: &[u8], keys: KeyMap) {
+    let mut cache = CACHE.lock().unwrap_or_else(|e| e.into_inner());
+    cache.retain(|(seen, _)| seen != uid);
+    cache.push_back((uid.to_vec(), keys));
+    if cache.len() > CACHED_CARDS {
+        cache.pop_front();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::apdu;
+    use crate::cards;
+    use crate::dictionary;
+    use crate::ndef;
+    use crate::sim::{self, SimModel, SimulatedCard, TestResult, rekey_all_sectors};
+    use crate::types::OutgoingMessage;
+
+    #[test]
+    fn mifare_key_map_reuse() -> TestResult {
+        let _serial = sim::serial();
+        let card = SimulatedCard::new(SimModel::MifareClassic1K, &[0x13, 0x00, 0x00, 0x01]);
+        let kind = sim::write_text(&card, &"k".repeat(100))?;
+        let last_key = dictionary::BUILTIN_KEYS[dictionary::BUILTIN_KEYS.len() - 1];
+        rekey_all_sectors(&card, last_key)?;
+
+        let before = card.key_loads();
+        cards::read_card(&card, kind)?;
+        let first_read = card.key_loads() - before;
+        cards::read_card(&card, kind)?;
+        let second_read = card.key_loads() - before - first_read;
+        // The second read goes straight to the recorded key, one load per sector
+        if second_read * 4 > first_read {
+            return Err(format!(
+                "{} key loads on repeat read vs {} first",
+                second_read, first_read
+            )
+            .into());
+        }
+
+        let uid = apdu::get_uid(&card)?;
+        let report = serde_json::to_value(OutgoingMessage::key_report(&uid, &cached(&uid)))?;
+        assert_eq!(report["type"].as_str(), Some("CARD_KEY_REPORT"));
+        assert_eq!(report["uid"].as_str(), Some("13000001"));
+        assert_eq!(report["sectors"][0]["sector"].as_u64(), Some(0));
+        assert_eq!(report["sectors"][1]["key_a"].as_str(), Some("AABBCCDDEEFF"));
+        Ok(())
+    }
+
+    #[test]
+    fn mifare_key_map_rekeyed() -> TestResult {
+        let _serial = sim::serial();
+        let card = SimulatedCard::new(SimModel::MifareClassic1K, &[0x13, 0x00, 0x00, 0x02]);
+        let kind = sim::write_text(&card, "rekeyed")?;
+        // Change the keys behind the cache's back: the stale entries must be replaced
+        rekey_all_sectors(&card, [0x00; 6])?;
+        assert_eq!(
+            ndef::decode_ndef_content(&cards::read_card(&card, kind)?)?,
+            "rekeyed".to_string()
+        );
+        let uid = apdu::get_uid(&card)?;
+        assert_eq!(cached(&uid).get(1, KEY_A), Some([0x00; 6]));
+        Ok(())
+    }
+
+    #[test]
+    fn key_cache_is_bounded() {
+        let _serial = sim::serial();
+        let mut keys = KeyMap::default();
+        keys.record(0, KEY_A, [0x0A; 6]);
+        let uid = |n: usize| vec![0xEE, (n >> 8) as u8, n as u8, 0x00];
+        remember(&uid(0), keys.clone());
+        remember(&uid(1), keys.clone());
+        for n in 2..CACHED_CARDS {
+            remember(&uid(n), keys.clone());
+        }
+        // Using card 0 keeps it; card 1 is now the least recently used
+        assert_eq!(cached(&uid(0)), keys);
+        remember(&uid(CACHED_CARDS), keys.clone());
+        assert_eq!(cached(&uid(0)), keys);
+        assert_eq!(cached(&uid(1)), KeyMap::default());
+        assert_eq!(cached(&uid(CACHED_CARDS)), keys);
+    }
+}
@@ -0,0 +1,429 @@
+// src/mad.rs
+// NFC Forum NDEF mapping for MIFARE Classic.
+// The MIFARE Application Directory (MAD) in sector 0 (plus sector 16 on 4K cards)
+// lists which sectors belong to the NDEF application; the NDEF TLV runs through
+// those sectors in order, skipping trailers.
+use crate::apdu;
+use crate::cards::{self, ClassicLayout};
+use crate::error::NfcError;
+use crate::keys::{KEY_A, KEY_B, KeyMap};
+use crate::trailer::{self, AccessBits, AccessCondition, SectorTrailer};
+use crate::transport::CardTransport;
+
+// NDEF application ID as it is stored in a MAD slot
+pub const NDEF_AID: [u8; 2] = [0x03, 0xE1];
+const FREE_AID: [u8; 2] = [0x00, 0x00];
+
+// Public keys defined by the mapping
+pub const MAD_KEY_A: [u8; 6] = [0xA0, 0xA1, 0xA2, 0xA3, 0xA4, 0xA5];
+pub const NDEF_KEY_A: [u8; 6] = [0xD3, 0xF7, 0xD3, 0xF7, 0xD3, 0xF7];
+// Key B stays at the factory value so a formatted card can be re-formatted with the dictionary
+const DEFAULT_KEY_B: [u8; 6] = [0xFF; 6];
+
+// MAD sectors (78 77 88): MAD blocks readable with A|B, writable with B; trailer writable with B
+const MAD_ACCESS_BITS: AccessBits = AccessBits([
+    AccessCondition(0b100),
+    AccessCondition(0b100),
+    AccessCondition(0b100),
+    AccessCondition(0b011),
+]);
+// NDEF sectors (7F 07 88): data read/write with A|B; trailer writable with B
+const NDEF_ACCESS_BITS: AccessBits = AccessBits([
+    AccessCondition(0b000),
+    AccessCondition(0b000),
+    AccessCondition(0b000),
+    AccessCondition(0b011),
+]);
+
+// General purpose byte: DA=1, MA=1, MAD version
+const GPB_MAD1: u8 = 0xC1;
+const GPB_MAD2: u8 = 0xC2;
+const GPB_DA: u8 = 0x80;
+const GPB_MAD_VERSION: u8 = 0x03;
+// General purpose byte of NDEF sectors: mapping version 1.0, read/write granted
+const GPB_NDEF_RW: u8 = 0x40;
+
+const MAD_INFO_BYTE: u8 = 0x01;
+pub const MAD2_SECTOR: u8 = 16;
+
+// CRC-8 over the MAD: polynomial x^8 + x^4 + x^3 + x^2 + 1 (0x1D), preset 0xC7
+pub fn crc8(data: &[u8]) -> u8 {
+    let mut crc: u8 = 0xC7;
+    for &byte in data {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x1D
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+fn sector_trailer(key_a: [u8; 6], access: AccessBits, gpb: u8) -> SectorTrailer {
+    SectorTrailer {
+        key_a,
+        access,
+        gpb,
+        key_b: DEFAULT_KEY_B,
+    }
+}
+
+// Build a MAD body: CRC | info byte | one 2-byte AID per sector in `sectors`
+fn encode_mad(aids: &[[u8; 2]]) -> Vec<u8> {
+    let mut body = vec![MAD_INFO_BYTE];
+    for aid in aids {
+        body.extend_from_slice(aid);
+    }
+    let mut mad = vec![crc8(&body)];
+    mad.extend(body);
+    mad
+}
+
+// Check the CRC and split a MAD body into its AIDs
+fn decode_mad(mad: &[u8]) -> Option<Vec<[u8; 2]>> {
+    if mad.len() < 2 || crc8(&mad[1..]) != mad[0] {
+        return None;
+    }
+    Some(mad[2..].chunks(2).map(|c| [c[0], c[1]]).collect())
+}
+
+fn read_raw_blocks(
+    card: &dyn CardTransport,
+    layout: &ClassicLayout,
+    keys: &mut KeyMap,
+    blocks: &[u8],
+) -> Result<Option<Vec<u8>>, NfcError> {
+    match cards::authenticate_sector(card, layout, keys, blocks[0], &[KEY_A, KEY_B]) {
+        Ok(()) => {}
+        // A sector we cannot open simply means "no readable MAD"
+        Err(NfcError::AuthFailed { .. }) => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let mut out = Vec::new();
+    for &block in blocks {
+        out.extend(apdu::read_binary(card, block, 16)?);
+    }
+    Ok(Some(out))
+}
+
+// Read the MAD and return the AID of every sector it covers (index = sector number;
+// the MAD sectors themselves are reported as free). A 4K card with a version 1
+// MAD only covers sectors 0-15. None if the card has no valid MAD.
+pub fn read_mad(
+    card: &dyn CardTransport,
+    layout: &ClassicLayout,
+    keys: &mut KeyMap,
+) -> Result<Option<Vec<[u8; 2]>>, NfcError> {
+    // MAD1: sector 0 blocks 1-2, AIDs for sectors 1-15
+    let mad1 = match read_raw_blocks(card, layout, keys, &[1, 2])? {
+        Some(raw) => raw,
+        None => return Ok(None),
+    };
+    let mut aids = vec![FREE_AID];
+    match decode_mad(&mad1) {
+        Some(slots) => aids.extend(slots),
+        None => return Ok(None),
+    }
+
+    // MAD2 (4K only, when the GPB of sector 0 says MAD version 2): sector 16
+    // blocks 64-66, AIDs for sectors 17-39
+    if layout.sectors > MAD2_SECTOR && announces_mad2(card, layout, keys)? {
+        let first = layout.first_block(MAD2_SECTOR);
+        let mad2 = match read_raw_blocks(card, layout, keys, &[first, first + 1, first + 2])? {
+            Some(raw) => raw,
+            None => return Ok(None),
+        };
+        aids.push(FREE_AID);
+        match decode_mad(&mad2) {
+            Some(slots) => aids.extend(slots),
+            None => return Ok(None),
+        }
+    }
+
+    aids.truncate(layout.sectors as usize);
+    Ok(Some(aids))
+}
+
+// Sector 0's GPB has the DA bit set and MAD version 2. A trailer that cannot be
+// read leaves the MAD1 already read in place; only transport failures are errors.
+fn announces_mad2(
+    card: &dyn CardTransport,
+    layout: &ClassicLayout,
+    keys: &mut KeyMap,
+) -> Result<bool, NfcError> {
+    match trailer::read_trailer(card, layout, keys, 0) {
+        Ok(trailer) => Ok(trailer.gpb & GPB_DA != 0
+            && trailer.gpb & GPB_MAD_VERSION == GPB_MAD2 & GPB_MAD_VERSION),
+        Err(e) if e.is_transport() => Err(e),
+        Err(_) => Ok(false),
+    }
+}
+
+// Data blocks of every sector the MAD assigns to NDEF, in sector order
+pub fn ndef_blocks(layout: &ClassicLayout, aids: &[[u8; 2]]) -> Vec<u8> {
+    aids.iter()
+        .enumerate()
+        .filter(|(_, aid)| **aid == NDEF_AID)
+        .flat_map(|(sector, _)| layout.data_blocks(sector as u8..sector as u8 + 1))
+        .collect()
+}
+
+// Format the card for NDEF: MAD1 (and MAD2 on 4K) listing every other sector as NDEF,
+// public keys and access bits in all trailers, and an empty NDEF TLV in sector 1.
+// Returns the blocks written.
+pub fn format(
+    card: &dyn CardTransport,
+    layout: &ClassicLayout,
+    keys: &mut KeyMap,
+) -> Result<Vec<u8>, NfcError> {
+    let is_ndef_sector =
+        |sector: u8| sector != 0 && sector != MAD2_SECTOR && sector < layout.sectors;
+    let slot = |sector: u8| {
+        if is_ndef_sector(sector) {
+            NDEF_AID
+        } else {
+            FREE_AID
+        }
+    };
+
+    // MAD1 lives in blocks 1-2
+    let mad1_aids: Vec<[u8; 2]> = (1..16).map(slot).collect();
+    let mad1 = encode_mad(&mad1_aids);
+    let has_mad2 = layout.sectors > MAD2_SECTOR;
+    let mut touched = Vec::new();
+
+    for (block, chunk) in [1u8, 2].iter().zip(mad1.chunks(16)) {
+        let mut data = [0u8; 16];
+        data.copy_from_slice(chunk);
+        cards::write_block_any_key(card, layout, keys, *block, &data)?;
+        touched.push(*block);
+    }
+    let gpb = if has_mad2 { GPB_MAD2 } else { GPB_MAD1 };
+    trailer::write_trailer(
+        card,
+        layout,
+        keys,
+        0,
+        &sector_trailer(MAD_KEY_A, MAD_ACCESS_BITS, gpb),
+    )?;
+    touched.push(layout.trailer_block(0));
+
+    // MAD2 lives in the first three blocks of sector 16
+    if has_mad2 {
+        let mad2_aids: Vec<[u8; 2]> = (17..40).map(slot).collect();
+        let mad2 = encode_mad(&mad2_aids);
+        let first = layout.first_block(MAD2_SECTOR);
+        for (i, chunk) in mad2.chunks(16).enumerate() {
+            let mut data = [0u8; 16];
+            data.copy_from_slice(chunk);
+            cards::write_block_any_key(card, layout, keys, first + i as u8, &data)?;
+            touched.push(first + i as u8);
+        }
+        trailer::write_trailer(
+            card,
+            layout,
+            keys,
+            MAD2_SECTOR,
+            &sector_trailer(MAD_KEY_A, MAD_ACCESS_BITS, GPB_MAD2),
+        )?;
+        touched.push(layout.trailer_block(MAD2_SECTOR));
+    }
+
+    // Empty NDEF message (03 00) followed by the terminator at the start of sector 1
+    let mut empty_tlv = [0u8; 16];
+    empty_tlv[0..3].copy_from_slice(&[0x03, 0x00, 0xFE]);
+    cards::write_block_any_key(card, layout, keys, layout.first_block(1), &empty_tlv)?;
+    touched.push(layout.first_block(1));
+
+    for sector in (1..layout.sectors).filter(|s| is_ndef_sector(*s)) {
+        trailer::write_trailer(
+            card,
+            layout,
+            keys,
+            sector,
+            &sector_trailer(NDEF_KEY_A, NDEF_ACCESS_BITS, GPB_NDEF_RW),
+        )?;
+        touched.push(layout.trailer_block(sector));
+    }
+    touched.sort_unstable();
+    Ok(touched)
+}
+
+// Read the NDEF sectors listed in the MAD. None if the card has no MAD.
+pub fn read_ndef(
+    card: &dyn CardTransport,
+    layout: &ClassicLayout,
+    keys: &mut KeyMap,
+) -> Result<Option<Vec<u8>>, NfcError> {
+    match read_mad(card, layout, keys)? {
+        Some(aids) => {
+            let blocks = ndef_blocks(layout, &aids);
+            cards::read_mifare_blocks(card, layout, keys, &blocks).map(Some)
+        }
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::apdu;
+    use crate::atr::{self, CardKind};
+    use crate::cards::{self, ClassicLayout, WriteOptions};
+    use crate::keys::{self, KeyMap};
+    use crate::ndef;
+    use crate::sim::{self, SimModel, SimulatedCard, TestResult};
+
+    #[test]
+    fn mad_crc() -> TestResult {
+        // MAD1 of a 1K card with every sector assigned to NDEF
+        let mut mad1 = vec![0x01];
+        for _ in 0..15 {
+            mad1.extend_from_slice(&NDEF_AID);
+        }
+        assert_eq!(crc8(&mad1), 0x14);
+        let mut mad2 = vec![0x01];
+        for _ in 0..23 {
+            mad2.extend_from_slice(&NDEF_AID);
+        }
+        assert_eq!(crc8(&mad2), 0xE8);
+        Ok(())
+    }
+
+    #[test]
+    fn mifare_nfc_forum_write_read() -> TestResult {
+        let _serial = sim::serial();
+        let options = WriteOptions {
+            nfc_forum: true,
+            ..WriteOptions::default()
+        };
+        for (model, sectors) in [
+            (SimModel::MifareMini, 4),
+            (SimModel::MifareClassic1K, 15),
+            (SimModel::MifareClassic4K, 38),
+        ] {
+            let card = SimulatedCard::mifare_classic(model);
+            let kind = atr::parse_atr(&card.atr());
+            let layout = ClassicLayout::for_kind(kind).ok_or("not a Classic layout")?;
+            let tlv = sim::text_tlv("NDEF-0001");
+            sim::write_card(&card, kind, &tlv, &options)?;
+
+            // The write left the keys it used (including the new NDEF keys) in the cache
+            let mut keys = keys::cached(&apdu::get_uid(&card)?);
+            let aids = read_mad(&card, &layout, &mut keys)?.ok_or("no MAD after formatting")?;
+            let ndef_sectors = aids.iter().filter(|aid| **aid == NDEF_AID).count();
+            assert_eq!(ndef_sectors, sectors);
+            // NDEF sectors now open with the public NFC Forum key
+            apdu::load_key(&card, &NDEF_KEY_A)?;
+            apdu::authenticate(&card, layout.first_block(1), KEY_A)?;
+            assert_eq!(
+                ndef::decode_ndef_content(&cards::read_card(&card, kind)?)?,
+                "NDEF-0001".to_string(),
+            );
+
+            // A second write goes through the existing MAD, even without the flag
+            sim::write_text(&card, "NDEF-0002")?;
+            assert_eq!(
+                ndef::decode_ndef_content(&cards::read_card(&card, kind)?)?,
+                "NDEF-0002".to_string(),
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn mifare_reads_through_mad() -> TestResult {
+        let _serial = sim::serial();
+        // 1K card whose MAD only lists sectors 2 and 5: sector 1 holds unrelated data
+        let card = SimulatedCard::mifare_classic_1k();
+        let mut keys = KeyMap::default();
+        let layout = ClassicLayout::CLASSIC_1K;
+        format(&card, &layout, &mut keys)?;
+        let mut aids = vec![0x01];
+        for sector in 1..16 {
+            let aid = if sector == 2 || sector == 5 {
+                NDEF_AID
+            } else {
+                [0x00, 0x00]
+            };
+            aids.extend_from_slice(&aid);
+        }
+        let mut mad1 = vec![crc8(&aids)];
+        mad1.extend(aids);
+        for (block, chunk) in [1u8, 2].iter().zip(mad1.chunks(16)) {
+            cards::write_block_any_key(&card, &layout, &mut keys, *block, chunk.try_into()?)?;
+        }
+        cards::write_block_any_key(&card, &layout, &mut keys, 4, &[0xAA; 16])?;
+
+        let user_id = "spread-over-two-non-adjacent-sectors-".repeat(2);
+        sim::write_text(&card, &user_id)?;
+        let tlv = sim::text_tlv(&user_id);
+        assert_eq!(
+            ndef::decode_ndef_content(&cards::read_card(&card, CardKind::MifareClassic1K)?)?,
+            user_id,
+        );
+        // The data landed in sectors 2 and 5, not in the unlisted sector 1
+        apdu::load_key(&card, &NDEF_KEY_A)?;
+        apdu::authenticate(&card, 20, KEY_A)?;
+        assert_eq!(
+            apdu::read_binary(&card, 20, 16)?[0..2].to_vec(),
+            tlv[16 * 3..16 * 3 + 2].to_vec()
+        );
+        apdu::authenticate(&card, 4, KEY_A)?;
+        assert_eq!(apdu::read_binary(&card, 4, 16)?, vec![0xAA; 16]);
+        Ok(())
+    }
+
+    #[test]
+    fn mifare_4k_mad1_only() -> TestResult {
+        let _serial = sim::serial();
+        // 4K cards with a MAD1 listing sector 3 only; sector 16 is plain data. Sector 0's
+        // GPB says version 1, or version 2 without the DA bit that marks a MAD as present.
+        for (uid, gpb) in [(0x01, GPB_MAD1), (0x02, GPB_MAD2 & !GPB_DA)] {
+            let card = SimulatedCard::new(SimModel::MifareClassic4K, &[0x11, 0x00, 0x00, uid]);
+            let mut keys = KeyMap::default();
+            let layout = ClassicLayout::CLASSIC_4K;
+            let aids: Vec<[u8; 2]> = (1..16)
+                .map(|sector| if sector == 3 { NDEF_AID } else { FREE_AID })
+                .collect();
+            for (block, chunk) in [1u8, 2].iter().zip(encode_mad(&aids).chunks(16)) {
+                cards::write_block_any_key(&card, &layout, &mut keys, *block, chunk.try_into()?)?;
+            }
+            trailer::write_trailer(
+                &card,
+                &layout,
+                &mut keys,
+                0,
+                &sector_trailer(MAD_KEY_A, MAD_ACCESS_BITS, gpb),
+            )?;
+            cards::write_block_any_key(
+                &card,
+                &layout,
+                &mut keys,
+                layout.first_block(MAD2_SECTOR),
+                &[0xAA; 16],
+            )?;
+
+            let aids =
+                read_mad(&card, &layout, &mut keys)?.ok_or("MAD1 not accepted without a MAD2")?;
+            assert_eq!(aids.len(), 16);
+            sim::write_text(&card, "mad version 1")?;
+            assert_eq!(
+                ndef::decode_ndef_content(&cards::read_card(&card, CardKind::MifareClassic4K)?)?,
+                "mad version 1".to_string(),
+            );
+            // Erasing clears sector 16 like any other data sector
+            cards::erase_card(&card, CardKind::MifareClassic4K)?;
+            apdu::load_key(&card, &[0xFF; 6])?;
+            apdu::authenticate(&card, layout.first_block(MAD2_SECTOR), KEY_A)?;
+            assert_eq!(
+                apdu::read_binary(&card, layout.first_block(MAD2_SECTOR), 16)?,
+                vec![0x00; 16]
+            );
+        }
+        Ok(())
+    }
+}
@@ -1,7 +1,18 @@
 mod apdu;
+mod atr;
 mod cards;
+mod dictionary;
+mod dump;
+mod error;
+mod keys;
+mod mad;
 mod ndef;
 mod nfc_service;
+mod password;
+#[cfg(test)]
+mod sim;
+mod trailer;
+mod transport;
 mod types;
 mod ws;
 
@@ -11,15 +22,34 @@ use tokio::sync::broadcast;
 #[tokio::main]
 async fn main() {
     env_logger::init();
+
+    // `dump <file>` / `restore <file>` copy the card on the reader to or from a
+    // JSON dump or a .mfd / .bin image, then exit
+    let args: Vec<String> = std::env::args().collect();
+    if let Some(command @ ("dump" | "restore")) = args.get(1).map(String::as_str) {
+        dictionary::init_from_env();
+        password::init_from_env();
+        if let Err(e) = dump::cli(command, args.get(2).map(String::as_str)) {
+            eprintln!("{} failed: {}", command, e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     println!("Starting NFC Rust Service...");
 
+    // MIFARE Classic keys from NFC_KEY_FILE (reloaded on change), built-in keys otherwise
+    dictionary::init_from_env();
+    // Deployment secret the NTAG passwords are derived from
+    password::init_from_env();
+
     // Channel: WS -> NFC (Commands)
     // We use Crossbeam (Sync) because NFC thread is blocking
-    let (cmd_tx, cmd_rx) = unbounded::<types::NfcCommand>();
+    let (cmd_tx, cmd_rx) = unbounded::<types::NfcRequest>();
 
     // Channel: NFC -> WS (Events)
     // We use Tokio Broadcast for distribution to WS clients
-    let (event_tx, event_rx) = broadcast::channel::<types::OutgoingMessage>(100);
+    let (event_tx, event_rx) = broadcast::channel::<types::NfcEvent>(100);
 
     // Spawn NFC Thread (Blocking OS Thread)
     let event_tx_clone = event_tx.clone();
@@ -31,7 +61,7 @@ async fn main() {
         // Wait, tokio broadcast send is sync, but we need to feed it from the NFC thread.
         // Let's use a crossbeam channel to bridge NFC thread -> Main Async Task -> Broadcast
 
-        let (bridge_tx, bridge_rx) = unbounded::<types::OutgoingMessage>();
+        let (bridge_tx, bridge_rx) = unbounded::<types::NfcEvent>();
 
         // Spawn the NFC logic
         std::thread::spawn(move || {
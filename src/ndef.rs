@@ -1,9 +1,9 @@
 // src/ndef.rs
 use std::str;
 
-// Basic NDEF Text Record Wrapper
-pub fn create_text_record_payload(text: &str) -> Vec<u8> {
-    let lang = b"en";
+// Text payload with an explicit IANA language code (at most 63 bytes)
+pub fn create_text_payload(lang: &str, text: &str) -> Vec<u8> {
+    let lang = lang.as_bytes();
     let lang_len = lang.len() as u8;
     let text_bytes = text.as_bytes();
 
@@ -15,92 +15,419 @@ pub fn create_text_record_payload(text: &str) -> Vec<u8> {
     payload
 }
 
-pub fn encode_ndef_message(text: &str) -> Vec<u8> {
-    let payload = create_text_record_payload(text);
+// Serialise records into one NDEF message, setting MB on the first and ME on the last.
+// NDEF Header: MB | ME | CF | SR | IL | TNF (3 bits)
+// e.g. a lone short Text record is 0xD1 = 1101 0001
+// Payloads over 255 bytes drop SR and use a 4-byte length.
+pub fn encode_ndef_records(records: &[NdefRecord]) -> Vec<u8> {
+    let mut msg = Vec::new();
+    for (i, rec) in records.iter().enumerate() {
+        let short = rec.payload.len() <= 0xFF;
 
-    // NDEF Header: MB=1, ME=1, CF=0, SR=1, IL=0, TNF=001 (NFC Forum Well Known Type)
-    // 0xD1 = 1101 0001
-    let header = 0xD1;
-    let type_field = b"T"; // 'T' for Text
+        let mut header = rec.tnf as u8;
+        if i == 0 {
+            header |= FLAG_MB;
+        }
+        if i == records.len() - 1 {
+            header |= FLAG_ME;
+        }
+        if short {
+            header |= FLAG_SR;
+        }
+        if !rec.id.is_empty() {
+            header |= FLAG_IL;
+        }
 
-    let mut record = Vec::new();
-    record.push(header);
-    record.push(type_field.len() as u8); // Type Length
-    record.push(payload.len() as u8); // Payload Length (assuming short record < 255)
-    record.extend_from_slice(type_field);
-    record.extend_from_slice(&payload);
+        msg.push(header);
+        msg.push(rec.record_type.len() as u8); // Type Length
+        if short {
+            msg.push(rec.payload.len() as u8); // Payload Length
+        } else {
+            msg.extend_from_slice(&(rec.payload.len() as u32).to_be_bytes());
+        }
+        if !rec.id.is_empty() {
+            msg.push(rec.id.len() as u8); // ID Length
+        }
+        msg.extend_from_slice(&rec.record_type);
+        msg.extend_from_slice(&rec.id);
+        msg.extend_from_slice(&rec.payload);
+    }
+    msg
+}
+
+// NFC Forum URI Record Type Definition: identifier code -> abbreviated prefix
+pub const URI_PREFIXES: [&str; 36] = [
+    "",
+    "http://www.",
+    "https://www.",
+    "http://",
+    "https://",
+    "tel:",
+    "mailto:",
+    "ftp://anonymous:anonymous@",
+    "ftp://ftp.",
+    "ftps://",
+    "sftp://",
+    "smb://",
+    "nfs://",
+    "ftp://",
+    "dav://",
+    "news:",
+    "telnet://",
+    "imap:",
+    "rtsp://",
+    "urn:",
+    "pop:",
+    "sip:",
+    "sips:",
+    "tftp:",
+    "btspp://",
+    "btl2cap://",
+    "btgoep://",
+    "tcpobex://",
+    "irdaobex://",
+    "file://",
+    "urn:epc:id:",
+    "urn:epc:tag:",
+    "urn:epc:pat:",
+    "urn:epc:raw:",
+    "urn:epc:",
+    "urn:nfc:",
+];
 
-    record
+// URI payload: identifier code | rest of the URI
+// Picks the longest matching prefix so "https://www." wins over "https://"
+pub fn create_uri_record_payload(uri: &str) -> Vec<u8> {
+    let (code, prefix) = URI_PREFIXES
+        .iter()
+        .enumerate()
+        .skip(1)
+        .filter(|(_, p)| uri.starts_with(*p))
+        .max_by_key(|(_, p)| p.len())
+        .map(|(i, p)| (i as u8, *p))
+        .unwrap_or((0x00, ""));
+
+    let mut payload = vec![code];
+    payload.extend_from_slice(&uri.as_bytes()[prefix.len()..]);
+    payload
 }
 
+fn decode_uri_payload(payload: &[u8]) -> Result<String, String> {
+    let (&code, rest) = payload.split_first().ok_or("Empty Payload")?;
+    // Codes past the table are RFU and treated as "no prefix"
+    let prefix = URI_PREFIXES.get(code as usize).copied().unwrap_or("");
+    let rest = str::from_utf8(rest).map_err(|_| "UTF-8 Decode Error".to_string())?;
+    Ok(format!("{}{}", prefix, rest))
+}
+
+// TLV block types (NFC Forum Type 2 Tag / MIFARE Classic mapping)
+pub const TLV_NULL: u8 = 0x00;
+pub const TLV_NDEF: u8 = 0x03;
+pub const TLV_TERMINATOR: u8 = 0xFE;
+
 pub fn wrap_in_tlv(ndef_bytes: &[u8]) -> Vec<u8> {
     let mut tlv = Vec::new();
     // T = 0x03 (NDEF Message)
-    tlv.push(0x03);
+    tlv.push(TLV_NDEF);
 
-    // L (Length)
-    if ndef_bytes.len() < 255 {
+    // L (Length): 1 byte up to 254, otherwise 0xFF followed by a 2-byte big-endian length
+    if ndef_bytes.len() < 0xFF {
         tlv.push(ndef_bytes.len() as u8);
     } else {
-        // Simple implementation: we assume short messages for this user ID use case
         tlv.push(0xFF);
-        // Real implementation would handle multi-byte length, but 1K/NTAG usually small
+        tlv.extend_from_slice(&(ndef_bytes.len() as u16).to_be_bytes());
     }
 
     // V (Value)
     tlv.extend_from_slice(ndef_bytes);
 
     // Terminator
-    tlv.push(0xFE);
+    tlv.push(TLV_TERMINATOR);
 
     tlv
 }
 
-pub fn decode_ndef_text(buffer: &[u8]) -> Result<String, String> {
-    // 1. Find NDEF TLV (0x03)
-    let start = buffer
+// Read a TLV length field at `pos`.
+// Returns (length, number of bytes the length field used), or None if truncated.
+pub fn read_tlv_length(buffer: &[u8], pos: usize) -> Option<(usize, usize)> {
+    match *buffer.get(pos)? {
+        0xFF => {
+            let hi = *buffer.get(pos + 1)? as usize;
+            let lo = *buffer.get(pos + 2)? as usize;
+            Some(((hi << 8) | lo, 3))
+        }
+        len => Some((len as usize, 1)),
+    }
+}
+
+// Walk the TLV blocks and return the value of the first NDEF Message TLV.
+// NULL TLVs are single padding bytes; Lock/Memory Control, proprietary and
+// unknown TLVs are skipped using their length.
+pub fn find_ndef_tlv(buffer: &[u8]) -> Result<&[u8], String> {
+    let mut pos = 0;
+    while pos < buffer.len() {
+        let tag = buffer[pos];
+        match tag {
+            TLV_NULL => {
+                pos += 1;
+                continue;
+            }
+            TLV_TERMINATOR => break,
+            _ => {}
+        }
+
+        let (len, len_size) = read_tlv_length(buffer, pos + 1).ok_or("Invalid buffer length")?;
+        let start_data = pos + 1 + len_size;
+        if start_data + len > buffer.len() {
+            return Err("Incomplete data".to_string());
+        }
+
+        match tag {
+            TLV_NDEF => return Ok(&buffer[start_data..start_data + len]),
+            // Lock/Memory Control (01/02), proprietary (FD) and unknown TLVs
+            _ => pos = start_data + len,
+        }
+    }
+    Err("No NDEF TLV found".to_string())
+}
+
+// How many bytes of a tag's TLV area a read needs: up to the end of the first
+// NDEF TLV's value, or through the terminator. None while `buffer` is too short
+// to tell, so blank memory (NULL TLVs only) is read up to the capacity. Bytes
+// inside TLV values are never inspected, so zeros in a payload do not end the read.
+pub fn tlv_area_end(buffer: &[u8]) -> Option<usize> {
+    let mut pos = 0;
+    while pos < buffer.len() {
+        let tag = buffer[pos];
+        match tag {
+            TLV_NULL => {
+                pos += 1;
+                continue;
+            }
+            TLV_TERMINATOR => return Some(pos + 1),
+            _ => {}
+        }
+
+        let (len, len_size) = read_tlv_length(buffer, pos + 1)?;
+        let end = pos + 1 + len_size + len;
+        if tag == TLV_NDEF {
+            return Some(end);
+        }
+        pos = end;
+    }
+    None
+}
+
+// Decode the first Text or URI record: the user-facing content of the tag
+pub fn decode_ndef_content(buffer: &[u8]) -> Result<String, String> {
+    let ndef_msg = find_ndef_tlv(buffer)?;
+    let records = parse_ndef_message(ndef_msg)?;
+    records
         .iter()
-        .position(|&b| b == 0x03)
-        .ok_or("No NDEF TLV found")?;
+        .find_map(|r| {
+            r.decode_text()
+                .map(|(_lang, text)| text)
+                .or_else(|| r.decode_uri())
+        })
+        .ok_or_else(|| "No Text or URI record found".to_string())
+}
+
+// Type Name Format (3 low bits of the record header)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Tnf {
+    Empty = 0x00,
+    WellKnown = 0x01,
+    MediaType = 0x02,
+    AbsoluteUri = 0x03,
+    External = 0x04,
+    Unknown = 0x05,
+    Unchanged = 0x06,
+    Reserved = 0x07,
+}
 
-    // Safety check for length index
-    if start + 1 >= buffer.len() {
-        return Err("Invalid buffer length".to_string());
+impl Tnf {
+    fn from_bits(bits: u8) -> Self {
+        match bits & 0x07 {
+            0x00 => Tnf::Empty,
+            0x01 => Tnf::WellKnown,
+            0x02 => Tnf::MediaType,
+            0x03 => Tnf::AbsoluteUri,
+            0x04 => Tnf::External,
+            0x05 => Tnf::Unknown,
+            0x06 => Tnf::Unchanged,
+            _ => Tnf::Reserved,
+        }
     }
+}
+
+// Record header flags
+const FLAG_MB: u8 = 0x80; // Message Begin
+const FLAG_ME: u8 = 0x40; // Message End
+const FLAG_CF: u8 = 0x20; // Chunk Flag
+const FLAG_SR: u8 = 0x10; // Short Record (1-byte payload length)
+const FLAG_IL: u8 = 0x08; // ID Length present
 
-    let len = buffer[start + 1] as usize;
-    let start_data = start + 2;
+// One logical NDEF record (chunked records are already reassembled)
+#[derive(Debug, Clone, PartialEq)]
+pub struct NdefRecord {
+    pub tnf: Tnf,
+    pub record_type: Vec<u8>,
+    pub id: Vec<u8>,
+    pub payload: Vec<u8>,
+}
 
-    if start_data + len > buffer.len() {
-        return Err("Incomplete data".to_string());
+impl NdefRecord {
+    pub fn well_known(record_type: &[u8], payload: Vec<u8>) -> Self {
+        Self {
+            tnf: Tnf::WellKnown,
+            record_type: record_type.to_vec(),
+            id: Vec::new(),
+            payload,
+        }
     }
 
-    let ndef_msg = &buffer[start_data..start_data + len];
+    pub fn mime(content_type: &str, payload: Vec<u8>) -> Self {
+        Self {
+            tnf: Tnf::MediaType,
+            record_type: content_type.as_bytes().to_vec(),
+            id: Vec::new(),
+            payload,
+        }
+    }
 
-    // 2. Parse NDEF Record (Assuming single Text Record for this specific use case)
-    if ndef_msg.is_empty() {
-        return Err("Empty NDEF".to_string());
+    pub fn is_well_known(&self, record_type: &[u8]) -> bool {
+        self.tnf == Tnf::WellKnown && self.record_type == record_type
+    }
+
+    // Decode a Well Known 'T' record into (language code, text)
+    pub fn decode_text(&self) -> Option<(String, String)> {
+        if !self.is_well_known(b"T") {
+            return None;
+        }
+        decode_text_payload(&self.payload).ok()
     }
 
-    // Skip Header (byte 0) and Type Length (byte 1)
-    if ndef_msg.len() < 3 {
-        return Err("Invalid NDEF Header".to_string());
+    // Decode a Well Known 'U' record into the expanded URI
+    pub fn decode_uri(&self) -> Option<String> {
+        if !self.is_well_known(b"U") {
+            return None;
+        }
+        decode_uri_payload(&self.payload).ok()
+    }
+}
+
+// Cursor over the raw message bytes
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        if self.pos + n > self.buf.len() {
+            return Err("Truncated NDEF record".to_string());
+        }
+        let out = &self.buf[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(out)
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+}
+
+// Parse a raw NDEF message (TLV value) into its records.
+// Handles MB/ME/CF/SR/IL flags, 4-byte payload lengths, ID fields and chunked records.
+pub fn parse_ndef_message(msg: &[u8]) -> Result<Vec<NdefRecord>, String> {
+    if msg.is_empty() {
+        return Err("Empty NDEF".to_string());
     }
-    let _header = ndef_msg[0];
-    let type_len = ndef_msg[1] as usize;
-    let payload_len = ndef_msg[2] as usize;
 
-    // Calculate offsets
-    let type_start = 3;
-    let payload_start = type_start + type_len;
+    let mut reader = Reader { buf: msg, pos: 0 };
+    let mut records = Vec::new();
+    // Record being reassembled from chunks
+    let mut chunked: Option<NdefRecord> = None;
+    let mut first = true;
+
+    loop {
+        let header = reader.byte()?;
+        let tnf = Tnf::from_bits(header);
+
+        if first && header & FLAG_MB == 0 {
+            return Err("First record missing MB flag".to_string());
+        }
+        if !first && header & FLAG_MB != 0 {
+            return Err("Unexpected MB flag inside message".to_string());
+        }
+        first = false;
+
+        let type_len = reader.byte()? as usize;
+        let payload_len = if header & FLAG_SR != 0 {
+            reader.byte()? as usize
+        } else {
+            let b = reader.take(4)?;
+            u32::from_be_bytes([b[0], b[1], b[2], b[3]]) as usize
+        };
+        let id_len = if header & FLAG_IL != 0 {
+            reader.byte()? as usize
+        } else {
+            0
+        };
+
+        let record_type = reader.take(type_len)?.to_vec();
+        let id = reader.take(id_len)?.to_vec();
+        let payload = reader.take(payload_len)?;
+
+        match chunked.as_mut() {
+            // Middle or terminating chunk: TNF must be Unchanged with no type
+            Some(rec) => {
+                if tnf != Tnf::Unchanged || type_len != 0 || id_len != 0 {
+                    return Err("Invalid NDEF chunk".to_string());
+                }
+                rec.payload.extend_from_slice(payload);
+                if header & FLAG_CF == 0 {
+                    records.push(chunked.take().unwrap());
+                }
+            }
+            None => {
+                if tnf == Tnf::Unchanged {
+                    return Err("Unchanged TNF outside a chunked record".to_string());
+                }
+                if tnf == Tnf::Empty && (type_len != 0 || id_len != 0 || payload_len != 0) {
+                    return Err("Empty record with non-empty fields".to_string());
+                }
+                let rec = NdefRecord {
+                    tnf,
+                    record_type,
+                    id,
+                    payload: payload.to_vec(),
+                };
+                if header & FLAG_CF != 0 {
+                    chunked = Some(rec);
+                } else {
+                    records.push(rec);
+                }
+            }
+        }
 
-    if payload_start + payload_len > ndef_msg.len() {
-        return Err("Invalid payload structure".to_string());
+        if header & FLAG_ME != 0 {
+            if chunked.is_some() {
+                return Err("Message ended inside a chunked record".to_string());
+            }
+            break;
+        }
+        if reader.pos >= msg.len() {
+            return Err("NDEF message missing ME flag".to_string());
+        }
     }
 
-    let payload = &ndef_msg[payload_start..payload_start + payload_len];
+    Ok(records)
+}
 
-    // 3. Decode Text Payload
+// Text payload: status byte | language code | text
+// Status byte bit 7 = UTF-16, bits 0-5 = language code length
+fn decode_text_payload(payload: &[u8]) -> Result<(String, String), String> {
     if payload.is_empty() {
         return Err("Empty Payload".to_string());
     }
@@ -113,9 +440,217 @@ pub fn decode_ndef_text(buffer: &[u8]) -> Result<String, String> {
         return Err("Invalid Text Payload".to_string());
     }
 
+    let lang = String::from_utf8_lossy(&payload[1..text_start]).into_owned();
     let text_bytes = &payload[text_start..];
 
-    str::from_utf8(text_bytes)
-        .map(|s| s.to_string())
-        .map_err(|_| "UTF-8 Decode Error".to_string())
+    let text = if status_byte & 0x80 != 0 {
+        decode_utf16(text_bytes)?
+    } else {
+        str::from_utf8(text_bytes)
+            .map(|s| s.to_string())
+            .map_err(|_| "UTF-8 Decode Error".to_string())?
+    };
+    Ok((lang, text))
+}
+
+// UTF-16 text, big-endian unless a little-endian BOM is present
+fn decode_utf16(bytes: &[u8]) -> Result<String, String> {
+    if !bytes.len().is_multiple_of(2) {
+        return Err("UTF-16 Decode Error".to_string());
+    }
+    let (little_endian, body) = match bytes {
+        [0xFF, 0xFE, rest @ ..] => (true, rest),
+        [0xFE, 0xFF, rest @ ..] => (false, rest),
+        _ => (false, bytes),
+    };
+    let units: Vec<u16> = body
+        .chunks(2)
+        .map(|c| {
+            if little_endian {
+                u16::from_le_bytes([c[0], c[1]])
+            } else {
+                u16::from_be_bytes([c[0], c[1]])
+            }
+        })
+        .collect();
+    String::from_utf16(&units).map_err(|_| "UTF-16 Decode Error".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cards::{self, ClassicLayout};
+    use crate::keys::KeyMap;
+    use crate::sim::{self, SimModel, SimulatedCard, TestResult};
+
+    fn uri_message(uri: &str) -> Vec<u8> {
+        encode_ndef_records(&[NdefRecord::well_known(b"U", create_uri_record_payload(uri))])
+    }
+
+    #[test]
+    fn ndef_text_round_trip() -> TestResult {
+        let tlv = sim::text_tlv("user-42");
+        assert_eq!(decode_ndef_content(&tlv)?, "user-42".to_string());
+        Ok(())
+    }
+
+    #[test]
+    fn ndef_multi_record() -> TestResult {
+        // Short URI record with an ID field, then a long-form (SR=0) Text record
+        let mut msg = vec![0x99, 0x01, 0x08, 0x02, b'U', b'i', b'd'];
+        msg.extend_from_slice(&[0x04]);
+        msg.extend_from_slice(b"a.b/c/d");
+        msg.extend_from_slice(&[0x41, 0x01, 0x00, 0x00, 0x00, 0x06, b'T', 0x02, b'e', b'n']);
+        msg.extend_from_slice(b"abc");
+
+        let records = parse_ndef_message(&msg)?;
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].tnf, Tnf::WellKnown);
+        assert_eq!(records[0].id.clone(), b"id".to_vec());
+        assert_eq!(records[0].payload.len(), 8);
+        assert_eq!(
+            records[1].decode_text(),
+            Some(("en".to_string(), "abc".to_string())),
+        );
+
+        let tlv = wrap_in_tlv(&msg);
+        assert_eq!(find_ndef_tlv(&tlv)?, &msg[..]);
+        Ok(())
+    }
+
+    #[test]
+    fn ndef_chunked_record() -> TestResult {
+        // MIME record split into three chunks: "hel" + "lo " + "world"
+        let mut msg = vec![0xB2, 0x0A, 0x03];
+        msg.extend_from_slice(b"text/plain");
+        msg.extend_from_slice(b"hel");
+        msg.extend_from_slice(&[0x36, 0x00, 0x03]);
+        msg.extend_from_slice(b"lo ");
+        msg.extend_from_slice(&[0x56, 0x00, 0x05]);
+        msg.extend_from_slice(b"world");
+
+        let records = parse_ndef_message(&msg)?;
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].tnf, Tnf::MediaType);
+        assert_eq!(records[0].record_type.clone(), b"text/plain".to_vec());
+        assert_eq!(records[0].payload.clone(), b"hello world".to_vec());
+        Ok(())
+    }
+
+    #[test]
+    fn ndef_malformed() -> TestResult {
+        let bad: [&[u8]; 4] = [
+            // Truncated payload
+            &[0xD1, 0x01, 0x05, b'T', 0x02],
+            // No MB on first record
+            &[0x51, 0x01, 0x00, b'T'],
+            // No ME before the end
+            &[0x91, 0x01, 0x00, b'T'],
+            // Unchanged TNF without a preceding chunk
+            &[0xD6, 0x00, 0x00],
+        ];
+        for msg in bad {
+            if parse_ndef_message(msg).is_ok() {
+                return Err(format!("accepted malformed message {:02X?}", msg).into());
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn ndef_uri_prefixes() -> TestResult {
+        let _serial = sim::serial();
+        let cases = [
+            ("https://www.example.com/badge", 0x02, "example.com/badge"),
+            ("https://example.com", 0x04, "example.com"),
+            ("tel:+15551234", 0x05, "+15551234"),
+            ("urn:epc:id:sgtin:1", 0x1E, "sgtin:1"),
+            ("custom-scheme:x", 0x00, "custom-scheme:x"),
+        ];
+        for (uri, code, rest) in cases {
+            let payload = create_uri_record_payload(uri);
+            assert_eq!(payload[0], code);
+            assert_eq!(&payload[1..], rest.as_bytes());
+
+            let tlv = wrap_in_tlv(&uri_message(uri));
+            assert_eq!(decode_ndef_content(&tlv)?, uri.to_string());
+        }
+
+        let card = SimulatedCard::ntag(SimModel::Ntag213);
+        let tlv = wrap_in_tlv(&uri_message("https://kiosk.example.org/t/42"));
+        cards::write_ntag(&card, &tlv)?;
+        let raw = cards::read_ntag(&card)?;
+        assert_eq!(
+            decode_ndef_content(&raw)?,
+            "https://kiosk.example.org/t/42".to_string(),
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn tlv_long_length() -> TestResult {
+        let _serial = sim::serial();
+        let user_id = "L".repeat(300);
+        let tlv = sim::text_tlv(&user_id);
+        assert_eq!(tlv[1..4].to_vec(), vec![0xFF, 0x01, 0x36]);
+        assert_eq!(decode_ndef_content(&tlv)?, user_id.clone());
+
+        let card = SimulatedCard::ntag(SimModel::Ntag215);
+        cards::write_ntag(&card, &tlv)?;
+        let raw = cards::read_ntag(&card)?;
+        assert_eq!(decode_ndef_content(&raw)?, user_id);
+        Ok(())
+    }
+
+    #[test]
+    fn tlv_skips_control_blocks() -> TestResult {
+        // NULL padding, a Lock Control TLV whose value contains 0x03, a proprietary TLV, then NDEF
+        let mut buf = vec![0x00, 0x01, 0x03, 0x03, 0x03, 0x03, 0xFD, 0x02, 0x03, 0x03];
+        buf.extend(sim::text_tlv("after-control"));
+        assert_eq!(decode_ndef_content(&buf)?, "after-control".to_string());
+
+        // Terminator before any NDEF TLV means there is no message
+        let empty = [0x01, 0x03, 0xA0, 0x10, 0x44, 0xFE, 0x03, 0x00];
+        assert!(find_ndef_tlv(&empty).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn tlv_area_end_detection() -> TestResult {
+        // Zeros inside the NDEF value do not end the area
+        let mut buf = vec![0x03, 0x14];
+        buf.extend([0u8; 20]);
+        buf.push(0xFE);
+        assert_eq!(tlv_area_end(&buf[..4]), Some(22));
+        assert_eq!(tlv_area_end(&buf), Some(22));
+        // Control TLVs are skipped; the header alone is not enough to decide
+        assert_eq!(tlv_area_end(&[0x01, 0x03, 0xA0, 0x10, 0x44]), None);
+        assert_eq!(
+            tlv_area_end(&[0x01, 0x03, 0xA0, 0x10, 0x44, 0x03, 0xFF, 0x01, 0x2C]),
+            Some(9 + 300)
+        );
+        assert_eq!(tlv_area_end(&[0x00, 0x00, 0xFE, 0x03]), Some(3));
+        // NULL padding is legal however long it runs: blank memory never ends the area
+        assert_eq!(tlv_area_end(&[0u8; 64]), None);
+        Ok(())
+    }
+
+    #[test]
+    fn tlv_long_null_padding() -> TestResult {
+        let _serial = sim::serial();
+        // More NULL TLVs than fill a 16-byte block before the NDEF TLV
+        let mut buf = vec![0x00; 40];
+        buf.extend(sim::text_tlv("after-padding"));
+        assert_eq!(tlv_area_end(&buf[..32]), None);
+        assert_eq!(tlv_area_end(&buf), Some(buf.len() - 1));
+
+        // Classic reads one block at a time, so a padding run used to end the read
+        let card = SimulatedCard::mifare_classic_1k();
+        let layout = ClassicLayout::CLASSIC_1K;
+        let mut keys = KeyMap::default();
+        cards::write_mifare_blocks(&card, &layout, &mut keys, &layout.user_blocks(), &buf)?;
+        let raw = cards::read_mifare(&card, &layout, &mut keys)?;
+        assert_eq!(decode_ndef_content(&raw)?, "after-padding".to_string());
+        Ok(())
+    }
 }
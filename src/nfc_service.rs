@@ -1,18 +1,74 @@
 // src/nfc_service.rs
-use crossbeam_channel::{Receiver, Sender};
+use crossbeam_channel::{Receiver, SendError, Sender};
 use log::{error, info};
-use pcsc::{Context, PNP_NOTIFICATION, Protocols, ReaderState, Scope, ShareMode, State, Error}; // <--- Changed here
+use pcsc::{
+    Card, Context, Error, PNP_NOTIFICATION, Protocols, ReaderState, Scope, ShareMode, State,
+};
+use std::collections::HashMap;
 use std::ffi::{CStr, CString};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use crate::types::{CARD_TYPE_MIFARE_1K, NfcCommand, OutgoingMessage};
-use crate::{cards, ndef};
+use crate::atr::{self, CardKind};
+use crate::error::NfcError;
+use crate::transport::CardTransport;
+use crate::types::{
+    LockSpec, NfcCommand, NfcEvent, NfcRequest, Origin, OutgoingMessage, ProtectionSpec, ReadSpec,
+    RecordReport, RestoreSpec, WriteSpec, WriteStage,
+};
+use crate::{apdu, cards, dump, keys, ndef, password};
+
+// Sends messages to the WebSocket side: answers to the connection whose
+// command is being handled, hardware events to every connection
+#[derive(Clone)]
+struct EventSender {
+    tx: Sender<NfcEvent>,
+    to: Option<Origin>,
+}
+
+impl EventSender {
+    fn broadcast(tx: Sender<NfcEvent>) -> Self {
+        Self { tx, to: None }
+    }
+
+    fn reply_to(&self, origin: Origin) -> Self {
+        Self {
+            tx: self.tx.clone(),
+            to: Some(origin),
+        }
+    }
+
+    // Fails only once the WebSocket side is gone
+    fn send(&self, msg: OutgoingMessage) -> Result<(), SendError<()>> {
+        self.tx
+            .send(NfcEvent {
+                to: self.to.clone(),
+                msg,
+            })
+            .map_err(|_| SendError(()))
+    }
+}
 
 // Struct to track state and prevent spamming duplicate messages
 struct ServiceState {
     reader_connected: bool,
     card_present: bool,
     last_data_read: Option<String>,
+    // UID of the card currently on the reader, echoed on the removal event
+    last_uid: Option<String>,
+    // LOCK_TAG tokens waiting for confirmation, by the connection they were issued to
+    pending_locks: HashMap<u64, cards::LockConfirmation>,
+    // Armed WRITE_DATA waiting for a card
+    pending_write: Option<PendingWrite>,
+}
+
+struct PendingWrite {
+    spec: WriteSpec,
+    ndef_msg: Vec<u8>,
+    // Connection that armed the write; only it may cancel
+    client: u64,
+    // Progress and the result go to the connection that armed the write
+    tx: EventSender,
+    deadline: Instant,
 }
 
 impl ServiceState {
@@ -21,12 +77,16 @@ impl ServiceState {
             reader_connected: false,
             card_present: false,
             last_data_read: None,
+            last_uid: None,
+            pending_locks: HashMap::new(),
+            pending_write: None,
         }
     }
 }
 
-pub fn run(tx: Sender<OutgoingMessage>, rx: Receiver<NfcCommand>) {
+pub fn run(tx: Sender<NfcEvent>, rx: Receiver<NfcRequest>) {
     info!("Starting NFC Service (Auto-Restart + Deduplication)...");
+    let tx = EventSender::broadcast(tx);
 
     // cache persists outside the recovery loop so we don't spam "Reader Connected" on every restart
     let mut state_cache = ServiceState::new();
@@ -43,6 +103,7 @@ pub fn run(tx: Sender<OutgoingMessage>, rx: Receiver<NfcCommand>) {
             }
             Err(err) => {
                 error!("Failed to establish context: {}. Retrying in 3s...", err);
+                expire_pending_write(&mut state_cache);
                 if state_cache.reader_connected {
                     state_cache.reader_connected = false;
                     let _ = tx.send(OutgoingMessage::READER_ERROR {
@@ -62,15 +123,20 @@ pub fn run(tx: Sender<OutgoingMessage>, rx: Receiver<NfcCommand>) {
 
         // 1. INITIAL SCAN (Fix for "Not working at all")
         // Force an update immediately so we don't have to wait for a plug/unplug event
-        update_reader_list(&ctx, &mut reader_names, &mut reader_states, &mut readers_buf);
-        
+        update_reader_list(
+            &ctx,
+            &mut reader_names,
+            &mut reader_states,
+            &mut readers_buf,
+        );
+
         let is_connected = !reader_names.is_empty();
         if is_connected {
             state_cache.reader_connected = true;
             let _ = tx.send(OutgoingMessage::READER_STATUS { success: true });
             info!("Initial Reader Found: {:?}", reader_names);
         } else {
-             // If we restart and no reader is there, update cache
+            // If we restart and no reader is there, update cache
             state_cache.reader_connected = false;
         }
 
@@ -78,7 +144,8 @@ pub fn run(tx: Sender<OutgoingMessage>, rx: Receiver<NfcCommand>) {
         loop {
             // 2. Wait for State Change
             // We use a timeout to allow checking for WebSocket commands periodically
-            if let Err(err) = ctx.get_status_change(Duration::from_millis(500), &mut reader_states) {
+            if let Err(err) = ctx.get_status_change(Duration::from_millis(500), &mut reader_states)
+            {
                 match err {
                     Error::Timeout => {
                         // Normal behavior, just continue
@@ -94,15 +161,76 @@ pub fn run(tx: Sender<OutgoingMessage>, rx: Receiver<NfcCommand>) {
                 }
             }
 
+            expire_pending_write(&mut state_cache);
+
             // 3. PROCESS COMMANDS
-            while let Ok(cmd) = rx.try_recv() {
+            while let Ok(NfcRequest { origin, cmd }) = rx.try_recv() {
+                // Everything sent while handling the command answers its sender
+                let client = origin.client;
+                let tx = tx.reply_to(origin);
                 match cmd {
-                    NfcCommand::Write { user_id } => {
-                        println!("Received Write Command for user_id: {}", user_id);
-                        handle_write_command(&ctx, &reader_names, &user_id, &tx);
+                    NfcCommand::Read { spec } => {
+                        println!("Received Read Command (reader: {:?})", spec.reader);
+                        handle_read_command(&ctx, &reader_names, &spec, &tx);
+                    }
+                    NfcCommand::Write { spec } if spec.arm => {
+                        println!("Received Armed Write Command ({})", spec.data_type);
+                        handle_arm_command(spec, client, &tx, &mut state_cache);
+                    }
+                    NfcCommand::Write { spec } => {
+                        println!(
+                            "Received Write Command ({}) for user_id: {}",
+                            spec.data_type, spec.user_id
+                        );
+                        handle_write_command(&ctx, &reader_names, &spec, &tx);
+                    }
+                    NfcCommand::SetProtection { spec } => {
+                        println!(
+                            "Received Protection Command ({:?} from page {})",
+                            spec.mode, spec.start_page
+                        );
+                        handle_protection_command(&ctx, &reader_names, &spec, &tx);
+                    }
+                    NfcCommand::Format => {
+                        println!("Received Format Command");
+                        handle_format_command(&ctx, &reader_names, false, &tx);
+                    }
+                    NfcCommand::Erase => {
+                        println!("Received Erase Command");
+                        handle_format_command(&ctx, &reader_names, true, &tx);
+                    }
+                    NfcCommand::Dump => {
+                        println!("Received Dump Command");
+                        handle_dump_command(&ctx, &reader_names, &tx);
+                    }
+                    NfcCommand::Restore { spec } => {
+                        println!("Received Restore Command");
+                        handle_restore_command(&ctx, &reader_names, &spec, &tx);
+                    }
+                    NfcCommand::Lock { spec } => {
+                        println!(
+                            "Received Lock Command (confirmed: {})",
+                            spec.confirm.is_some()
+                        );
+                        handle_lock_command(
+                            &ctx,
+                            &reader_names,
+                            &spec,
+                            client,
+                            &tx,
+                            &mut state_cache,
+                        );
+                    }
+                    NfcCommand::CancelWrite => {
+                        println!("Received Cancel Write Command");
+                        handle_cancel_write(client, &tx, &mut state_cache);
+                    }
+                    NfcCommand::KeyReport => {
+                        println!("Received Key Report Command");
+                        handle_key_report_command(&ctx, &reader_names, &tx);
                     }
                     NfcCommand::CheckReaderStatus => {
-                        // We use the cached state because if the context is dead, 
+                        // We use the cached state because if the context is dead,
                         // list_readers would fail anyway.
                         let _ = tx.send(OutgoingMessage::READER_STATUS {
                             success: state_cache.reader_connected,
@@ -113,12 +241,19 @@ pub fn run(tx: Sender<OutgoingMessage>, rx: Receiver<NfcCommand>) {
 
             // 4. PROCESS PnP EVENTS (Hardware Changes)
             // Check if PnP (Index 0) changed
-            if !reader_states.is_empty() && reader_states[0].event_state().intersects(State::CHANGED) {
+            if !reader_states.is_empty()
+                && reader_states[0].event_state().intersects(State::CHANGED)
+            {
                 // Acknowledge change
                 reader_states[0].sync_current_state();
-                
+
                 info!("Hardware change detected, refreshing list...");
-                update_reader_list(&ctx, &mut reader_names, &mut reader_states, &mut readers_buf);
+                update_reader_list(
+                    &ctx,
+                    &mut reader_names,
+                    &mut reader_states,
+                    &mut readers_buf,
+                );
 
                 let is_connected = !reader_names.is_empty();
                 // DEDUPLICATION: Only send if status actually changed
@@ -133,7 +268,9 @@ pub fn run(tx: Sender<OutgoingMessage>, rx: Receiver<NfcCommand>) {
             // 5. PROCESS CARD EVENTS (Indices 1..n)
             for i in 1..reader_states.len() {
                 // Safety check
-                if i >= reader_states.len() { break; }
+                if i >= reader_states.len() {
+                    break;
+                }
 
                 let name = reader_names[i - 1].clone();
                 let rs = &reader_states[i];
@@ -154,7 +291,13 @@ pub fn run(tx: Sender<OutgoingMessage>, rx: Receiver<NfcCommand>) {
                         // DEDUPLICATION: Only read if we didn't think a card was there
                         if !state_cache.card_present {
                             state_cache.card_present = true;
-                            handle_card_insertion(&ctx, &name, &tx, &mut state_cache);
+                            // An armed write takes the card before the normal read
+                            match state_cache.pending_write.take() {
+                                Some(pending) => {
+                                    handle_armed_write(&ctx, &name, pending, &tx, &mut state_cache)
+                                }
+                                None => handle_card_insertion(&ctx, &name, &tx, &mut state_cache),
+                            }
                         }
                     }
 
@@ -167,6 +310,7 @@ pub fn run(tx: Sender<OutgoingMessage>, rx: Receiver<NfcCommand>) {
                             let _ = tx.send(OutgoingMessage::CARD_STATUS {
                                 success: false,
                                 message: "Card removed!".into(),
+                                uid: state_cache.last_uid.take(),
                             });
                         }
                     }
@@ -174,11 +318,11 @@ pub fn run(tx: Sender<OutgoingMessage>, rx: Receiver<NfcCommand>) {
             }
         } // End Inner Loop
 
-        // If we reach here, the inner loop broke (crash). 
+        // If we reach here, the inner loop broke (crash).
         // Reset non-essential cache, but keep 'last_data_read' if you want.
         state_cache.reader_connected = false;
         state_cache.card_present = false;
-        
+
         info!("Service loop exited. restarting in 1 second...");
         std::thread::sleep(Duration::from_secs(1));
     } // End Outer Loop
@@ -194,7 +338,7 @@ fn update_reader_list(
 ) {
     match ctx.list_readers(buf) {
         Ok(iter) => {
-            *reader_names = iter.map(|name| CString::from(name)).collect();
+            *reader_names = iter.map(CString::from).collect();
 
             // Reset states, keeping PnP at index 0
             reader_states.truncate(1);
@@ -211,75 +355,185 @@ fn update_reader_list(
     }
 }
 
+// ATR of the card; None if the reader would not report status
+fn card_atr(card: &Card) -> Option<Vec<u8>> {
+    let mut names_buf = [0u8; 128];
+    let mut atr_buf = [0u8; 64];
+    card.status2(&mut names_buf, &mut atr_buf)
+        .ok()
+        .map(|status| status.atr().to_vec())
+}
+
+// Classify the card from its ATR
+fn card_kind(card: &Card) -> Option<CardKind> {
+    card_atr(card).map(|atr| atr::parse_atr(&atr))
+}
+
 fn handle_card_insertion(
     ctx: &Context,
     reader_name: &CStr,
-    tx: &Sender<OutgoingMessage>,
+    tx: &EventSender,
     cache: &mut ServiceState,
 ) {
-    let _ = tx.send(OutgoingMessage::CARD_STATUS {
-        success: true,
-        message: "Card detected!".into(),
-    });
-
     match ctx.connect(reader_name, ShareMode::Shared, Protocols::ANY) {
         Ok(card) => {
-            let mut names_buf = [0u8; 128];
-            let mut atr_buf = [0u8; 64];
-            let card_type = match card.status2(&mut names_buf, &mut atr_buf) {
-                Ok(status) => {
-                    let atr = status.atr();
-                    if let Some(last) = atr.last() {
-                        format!("{:x}", last)
-                    } else {
-                        "unknown".into()
-                    }
+            // UID first: it identifies blank and non-NDEF cards too
+            let uid = match apdu::get_uid(&card) {
+                Ok(bytes) => Some(hex::encode_upper(bytes)),
+                Err(e) => {
+                    error!("Failed to read card UID: {}", e);
+                    None
                 }
-                Err(_) => "unknown".into(),
             };
+            cache.last_uid = uid.clone();
 
-            let data_res = if card_type == CARD_TYPE_MIFARE_1K {
-                cards::read_mifare(&card)
-            } else {
-                cards::read_ntag(&card)
-            };
+            let _ = tx.send(OutgoingMessage::CARD_STATUS {
+                success: true,
+                message: "Card detected!".into(),
+                uid: uid.clone(),
+            });
+
+            let kind = card_kind(&card).unwrap_or(CardKind::Unknown);
+            info!("Card type: {}", kind);
+
+            let data_res = cards::read_card(&card, kind);
 
             match data_res {
-                Ok(raw) => match ndef::decode_ndef_text(&raw) {
+                Ok(raw) => match ndef::decode_ndef_content(&raw) {
                     Ok(text) => {
                         // DEDUPLICATION: Only send data if it changed
                         if cache.last_data_read.as_ref() != Some(&text) {
                             cache.last_data_read = Some(text.clone());
-                            let _ = tx.send(OutgoingMessage::DATA_READ_SUCCESS { data: text });
+                            let _ = tx.send(OutgoingMessage::DATA_READ_SUCCESS {
+                                data: Some(text),
+                                uid,
+                                raw: None,
+                                records: None,
+                            });
                         }
                     }
                     Err(_) => {
                         // Optional: Deduplicate error messages too if desired
                         let _ = tx.send(OutgoingMessage::DATA_READ_ERROR {
                             error: "Empty/Non-NDEF".into(),
+                            uid,
+                            code: "NOT_NDEF".into(),
+                            status_word: None,
                         });
                     }
                 },
                 Err(e) => {
-                    let _ = tx.send(OutgoingMessage::DATA_READ_ERROR { error: e });
+                    let _ = tx.send(OutgoingMessage::read_error(&e, uid));
                 }
             }
         }
-        Err(e) => error!("Failed to connect to card: {}", e),
+        Err(e) => {
+            error!("Failed to connect to card: {}", e);
+            let _ = tx.send(OutgoingMessage::CARD_STATUS {
+                success: true,
+                message: "Card detected!".into(),
+                uid: None,
+            });
+        }
+    }
+}
+
+// READ_DATA: read the card on the chosen reader (or the first with a card) and
+// answer whatever happens, bypassing the insertion dedup
+fn handle_read_command(ctx: &Context, reader_names: &[CString], spec: &ReadSpec, tx: &EventSender) {
+    let readers: Vec<&CString> = match &spec.reader {
+        Some(wanted) => reader_names
+            .iter()
+            .filter(|name| name.to_string_lossy() == wanted.as_str())
+            .collect(),
+        None => reader_names.iter().collect(),
+    };
+    if readers.is_empty() {
+        let _ = tx.send(OutgoingMessage::DATA_READ_ERROR {
+            error: match &spec.reader {
+                Some(wanted) => format!("Reader not found: {}", wanted),
+                None => "No reader connected".into(),
+            },
+            uid: None,
+            code: "NO_READER".into(),
+            status_word: None,
+        });
+        return;
+    }
+
+    let names: Vec<CString> = readers.into_iter().cloned().collect();
+    let Some((card, kind)) = connect_card(ctx, &names) else {
+        let _ = tx.send(OutgoingMessage::read_error(&NfcError::NoCard, None));
+        return;
+    };
+    let _ = tx.send(read_data(&card, kind, spec));
+}
+
+// Answer to READ_DATA for the card on the reader
+pub fn read_data(card: &dyn CardTransport, kind: CardKind, spec: &ReadSpec) -> OutgoingMessage {
+    let uid = match apdu::get_uid(card) {
+        Ok(bytes) => hex::encode_upper(bytes),
+        Err(e) => return OutgoingMessage::read_error(&e, None),
+    };
+    if spec.uid_only {
+        return OutgoingMessage::DATA_READ_SUCCESS {
+            data: None,
+            uid: Some(uid),
+            raw: None,
+            records: None,
+        };
+    }
+
+    let raw = match cards::read_card(card, kind) {
+        Ok(raw) => raw,
+        Err(e) => return OutgoingMessage::read_error(&e, Some(uid)),
+    };
+    let records = ndef::find_ndef_tlv(&raw).and_then(ndef::parse_ndef_message);
+    // Blank and non-NDEF cards are only a success when the raw bytes were asked for
+    if records.is_err() && !spec.raw {
+        return OutgoingMessage::DATA_READ_ERROR {
+            error: "Empty/Non-NDEF".into(),
+            uid: Some(uid),
+            code: "NOT_NDEF".into(),
+            status_word: None,
+        };
+    }
+    OutgoingMessage::DATA_READ_SUCCESS {
+        data: ndef::decode_ndef_content(&raw).ok(),
+        uid: Some(uid),
+        raw: spec.raw.then(|| hex::encode_upper(&raw)),
+        records: spec.records.then(|| {
+            records
+                .unwrap_or_default()
+                .iter()
+                .map(RecordReport::from)
+                .collect()
+        }),
     }
 }
 
 fn handle_write_command(
     ctx: &Context,
     reader_names: &[CString],
-    user_id: &str,
-    tx: &Sender<OutgoingMessage>,
+    spec: &WriteSpec,
+    tx: &EventSender,
 ) {
+    println!("Starting write process for user_id: {}", spec.user_id);
+
+    // Encode first so a bad request is rejected before touching any card
+    let ndef_msg = match spec.to_ndef_message() {
+        Ok(msg) => msg,
+        Err(e) => {
+            let _ = tx.send(OutgoingMessage::write_error(&e));
+            return;
+        }
+    };
 
-    println!("Starting write process for user_id: {}", user_id);
     if reader_names.is_empty() {
         let _ = tx.send(OutgoingMessage::DATA_WRITE_ERROR {
             error: "No reader connected".into(),
+            code: "NO_READER".into(),
+            status_word: None,
         });
         return;
     }
@@ -289,28 +543,12 @@ fn handle_write_command(
     let mut success = false;
     for name in reader_names {
         if let Ok(card) = ctx.connect(name, ShareMode::Shared, Protocols::ANY) {
-            let mut names_buf = [0u8; 128];
-            let mut atr_buf = [0u8; 64];
-            let card_type = match card.status2(&mut names_buf, &mut atr_buf) {
-                Ok(status) => {
-                    let atr = status.atr();
-                    if let Some(last) = atr.last() {
-                        format!("{:x}", last)
-                    } else {
-                        "unknown".into()
-                    }
-                }
-                Err(_) => continue,
+            let kind = match card_kind(&card) {
+                Some(kind) => kind,
+                None => continue,
             };
 
-            let ndef_msg = ndef::encode_ndef_message(user_id);
-            let tlv_data = ndef::wrap_in_tlv(&ndef_msg);
-
-            let write_res = if card_type == CARD_TYPE_MIFARE_1K {
-                cards::write_mifare(&card, &tlv_data)
-            } else {
-                cards::write_ntag(&card, &tlv_data)
-            };
+            let write_res = write_to_card(&card, kind, spec, &ndef_msg, &|_| {});
 
             match write_res {
                 Ok(_) => {
@@ -322,7 +560,7 @@ fn handle_write_command(
                 }
                 Err(e) => {
                     println!("Failed to write data to card: {}", e);
-                    let _ = tx.send(OutgoingMessage::DATA_WRITE_ERROR { error: e });
+                    let _ = tx.send(OutgoingMessage::write_error(&e));
                     success = true;
                 }
             }
@@ -331,8 +569,671 @@ fn handle_write_command(
     }
 
     if !success {
+        let _ = tx.send(OutgoingMessage::write_error(&NfcError::NoCard));
+    }
+}
+
+// Write `ndef_msg` as `spec` asks (formatting and verifying included),
+// reporting the writing and verifying stages to `progress`
+pub fn write_to_card(
+    card: &dyn CardTransport,
+    kind: CardKind,
+    spec: &WriteSpec,
+    ndef_msg: &[u8],
+    progress: &dyn Fn(WriteStage),
+) -> Result<(), NfcError> {
+    let tlv_data = ndef::wrap_in_tlv(ndef_msg);
+    let options = cards::WriteOptions {
+        nfc_forum: spec.nfc_forum,
+        verify: spec.verify,
+    };
+    cards::write_card_reporting(card, kind, &tlv_data, &options, progress)?;
+    if spec.verify {
+        // Bytes matched; now make sure the NDEF decodes to what was asked for
+        let raw = cards::read_card(card, kind)?;
+        spec.check_read_back(&raw, ndef_msg)?;
+    }
+    Ok(())
+}
+
+// WRITE_DATA with `arm`: wait for the next card tapped on any reader
+fn handle_arm_command(spec: WriteSpec, client: u64, tx: &EventSender, cache: &mut ServiceState) {
+    let ndef_msg = match spec.to_ndef_message() {
+        Ok(msg) => msg,
+        Err(e) => {
+            let _ = tx.send(OutgoingMessage::write_error(&e));
+            return;
+        }
+    };
+    if cache.pending_write.is_some() {
         let _ = tx.send(OutgoingMessage::DATA_WRITE_ERROR {
-            error: "No card found on reader".into(),
+            error: "Another write is already waiting for a card".into(),
+            code: "WRITE_PENDING".into(),
+            status_word: None,
+        });
+        return;
+    }
+
+    let timeout = spec.arm_timeout();
+    println!(
+        "Write armed for {}s, waiting for a card...",
+        timeout.as_secs()
+    );
+    let _ = tx.send(OutgoingMessage::WRITE_PROGRESS {
+        stage: WriteStage::Armed,
+        uid: None,
+        card_present: Some(cache.card_present),
+    });
+    cache.pending_write = Some(PendingWrite {
+        spec,
+        ndef_msg,
+        client,
+        tx: tx.clone(),
+        deadline: Instant::now() + timeout,
+    });
+}
+
+// Only the connection that armed the write can cancel it; anyone else gets `cancelled: false`
+fn handle_cancel_write(client: u64, tx: &EventSender, cache: &mut ServiceState) {
+    let pending = cache
+        .pending_write
+        .take_if(|pending| pending.client == client);
+    if let Some(pending) = &pending {
+        println!("Armed write cancelled");
+        let _ = pending.tx.send(OutgoingMessage::DATA_WRITE_ERROR {
+            error: "Write cancelled".into(),
+            code: "WRITE_CANCELLED".into(),
+            status_word: None,
+        });
+    }
+    let _ = tx.send(OutgoingMessage::WRITE_CANCELLED {
+        cancelled: pending.is_some(),
+    });
+}
+
+// Give up on an armed write once its timeout has passed
+fn expire_pending_write(cache: &mut ServiceState) {
+    if cache
+        .pending_write
+        .as_ref()
+        .is_some_and(|pending| Instant::now() >= pending.deadline)
+        && let Some(pending) = cache.pending_write.take()
+    {
+        println!("Armed write timed out");
+        let _ = pending.tx.send(OutgoingMessage::DATA_WRITE_ERROR {
+            error: "No card was tapped before the timeout".into(),
+            code: "WRITE_TIMEOUT".into(),
+            status_word: None,
+        });
+    }
+}
+
+// A card arrived while a write was armed: write it instead of reading it
+fn handle_armed_write(
+    ctx: &Context,
+    reader_name: &CStr,
+    pending: PendingWrite,
+    events: &EventSender,
+    cache: &mut ServiceState,
+) {
+    let card = match ctx.connect(reader_name, ShareMode::Shared, Protocols::ANY) {
+        Ok(card) => card,
+        Err(e) => {
+            error!("Failed to connect to card: {}", e);
+            // Still armed: the card can be tapped again
+            cache.pending_write = Some(pending);
+            return;
+        }
+    };
+    let uid = apdu::get_uid(&card).ok().map(hex::encode_upper);
+    cache.last_uid = uid.clone();
+    let _ = events.send(OutgoingMessage::CARD_STATUS {
+        success: true,
+        message: "Card detected!".into(),
+        uid: uid.clone(),
+    });
+    let kind = card_kind(&card).unwrap_or(CardKind::Unknown);
+    write_armed(&card, kind, uid, &pending);
+}
+
+// Write an armed WRITE_DATA to `card`, with the card's UID on every progress report
+fn write_armed(
+    card: &dyn CardTransport,
+    kind: CardKind,
+    uid: Option<String>,
+    pending: &PendingWrite,
+) {
+    let tx = &pending.tx;
+    let progress = |stage| {
+        let _ = tx.send(OutgoingMessage::WRITE_PROGRESS {
+            stage,
+            uid: uid.clone(),
+            card_present: None,
         });
+    };
+    progress(WriteStage::CardDetected);
+    match write_to_card(card, kind, &pending.spec, &pending.ndef_msg, &progress) {
+        Ok(()) => {
+            println!("Data written successfully to card.");
+            progress(WriteStage::Done);
+            let _ = tx.send(OutgoingMessage::DATA_WRITE_SUCCESS {
+                message: "Data Written Successfully!".into(),
+            });
+        }
+        Err(e) => {
+            println!("Failed to write data to card: {}", e);
+            let _ = tx.send(OutgoingMessage::write_error(&e));
+        }
+    }
+}
+
+// First reader with a card on it, with the card's kind
+fn connect_card(ctx: &Context, reader_names: &[CString]) -> Option<(Card, CardKind)> {
+    reader_names.iter().find_map(|name| {
+        let card = ctx.connect(name, ShareMode::Shared, Protocols::ANY).ok()?;
+        let kind = card_kind(&card)?;
+        Some((card, kind))
+    })
+}
+
+fn handle_protection_command(
+    ctx: &Context,
+    reader_names: &[CString],
+    spec: &ProtectionSpec,
+    tx: &EventSender,
+) {
+    let Some((card, kind)) = connect_card(ctx, reader_names) else {
+        let _ = tx.send(OutgoingMessage::protection_error(&NfcError::NoCard));
+        return;
+    };
+
+    let res = if kind.is_type2() {
+        password::set_protection(&card, spec.mode, spec.start_page)
+    } else {
+        Err(NfcError::UnsupportedCard(kind.to_string()))
+    };
+    let _ = tx.send(match res {
+        Ok(()) => OutgoingMessage::TAG_PROTECTION_SUCCESS {
+            message: "Tag protection updated".into(),
+        },
+        Err(e) => {
+            println!("Failed to set tag protection: {}", e);
+            OutgoingMessage::protection_error(&e)
+        }
+    });
+}
+
+fn handle_lock_command(
+    ctx: &Context,
+    reader_names: &[CString],
+    spec: &LockSpec,
+    client: u64,
+    tx: &EventSender,
+    cache: &mut ServiceState,
+) {
+    let Some((card, kind)) = connect_card(ctx, reader_names) else {
+        let _ = tx.send(OutgoingMessage::lock_error(&NfcError::NoCard));
+        return;
+    };
+    let uid = match apdu::get_uid(&card) {
+        Ok(uid) => uid,
+        Err(e) => {
+            let _ = tx.send(OutgoingMessage::lock_error(&e));
+            return;
+        }
+    };
+
+    let token = match &spec.confirm {
+        Some(token) => token,
+        None => {
+            let _ = tx.send(issue_lock_token(client, &uid, cache));
+            return;
+        }
+    };
+    if !take_lock_confirmation(client, token, &uid, cache) {
+        let _ = tx.send(OutgoingMessage::TAG_LOCK_ERROR {
+            error: "Lock confirmation token is invalid, expired or for another card".into(),
+            code: "CONFIRMATION_INVALID".into(),
+            status_word: None,
+        });
+        return;
+    }
+
+    println!("Locking {} {} read-only", kind, hex::encode_upper(&uid));
+    let _ = tx.send(match cards::lock_card(&card, kind) {
+        Ok(()) => OutgoingMessage::TAG_LOCK_SUCCESS {
+            message: "Tag locked read-only".into(),
+            uid: hex::encode_upper(&uid),
+        },
+        Err(e) => {
+            println!("Failed to lock tag: {}", e);
+            OutgoingMessage::lock_error(&e)
+        }
+    });
+}
+
+// First LOCK_TAG from a connection: hand out a token for this card and wait for it.
+// Each connection holds its own token, so one tab cannot void another's.
+fn issue_lock_token(client: u64, uid: &[u8], cache: &mut ServiceState) -> OutgoingMessage {
+    cache.pending_locks.retain(|_, pending| !pending.expired());
+    let confirmation = cards::LockConfirmation::issue(uid);
+    let msg = OutgoingMessage::LOCK_CONFIRMATION_REQUIRED {
+        uid: hex::encode_upper(uid),
+        token: confirmation.token.clone(),
+        expires_in: cards::LockConfirmation::VALID_FOR.as_secs(),
+    };
+    cache.pending_locks.insert(client, confirmation);
+    msg
+}
+
+// A token is good for one attempt only, from the connection it was issued to
+fn take_lock_confirmation(client: u64, token: &str, uid: &[u8], cache: &mut ServiceState) -> bool {
+    cache
+        .pending_locks
+        .remove(&client)
+        .is_some_and(|pending| pending.confirms(token, uid))
+}
+
+// FORMAT_CARD (`erase` false) and ERASE_CARD
+fn handle_format_command(ctx: &Context, reader_names: &[CString], erase: bool, tx: &EventSender) {
+    let Some((card, kind)) = connect_card(ctx, reader_names) else {
+        let _ = tx.send(OutgoingMessage::format_error(erase, &NfcError::NoCard));
+        return;
+    };
+    let uid = apdu::get_uid(&card).ok().map(hex::encode_upper);
+
+    let res = if erase {
+        cards::erase_card(&card, kind)
+    } else {
+        cards::format_card(&card, kind)
+    };
+    let _ = tx.send(match res {
+        Ok(touched) => OutgoingMessage::format_success(erase, uid, kind, touched),
+        Err(e) => {
+            println!(
+                "Failed to {} card: {}",
+                if erase { "erase" } else { "format" },
+                e
+            );
+            OutgoingMessage::format_error(erase, &e)
+        }
+    });
+}
+
+fn handle_dump_command(ctx: &Context, reader_names: &[CString], tx: &EventSender) {
+    let Some((card, atr)) = reader_names.iter().find_map(|name| {
+        let card = ctx.connect(name, ShareMode::Shared, Protocols::ANY).ok()?;
+        let atr = card_atr(&card)?;
+        Some((card, atr))
+    }) else {
+        let _ = tx.send(OutgoingMessage::dump_error(&NfcError::NoCard));
+        return;
+    };
+
+    let _ = tx.send(match dump::dump_card(&card, &atr) {
+        Ok(dump) => {
+            let image = hex::encode_upper(dump.to_image());
+            OutgoingMessage::CARD_DUMP_SUCCESS { dump, image }
+        }
+        Err(e) => {
+            println!("Failed to dump card: {}", e);
+            OutgoingMessage::dump_error(&e)
+        }
+    });
+}
+
+// The keys are sent in plaintext, so only ever to the client that asked
+fn handle_key_report_command(ctx: &Context, reader_names: &[CString], tx: &EventSender) {
+    let Some((card, _)) = connect_card(ctx, reader_names) else {
+        let _ = tx.send(OutgoingMessage::key_report_error(&NfcError::NoCard));
+        return;
+    };
+    let _ = tx.send(key_report(&card));
+}
+
+// Keys recorded for the card on the reader by earlier reads and writes
+pub fn key_report(card: &dyn CardTransport) -> OutgoingMessage {
+    match apdu::get_uid(card) {
+        Ok(uid) => OutgoingMessage::key_report(&uid, &keys::cached(&uid)),
+        Err(e) => OutgoingMessage::key_report_error(&e),
+    }
+}
+
+fn handle_restore_command(
+    ctx: &Context,
+    reader_names: &[CString],
+    spec: &RestoreSpec,
+    tx: &EventSender,
+) {
+    let dump = match spec.to_dump() {
+        Ok(dump) => dump,
+        Err(e) => {
+            let _ = tx.send(OutgoingMessage::restore_error(&e));
+            return;
+        }
+    };
+    let Some((card, kind)) = connect_card(ctx, reader_names) else {
+        let _ = tx.send(OutgoingMessage::restore_error(&NfcError::NoCard));
+        return;
+    };
+    let uid = apdu::get_uid(&card).ok().map(hex::encode_upper);
+
+    println!(
+        "Restoring dump of {} {} onto {}",
+        dump.card_type, dump.uid, kind
+    );
+    let _ = tx.send(match dump::restore_card(&card, kind, &dump) {
+        Ok(touched) => OutgoingMessage::restore_success(uid, kind, touched),
+        Err(e) => {
+            println!("Failed to restore card: {}", e);
+            OutgoingMessage::restore_error(&e)
+        }
+    });
+}
+
+// For the command line: the card on the first reader that has one, with its ATR
+pub fn connect_first_card() -> Result<(Card, Vec<u8>), String> {
+    let ctx = Context::establish(Scope::User).map_err(|e| format!("PC/SC unavailable: {}", e))?;
+    let mut readers_buf = [0; 2048];
+    let readers: Vec<CString> = ctx
+        .list_readers(&mut readers_buf)
+        .map_err(|e| format!("Cannot list readers: {}", e))?
+        .map(CString::from)
+        .collect();
+    for name in &readers {
+        if let Ok(card) = ctx.connect(name, ShareMode::Shared, Protocols::ANY)
+            && let Some(atr) = card_atr(&card)
+        {
+            return Ok((card, atr));
+        }
+    }
+    Err(NfcError::NoCard.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::apdu;
+    use crate::atr::{self, CardKind};
+    use crate::cards::{self, ClassicLayout, WriteOptions};
+    use crate::keys::KeyMap;
+    use crate::mad;
+    use crate::ndef;
+    use crate::sim::{self, SimModel, SimulatedCard, TestResult, write_spec};
+    use crate::types::{IncomingMessage, ReadSpec, WriteStage};
+
+    fn read_spec(json: &str) -> Result<ReadSpec, Box<dyn std::error::Error>> {
+        match serde_json::from_str::<IncomingMessage>(json)? {
+            IncomingMessage::READ_DATA(spec) => Ok(spec),
+            other => Err(format!("parsed as {:?}", other).into()),
+        }
+    }
+
+    #[test]
+    fn read_data_on_demand() -> TestResult {
+        let _serial = sim::serial();
+        let card = SimulatedCard::ntag(SimModel::Ntag215);
+        let kind = atr::parse_atr(&card.atr());
+        let spec = write_spec(
+            r#"{"type":"WRITE_DATA","data_type":"multi","records":[
+                {"data_type":"text","value":"desk 12"},
+                {"data_type":"uri","value":"https://example.com/d/12"}
+            ]}"#,
+        )?;
+        let tlv = ndef::wrap_in_tlv(&spec.to_ndef_message()?);
+        sim::write_card(&card, kind, &tlv, &WriteOptions::default())?;
+        let uid = hex::encode_upper(apdu::get_uid(&card)?);
+
+        // No flags: the same answer as on insertion, and again on every request
+        let spec = read_spec(r#"{"type":"READ_DATA"}"#)?;
+        for _ in 0..2 {
+            let msg = serde_json::to_value(read_data(&card, kind, &spec))?;
+            assert_eq!(msg["type"].as_str(), Some("DATA_READ_SUCCESS"));
+            assert_eq!(msg["data"].as_str(), Some("desk 12"));
+            assert_eq!(msg["uid"].as_str(), Some(uid.as_str()));
+            assert_eq!((msg.get("raw"), msg.get("records")), (None, None));
+        }
+
+        let spec = read_spec(
+            r#"{"type":"READ_DATA","reader":"ACS ACR122U 00 00","raw":true,"records":true}"#,
+        )?;
+        assert_eq!(spec.reader.as_deref(), Some("ACS ACR122U 00 00"));
+        let msg = serde_json::to_value(read_data(&card, kind, &spec))?;
+        let raw = hex::decode(msg["raw"].as_str().ok_or("no raw bytes")?)?;
+        assert!(raw.starts_with(&tlv));
+        let records = msg["records"].as_array().ok_or("no records")?;
+        assert_eq!(records.len(), 2);
+        assert_eq!(
+            (
+                records[0]["record_type"].as_str(),
+                records[0]["text"].as_str()
+            ),
+            (Some("T"), Some("desk 12"))
+        );
+        assert_eq!(records[0]["lang"].as_str(), Some("en"));
+        assert_eq!(records[1]["uri"].as_str(), Some("https://example.com/d/12"));
+        assert_eq!(records[1]["tnf"].as_u64(), Some(1));
+
+        // UID only: nothing but the UID
+        let spec = read_spec(r#"{"type":"READ_DATA","uid_only":true}"#)?;
+        let exchanges = card.exchanges();
+        let msg = serde_json::to_value(read_data(&card, kind, &spec))?;
+        assert_eq!(card.exchanges() - exchanges, 1);
+        assert_eq!(msg["uid"].as_str(), Some(uid.as_str()));
+        assert_eq!(msg.get("data"), None);
+        Ok(())
+    }
+
+    #[test]
+    fn read_data_blank() -> TestResult {
+        let _serial = sim::serial();
+        let card = SimulatedCard::mifare_classic_1k();
+        let kind = atr::parse_atr(&card.atr());
+        let msg = serde_json::to_value(read_data(&card, kind, &ReadSpec::default()))?;
+        assert_eq!(msg["type"].as_str(), Some("DATA_READ_ERROR"));
+        assert_eq!(msg["code"].as_str(), Some("NOT_NDEF"));
+
+        // Asked for the raw bytes, a blank card is a successful read
+        let spec = ReadSpec {
+            raw: true,
+            records: true,
+            ..ReadSpec::default()
+        };
+        let msg = serde_json::to_value(read_data(&card, kind, &spec))?;
+        assert_eq!(msg["type"].as_str(), Some("DATA_READ_SUCCESS"));
+        assert_eq!(msg.get("data"), None);
+        assert_eq!(msg["records"].as_array().map(Vec::len), Some(0));
+        let raw = hex::decode(msg["raw"].as_str().ok_or("no raw bytes")?)?;
+        assert!(raw.iter().all(|&b| b == 0));
+
+        let msg = serde_json::to_value(read_data(&card, CardKind::Desfire, &spec))?;
+        assert_eq!(msg["code"].as_str(), Some("UNSUPPORTED_CARD"));
+        Ok(())
+    }
+
+    #[test]
+    fn key_report_on_request() -> TestResult {
+        let _serial = sim::serial();
+        let card = SimulatedCard::new(SimModel::MifareClassic1K, &[0x13, 0x00, 0x00, 0x03]);
+        keys::remember(&apdu::get_uid(&card)?, KeyMap::default());
+        let msg = serde_json::to_value(key_report(&card))?;
+        assert_eq!(msg["type"].as_str(), Some("CARD_KEY_REPORT"));
+        assert_eq!(msg["sectors"].as_array().map(Vec::len), Some(0));
+
+        cards::read_card(&card, atr::parse_atr(&card.atr())).ok();
+        let msg = serde_json::to_value(key_report(&card))?;
+        assert_eq!(msg["uid"].as_str(), Some("13000003"));
+        assert_eq!(msg["sectors"][0]["key_a"].as_str(), Some("FFFFFFFFFFFF"));
+
+        card.remove();
+        let msg = serde_json::to_value(key_report(&card))?;
+        assert_eq!(msg["type"].as_str(), Some("CARD_KEY_REPORT_ERROR"));
+        Ok(())
+    }
+
+    #[test]
+    fn write_progress_stages() -> TestResult {
+        let _serial = sim::serial();
+        let stages = std::cell::RefCell::new(Vec::new());
+        let progress = |stage| stages.borrow_mut().push(stage);
+
+        let card = SimulatedCard::ntag(SimModel::Ntag215);
+        let kind = atr::parse_atr(&card.atr());
+        let spec = write_spec(
+            r#"{"type":"WRITE_DATA","data_type":"text","user_id":"EMP-9","arm":true,"verify":true}"#,
+        )?;
+        write_to_card(&card, kind, &spec, &spec.to_ndef_message()?, &progress)?;
+        assert_eq!(
+            stages.take(),
+            vec![WriteStage::Writing, WriteStage::Verifying]
+        );
+        assert_eq!(
+            ndef::decode_ndef_content(&cards::read_card(&card, kind)?)?,
+            "EMP-9".to_string()
+        );
+
+        // Verification of a MIFARE Classic write goes through the MAD like the write
+        let card = SimulatedCard::mifare_classic_1k();
+        let kind = atr::parse_atr(&card.atr());
+        let spec = write_spec(
+            r#"{"type":"WRITE_DATA","data_type":"text","user_id":"EMP-10","nfc_forum":true,"verify":true}"#,
+        )?;
+        write_to_card(&card, kind, &spec, &spec.to_ndef_message()?, &progress)?;
+        assert_eq!(
+            stages.take(),
+            vec![WriteStage::Writing, WriteStage::Verifying]
+        );
+        assert!(
+            mad::read_mad(&card, &ClassicLayout::CLASSIC_1K, &mut KeyMap::default())?.is_some()
+        );
+
+        // Lost writes are caught at the verifying stage
+        let card = SimulatedCard::ntag(SimModel::Ntag213);
+        let kind = atr::parse_atr(&card.atr());
+        card.lose_writes_after(1);
+        let res = write_to_card(&card, kind, &spec, &spec.to_ndef_message()?, &progress);
+        assert_eq!(res.map_err(|e| e.code()), Err("WRITE_VERIFY_FAILED"));
+        assert_eq!(
+            stages.take(),
+            vec![WriteStage::Writing, WriteStage::Verifying]
+        );
+
+        // No verification asked for
+        let spec = write_spec(r#"{"type":"WRITE_DATA","data_type":"text","user_id":"EMP-11"}"#)?;
+        let card = SimulatedCard::ntag(SimModel::Ntag213);
+        write_to_card(&card, kind, &spec, &spec.to_ndef_message()?, &progress)?;
+        assert_eq!(stages.take(), vec![WriteStage::Writing]);
+        Ok(())
+    }
+
+    #[test]
+    fn cancel_write_only_from_arming_client() -> TestResult {
+        let (events_tx, events) = crossbeam_channel::unbounded();
+        let tx = EventSender::broadcast(events_tx);
+        let origin = |client| Origin {
+            client,
+            request_id: None,
+        };
+        let mut state = ServiceState::new();
+        let spec = write_spec(
+            r#"{"type":"WRITE_DATA","data_type":"text","user_id":"EMP-12","arm":true}"#,
+        )?;
+        handle_arm_command(spec, 1, &tx.reply_to(origin(1)), &mut state);
+        events.try_iter().for_each(drop);
+
+        // Another connection cannot cancel it
+        handle_cancel_write(2, &tx.reply_to(origin(2)), &mut state);
+        let answers: Vec<NfcEvent> = events.try_iter().collect();
+        assert_eq!(answers.len(), 1);
+        assert!(answers[0].is_for(2) && !answers[0].is_for(1));
+        assert!(matches!(
+            answers[0].msg,
+            OutgoingMessage::WRITE_CANCELLED { cancelled: false }
+        ));
+        assert!(state.pending_write.is_some());
+
+        // The one that armed it can
+        handle_cancel_write(1, &tx.reply_to(origin(1)), &mut state);
+        let answers: Vec<NfcEvent> = events.try_iter().collect();
+        match &answers[0].msg {
+            OutgoingMessage::DATA_WRITE_ERROR { code, .. } => assert_eq!(code, "WRITE_CANCELLED"),
+            other => return Err(format!("not cancelled: {:?}", other).into()),
+        }
+        assert!(matches!(
+            answers[1].msg,
+            OutgoingMessage::WRITE_CANCELLED { cancelled: true }
+        ));
+        assert!(state.pending_write.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn armed_write_progress() -> TestResult {
+        let _serial = sim::serial();
+        let (events_tx, events) = crossbeam_channel::unbounded();
+        let tx = EventSender::broadcast(events_tx);
+        let mut state = ServiceState::new();
+        // A card left on the reader is reported, not written
+        state.card_present = true;
+        let spec = write_spec(
+            r#"{"type":"WRITE_DATA","data_type":"text","user_id":"EMP-13","arm":true,"verify":true}"#,
+        )?;
+        handle_arm_command(spec, 1, &tx, &mut state);
+        let answers: Vec<NfcEvent> = events.try_iter().collect();
+        assert!(matches!(
+            answers[0].msg,
+            OutgoingMessage::WRITE_PROGRESS {
+                stage: WriteStage::Armed,
+                uid: None,
+                card_present: Some(true),
+            }
+        ));
+
+        // Every stage after the tap names the card
+        let card = SimulatedCard::ntag(SimModel::Ntag215);
+        let kind = atr::parse_atr(&card.atr());
+        let pending = state.pending_write.take().ok_or("write not armed")?;
+        write_armed(&card, kind, Some("04AABBCC".into()), &pending);
+        let mut stages = Vec::new();
+        for event in events.try_iter() {
+            match event.msg {
+                OutgoingMessage::WRITE_PROGRESS { stage, uid, .. } => {
+                    assert_eq!(uid.as_deref(), Some("04AABBCC"));
+                    stages.push(stage);
+                }
+                OutgoingMessage::DATA_WRITE_SUCCESS { .. } => {}
+                other => return Err(format!("unexpected {:?}", other).into()),
+            }
+        }
+        assert_eq!(
+            stages,
+            vec![
+                WriteStage::CardDetected,
+                WriteStage::Writing,
+                WriteStage::Verifying,
+                WriteStage::Done
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn lock_token_per_client() -> TestResult {
+        let uid = [0x04, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66];
+        let token = |msg: OutgoingMessage| match msg {
+            OutgoingMessage::LOCK_CONFIRMATION_REQUIRED { token, .. } => Ok(token),
+            other => Err(format!("no token: {:?}", other)),
+        };
+        let mut state = ServiceState::new();
+        let first = token(issue_lock_token(1, &uid, &mut state))?;
+        // A second tab asking for its own token leaves the first one valid
+        let second = token(issue_lock_token(2, &uid, &mut state))?;
+        assert!(!take_lock_confirmation(2, &first, &uid, &mut state));
+        assert!(take_lock_confirmation(1, &first, &uid, &mut state));
+        // Each token is used up by one attempt
+        assert!(!take_lock_confirmation(1, &first, &uid, &mut state));
+        assert!(!take_lock_confirmation(2, &second, &uid, &mut state));
+        assert!(state.pending_locks.is_empty());
+        Ok(())
     }
 }
@@ -0,0 +1,359 @@
+// src/password.rs
+// NTAG21x / Ultralight EV1 password protection (PWD_AUTH).
+// Every tag gets its own 4-byte password and 2-byte PACK, derived from the
+// deployment secret in NFC_TAG_SECRET and the tag UID, so a password sniffed
+// from one tag does not open the others.
+//
+// Configuration pages follow user memory (NtagInfo::config_page):
+//   CFG0  MIRROR/MOD | RFUI | MIRROR_PAGE | AUTH0 (first protected page, past the end = off)
+//   CFG1  ACCESS (bit 7 PROT = reads protected too) | ...
+//   PWD   write-only, reads back as 00
+//   PACK  PACK (2 bytes) | RFUI
+use crate::apdu;
+use crate::cards::{self, NTAG_USER_START, NtagInfo};
+use crate::error::NfcError;
+use crate::transport::CardTransport;
+use hmac::{Hmac, Mac};
+use log::info;
+use serde::Deserialize;
+use sha2::Sha256;
+use std::sync::{Arc, RwLock};
+
+pub const SECRET_ENV: &str = "NFC_TAG_SECRET";
+
+// ACCESS byte of CFG1
+const PROT: u8 = 0x80;
+// AUTH0 value that protects nothing
+const AUTH0_OFF: u8 = 0xFF;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TagPassword {
+    pub pwd: [u8; 4],
+    pub pack: [u8; 2],
+}
+
+impl TagPassword {
+    // HMAC-SHA256(secret, uid): PWD is the first 4 bytes, PACK the next 2
+    pub fn derive(secret: &[u8], uid: &[u8]) -> Self {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC accepts any key length");
+        mac.update(uid);
+        let digest = mac.finalize().into_bytes();
+        TagPassword {
+            pwd: [digest[0], digest[1], digest[2], digest[3]],
+            pack: [digest[4], digest[5]],
+        }
+    }
+}
+
+// What AUTH0 / PROT should protect
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProtectMode {
+    // Protection off (AUTH0 past the end); the password stays on the tag
+    Off,
+    // Writes from the start page on need the password
+    Write,
+    // Reads and writes from the start page on need the password
+    ReadWrite,
+}
+
+// Protection as configured on a tag
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Protection {
+    pub auth0: u8,
+    pub read_protected: bool,
+}
+
+impl Protection {
+    // From the 16 bytes read at CFG0 (CFG0, CFG1, PWD, PACK)
+    fn from_config(config: &[u8]) -> Result<Self, NfcError> {
+        if config.len() < 8 {
+            return Err(NfcError::MalformedResponse { command: "Read" });
+        }
+        Ok(Protection {
+            auth0: config[3],
+            read_protected: config[4] & PROT != 0,
+        })
+    }
+
+    // Whether any page of the tag (configuration pages included) is protected
+    pub fn is_active(&self, tag: &NtagInfo) -> bool {
+        tag.config_page()
+            .is_some_and(|cfg0| (self.auth0 as u16) <= cfg0 as u16 + 3)
+    }
+}
+
+static SECRET: RwLock<Option<Arc<Vec<u8>>>> = RwLock::new(None);
+
+pub fn set_secret(secret: Option<Vec<u8>>) {
+    *SECRET.write().unwrap_or_else(|e| e.into_inner()) = secret.map(Arc::new);
+}
+
+fn secret() -> Option<Arc<Vec<u8>>> {
+    SECRET.read().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+// Take the deployment secret from NFC_TAG_SECRET, if set
+pub fn init_from_env() {
+    match std::env::var(SECRET_ENV) {
+        Ok(secret) if !secret.is_empty() => {
+            info!("NTAG password secret loaded from {}", SECRET_ENV);
+            set_secret(Some(secret.into_bytes()));
+        }
+        _ => info!(
+            "{} not set, password-protected tags cannot be opened",
+            SECRET_ENV
+        ),
+    }
+}
+
+// Password of the tag on the reader; None without a configured secret
+pub fn password_for(card: &dyn CardTransport) -> Result<Option<TagPassword>, NfcError> {
+    match secret() {
+        Some(secret) => Ok(Some(TagPassword::derive(&secret, &apdu::get_uid(card)?))),
+        None => Ok(None),
+    }
+}
+
+// PWD_AUTH, checking the PACK the tag answers with
+pub fn authenticate(card: &dyn CardTransport, password: &TagPassword) -> Result<(), NfcError> {
+    let pack = match apdu::pwd_auth(card, &password.pwd) {
+        Ok(pack) => pack,
+        Err(NfcError::TagRejected { .. }) => {
+            // The NAK halted the tag; wake it so the caller can report and carry on
+            apdu::reselect(card)?;
+            return Err(NfcError::PasswordRejected);
+        }
+        Err(e) => return Err(e),
+    };
+    if pack.get(..2) != Some(password.pack.as_slice()) {
+        return Err(NfcError::PackMismatch);
+    }
+    Ok(())
+}
+
+// Current protection; None when CFG0 cannot be read, i.e. reads are protected
+pub fn read_protection(
+    card: &dyn CardTransport,
+    tag: &NtagInfo,
+) -> Result<Option<Protection>, NfcError> {
+    let Some(cfg0) = tag.config_page() else {
+        return Ok(None);
+    };
+    match apdu::read_binary(card, cfg0, 16) {
+        Ok(config) => Protection::from_config(&config).map(Some),
+        Err(e) if e.is_transport() => Err(e),
+        Err(_) => {
+            apdu::reselect(card)?;
+            Ok(None)
+        }
+    }
+}
+
+// Authenticate with the derived password if the tag's protection covers what
+// is about to happen (`writing` or reading). Tags without a password are left alone.
+pub fn unlock(card: &dyn CardTransport, tag: &NtagInfo, writing: bool) -> Result<(), NfcError> {
+    if tag.config_page().is_none() {
+        return Ok(());
+    }
+    let needed = match read_protection(card, tag)? {
+        Some(protection) => protection.is_active(tag) && (writing || protection.read_protected),
+        // CFG0 itself is read-protected
+        None => true,
+    };
+    if !needed {
+        return Ok(());
+    }
+    let password = password_for(card)?.ok_or(NfcError::PasswordRequired)?;
+    authenticate(card, &password)
+}
+
+// Set the derived password and PACK and protect pages from `start_page` on
+// (`mode` Off lifts the protection). AUTH0 is written last, so the tag is never
+// protected with a password that did not make it onto the tag.
+pub fn set_protection(
+    card: &dyn CardTransport,
+    mode: ProtectMode,
+    start_page: u8,
+) -> Result<(), NfcError> {
+    let tag = cards::detect_ntag(card)?;
+    let Some(cfg0) = tag.config_page() else {
+        return Err(NfcError::UnsupportedCard(format!(
+            "{:?} has no password protection",
+            tag.model
+        )));
+    };
+    if mode != ProtectMode::Off && !(NTAG_USER_START..=cfg0 + 3).contains(&start_page) {
+        return Err(NfcError::InvalidWriteData(format!(
+            "start page must be between {} and {}",
+            NTAG_USER_START,
+            cfg0 + 3
+        )));
+    }
+    unlock(card, &tag, true)?;
+
+    let config = apdu::read_binary(card, cfg0, 16)?;
+    if config.len() < 8 {
+        return Err(NfcError::MalformedResponse { command: "Read" });
+    }
+    let mut cfg0_page = config[0..4].to_vec();
+    let mut cfg1_page = config[4..8].to_vec();
+
+    if mode == ProtectMode::Off {
+        cfg0_page[3] = AUTH0_OFF;
+        cfg1_page[0] &= !PROT;
+        apdu::update_binary(card, cfg0, &cfg0_page)?;
+        apdu::update_binary(card, cfg0 + 1, &cfg1_page)?;
+        info!("{:?}: password protection off", tag.model);
+        return Ok(());
+    }
+
+    let password = password_for(card)?.ok_or(NfcError::PasswordRequired)?;
+    if mode == ProtectMode::ReadWrite {
+        cfg1_page[0] |= PROT;
+    } else {
+        cfg1_page[0] &= !PROT;
+    }
+    cfg0_page[3] = start_page;
+    apdu::update_binary(card, cfg0 + 2, &password.pwd)?;
+    apdu::update_binary(
+        card,
+        cfg0 + 3,
+        &[password.pack[0], password.pack[1], 0x00, 0x00],
+    )?;
+    apdu::update_binary(card, cfg0 + 1, &cfg1_page)?;
+    apdu::update_binary(card, cfg0, &cfg0_page)?;
+    info!(
+        "{:?}: {:?} protection from page {}",
+        tag.model, mode, start_page
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::apdu;
+    use crate::cards::{self, WriteOptions};
+    use crate::error::NfcError;
+    use crate::ndef;
+    use crate::sim::{self, SimModel, SimulatedCard, TestResult, with_secret};
+
+    #[test]
+    fn ntag_password_derivation() -> TestResult {
+        let uid = [0x04, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66];
+        let password = TagPassword::derive(b"deployment", &uid);
+        assert_eq!(TagPassword::derive(b"deployment", &uid), password);
+        if TagPassword::derive(b"deployment", &[0x04, 0x11, 0x22, 0x33, 0x44, 0x55, 0x67])
+            == password
+            || TagPassword::derive(b"other deployment", &uid) == password
+        {
+            return Err("password does not depend on the uid and secret".into());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn ntag_write_protection() -> TestResult {
+        let _serial = sim::serial();
+        let card = SimulatedCard::ntag(SimModel::Ntag213);
+        let kind = with_secret(b"deployment", || {
+            let kind = sim::write_text(&card, "locked")?;
+            set_protection(&card, ProtectMode::Write, 4)?;
+            Ok(kind)
+        })?;
+
+        // Reads stay open; writes need the password
+        assert_eq!(
+            ndef::decode_ndef_content(&cards::read_card(&card, kind)?)?,
+            "locked".to_string()
+        );
+        let new_tlv = sim::text_tlv("changed");
+        assert_eq!(
+            sim::write_card(&card, kind, &new_tlv, &WriteOptions::default()),
+            Err(NfcError::PasswordRequired),
+        );
+        with_secret(b"another deployment", || {
+            assert_eq!(
+                sim::write_card(&card, kind, &new_tlv, &WriteOptions::default()),
+                Err(NfcError::PasswordRejected),
+            );
+            Ok(())
+        })?;
+        // The right secret authenticates automatically
+        with_secret(b"deployment", || {
+            let options = WriteOptions {
+                verify: true,
+                ..WriteOptions::default()
+            };
+            sim::write_card(&card, kind, &new_tlv, &options)?;
+            Ok(())
+        })?;
+        assert_eq!(
+            ndef::decode_ndef_content(&cards::read_card(&card, kind)?)?,
+            "changed".to_string()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn ntag_read_protection() -> TestResult {
+        let _serial = sim::serial();
+        let card = SimulatedCard::ntag(SimModel::Ntag216);
+        let user_id = "P".repeat(300);
+        let kind = with_secret(b"deployment", || {
+            let kind = sim::write_text(&card, &user_id)?;
+            set_protection(&card, ProtectMode::ReadWrite, 4)?;
+            Ok(kind)
+        })?;
+
+        assert_eq!(
+            cards::read_card(&card, kind),
+            Err(NfcError::PasswordRequired)
+        );
+        with_secret(b"deployment", || {
+            assert_eq!(
+                ndef::decode_ndef_content(&cards::read_card(&card, kind)?)?,
+                user_id.clone()
+            );
+            set_protection(&card, ProtectMode::Off, 4)?;
+            Ok(())
+        })?;
+        // Protection lifted: no secret needed any more
+        assert_eq!(
+            ndef::decode_ndef_content(&cards::read_card(&card, kind)?)?,
+            user_id
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn ntag_password_checks() -> TestResult {
+        let _serial = sim::serial();
+        with_secret(b"deployment", || {
+            // Chips without PWD_AUTH
+            let card = SimulatedCard::ntag(SimModel::Ultralight);
+            let res = set_protection(&card, ProtectMode::Write, 4);
+            assert_eq!(res.map_err(|e| e.code()), Err("UNSUPPORTED_CARD"));
+
+            let card = SimulatedCard::ntag(SimModel::UltralightEv1);
+            let res = set_protection(&card, ProtectMode::Write, 2);
+            assert_eq!(res.map_err(|e| e.code()), Err("INVALID_WRITE_DATA"));
+            set_protection(&card, ProtectMode::Write, 8)?;
+            let tag = cards::detect_ntag(&card)?;
+            assert_eq!(tag.config_page(), Some(0x10));
+            let protection = read_protection(&card, &tag)?;
+            assert_eq!(
+                protection.map(|p| (p.auth0, p.read_protected)),
+                Some((8, false))
+            );
+
+            // A tag answering with some other PACK is not one of ours
+            let password = password_for(&card)?.ok_or("no password")?;
+            authenticate(&card, &password)?;
+            apdu::update_binary(&card, 0x13, &[0x00, 0x00, 0x00, 0x00])?;
+            assert_eq!(authenticate(&card, &password), Err(NfcError::PackMismatch));
+            Ok(())
+        })
+    }
+}
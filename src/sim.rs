@@ -0,0 +1,795 @@
+// src/sim.rs
+// In-memory simulated cards that answer the same ACR122U pseudo-APDUs as a real
+// reader, plus helpers shared by the unit tests, so the read/write path is tested
+// without hardware.
+use crate::atr::{self, CardKind};
+use crate::cards::{self, ClassicLayout, WriteOptions};
+use crate::error::{NfcError, TransportError};
+use crate::keys::{KEY_A, KEY_B, KeyMap};
+use crate::ndef::{self, NdefRecord};
+use crate::password;
+use crate::trailer::{self, Access, AccessBits, AccessCondition, SectorTrailer};
+use crate::transport::CardTransport;
+use crate::types::{IncomingMessage, WriteSpec};
+use std::cell::RefCell;
+use std::sync::{Mutex, MutexGuard};
+
+const SW_SUCCESS: [u8; 2] = [0x90, 0x00];
+const SW_FAILED: [u8; 2] = [0x63, 0x00];
+const SW_SECURITY_NOT_SATISFIED: [u8; 2] = [0x69, 0x82];
+const SW_NOT_FOUND: [u8; 2] = [0x6A, 0x82];
+const SW_WRONG_LENGTH: [u8; 2] = [0x67, 0x00];
+const SW_INS_NOT_SUPPORTED: [u8; 2] = [0x6D, 0x00];
+
+// Factory trailer: Key A = FF..FF, access bits FF 07 80 (transport config), GPB 69, Key B = FF..FF
+const FACTORY_TRAILER: [u8; 16] = [
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x07, 0x80, 0x69, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SimModel {
+    MifareMini,
+    MifareClassic1K,
+    MifareClassic4K,
+    Ntag213,
+    Ntag215,
+    Ntag216,
+    Ultralight,
+    UltralightC,
+    // MF0UL11, 48 bytes of user memory
+    UltralightEv1,
+}
+
+impl SimModel {
+    // Kind the reader's ATR reports for this chip
+    pub fn kind(self) -> CardKind {
+        match self {
+            SimModel::MifareMini => CardKind::MifareMini,
+            SimModel::MifareClassic1K => CardKind::MifareClassic1K,
+            SimModel::MifareClassic4K => CardKind::MifareClassic4K,
+            SimModel::UltralightC => CardKind::UltralightC,
+            SimModel::Ntag213
+            | SimModel::Ntag215
+            | SimModel::Ntag216
+            | SimModel::Ultralight
+            | SimModel::UltralightEv1 => CardKind::Ultralight,
+        }
+    }
+
+    // Sector geometry of the Classic models
+    fn layout(self) -> Option<ClassicLayout> {
+        ClassicLayout::for_kind(self.kind())
+    }
+
+    // Total pages (NTAG, 4 bytes each) or blocks (Classic, 16 bytes each)
+    fn units(self) -> usize {
+        match self {
+            SimModel::MifareMini => 20,
+            SimModel::MifareClassic1K => 64,
+            SimModel::MifareClassic4K => 256,
+            SimModel::Ntag213 => 45,
+            SimModel::Ntag215 => 135,
+            SimModel::Ntag216 => 231,
+            SimModel::Ultralight => 16,
+            SimModel::UltralightC => 48,
+            SimModel::UltralightEv1 => 20,
+        }
+    }
+
+    fn unit_size(self) -> usize {
+        match self {
+            SimModel::MifareMini | SimModel::MifareClassic1K | SimModel::MifareClassic4K => 16,
+            _ => 4,
+        }
+    }
+
+    // Capability container size byte (user memory / 8)
+    fn cc_size(self) -> u8 {
+        match self {
+            SimModel::Ntag213 => 0x12,
+            SimModel::Ntag215 => 0x3E,
+            SimModel::Ntag216 => 0x6D,
+            SimModel::Ultralight | SimModel::UltralightEv1 => 0x06,
+            SimModel::UltralightC => 0x12,
+            SimModel::MifareMini | SimModel::MifareClassic1K | SimModel::MifareClassic4K => 0x00,
+        }
+    }
+
+    // PC/SC Part 3 card name bytes as the ACR122U reports them
+    fn card_name(self) -> [u8; 2] {
+        match self {
+            SimModel::MifareMini => [0x00, 0x26],
+            SimModel::MifareClassic1K => [0x00, 0x01],
+            SimModel::MifareClassic4K => [0x00, 0x02],
+            SimModel::UltralightC => [0x00, 0x3A],
+            _ => [0x00, 0x03],
+        }
+    }
+
+    // CFG0 page (then CFG1, PWD, PACK) of chips with password protection
+    fn config_page(self) -> Option<usize> {
+        match self {
+            SimModel::Ntag213 => Some(0x29),
+            SimModel::Ntag215 => Some(0x83),
+            SimModel::Ntag216 => Some(0xE3),
+            SimModel::UltralightEv1 => Some(0x10),
+            _ => None,
+        }
+    }
+
+    // Dynamic lock bytes page and pages locked per bit
+    fn dynamic_lock(self) -> Option<(usize, usize)> {
+        match self {
+            SimModel::Ntag213 => Some((0x28, 2)),
+            SimModel::Ntag215 => Some((0x82, 16)),
+            SimModel::Ntag216 => Some((0xE2, 16)),
+            _ => None,
+        }
+    }
+
+    // GET_VERSION response; None for chips that NAK it
+    fn version(self) -> Option<[u8; 8]> {
+        match self {
+            SimModel::Ntag213 => Some([0x00, 0x04, 0x04, 0x02, 0x01, 0x00, 0x0F, 0x03]),
+            SimModel::Ntag215 => Some([0x00, 0x04, 0x04, 0x02, 0x01, 0x00, 0x11, 0x03]),
+            SimModel::Ntag216 => Some([0x00, 0x04, 0x04, 0x02, 0x01, 0x00, 0x13, 0x03]),
+            SimModel::UltralightEv1 => Some([0x00, 0x04, 0x03, 0x01, 0x01, 0x00, 0x0B, 0x03]),
+            _ => None,
+        }
+    }
+}
+
+struct SimState {
+    memory: Vec<u8>,
+    loaded_key: Option<[u8; 6]>,
+    // Sector and key type (KEY_A / KEY_B) of the last successful authentication
+    authenticated: Option<(u8, u8)>,
+    removed: bool,
+    // A NAKed command halts a Type 2 tag until it is reselected
+    halted: bool,
+    // PWD_AUTH succeeded since the tag was last selected
+    pwd_authenticated: bool,
+    // LOAD KEY commands received, to measure the dictionary search
+    key_loads: usize,
+    // Every command received, to measure read strategies
+    exchanges: usize,
+    // Writes still stored before the card starts acknowledging writes it drops
+    writes_kept: Option<usize>,
+}
+
+pub struct SimulatedCard {
+    model: SimModel,
+    uid: Vec<u8>,
+    state: RefCell<SimState>,
+}
+
+impl SimulatedCard {
+    pub fn new(model: SimModel, uid: &[u8]) -> Self {
+        let mut memory = vec![0u8; model.units() * model.unit_size()];
+
+        match model.layout() {
+            Some(layout) => {
+                // Block 0: UID (4) | BCC | SAK | ATQA (2) | manufacturer data
+                let bcc = uid.iter().take(4).fold(0u8, |acc, b| acc ^ b);
+                memory[0..4].copy_from_slice(&uid[0..4]);
+                memory[4] = bcc;
+                memory[5] = 0x08;
+                memory[6] = 0x04;
+                memory[7] = 0x00;
+                for sector in 0..layout.sectors {
+                    let trailer = layout.trailer_block(sector) as usize * 16;
+                    memory[trailer..trailer + 16].copy_from_slice(&FACTORY_TRAILER);
+                }
+            }
+            None => {
+                // Page 0: UID0-2 | BCC0, Page 1: UID3-6, Page 2: BCC1 | internal | lock bytes
+                memory[0..3].copy_from_slice(&uid[0..3]);
+                memory[3] = 0x88 ^ uid[0] ^ uid[1] ^ uid[2];
+                memory[4..8].copy_from_slice(&uid[3..7]);
+                memory[8] = uid[3] ^ uid[4] ^ uid[5] ^ uid[6];
+                memory[9] = 0x48;
+                // Page 3: Capability Container (NDEF magic, version 1.0, size, read/write access)
+                memory[12..16].copy_from_slice(&[0xE1, 0x10, model.cc_size(), 0x00]);
+                // Factory configuration: AUTH0 = FF (nothing protected), PWD = FF FF FF FF
+                if let Some(cfg0) = model.config_page() {
+                    memory[cfg0 * 4 + 3] = 0xFF;
+                    memory[(cfg0 + 2) * 4..(cfg0 + 3) * 4].fill(0xFF);
+                }
+            }
+        }
+
+        Self {
+            model,
+            uid: uid.to_vec(),
+            state: RefCell::new(SimState {
+                memory,
+                loaded_key: None,
+                authenticated: None,
+                removed: false,
+                halted: false,
+                pwd_authenticated: false,
+                key_loads: 0,
+                exchanges: 0,
+                writes_kept: None,
+            }),
+        }
+    }
+
+    pub fn mifare_classic(model: SimModel) -> Self {
+        Self::new(model, &[0xDE, 0xAD, 0xBE, 0xEF])
+    }
+
+    pub fn mifare_classic_1k() -> Self {
+        Self::mifare_classic(SimModel::MifareClassic1K)
+    }
+
+    pub fn ntag(model: SimModel) -> Self {
+        Self::new(model, &[0x04, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66])
+    }
+
+    // Type 2 tag straight from the chip vendor: no capability container yet
+    pub fn ntag_unformatted(model: SimModel) -> Self {
+        let card = Self::ntag(model);
+        card.state.borrow_mut().memory[12..16].fill(0x00);
+        card
+    }
+
+    // Storage-card ATR: 3B 8F 80 01 80 4F 0C A0 00 00 03 06 03 C0 C1 00 00 00 00 TCK
+    pub fn atr(&self) -> Vec<u8> {
+        let name = self.model.card_name();
+        let mut atr = vec![
+            0x3B, 0x8F, 0x80, 0x01, 0x80, 0x4F, 0x0C, 0xA0, 0x00, 0x00, 0x03, 0x06, 0x03, name[0],
+            name[1], 0x00, 0x00, 0x00, 0x00,
+        ];
+        // TCK: XOR of every byte after TS
+        let tck = atr[1..].iter().fold(0u8, |acc, b| acc ^ b);
+        atr.push(tck);
+        atr
+    }
+
+    pub fn key_loads(&self) -> usize {
+        self.state.borrow().key_loads
+    }
+
+    pub fn exchanges(&self) -> usize {
+        self.state.borrow().exchanges
+    }
+
+    // Acknowledge every write after the first `n` without storing it, like a card
+    // that leaves the field while the reader still reports success
+    pub fn lose_writes_after(&self, n: usize) {
+        self.state.borrow_mut().writes_kept = Some(n);
+    }
+
+    // Take the card out of the field: every later transmit fails like a real removal
+    pub fn remove(&self) {
+        self.state.borrow_mut().removed = true;
+    }
+
+    fn is_classic(&self) -> bool {
+        self.model.kind().is_mifare_classic()
+    }
+
+    // Access bits of the sector holding `block`; None if they are corrupt
+    fn access_bits(state: &SimState, layout: &ClassicLayout, block: u8) -> Option<AccessBits> {
+        let trailer = layout.trailer_block(layout.sector_of(block)) as usize * 16;
+        let bytes = &state.memory[trailer + 6..trailer + 9];
+        AccessBits::decode(&[bytes[0], bytes[1], bytes[2]])
+    }
+
+    // Key type usable on `block`, or None if the authentication does not cover it.
+    // A Key B that the access bits make readable authenticates but grants nothing.
+    fn authenticated_key(
+        state: &SimState,
+        layout: &ClassicLayout,
+        block: u8,
+    ) -> Option<(u8, AccessBits)> {
+        let (sector, key_type) = state.authenticated?;
+        if sector != layout.sector_of(block) {
+            return None;
+        }
+        let access = Self::access_bits(state, layout, block)?;
+        if key_type == KEY_B && access.trailer().trailer_key_b_readable() {
+            return None;
+        }
+        Some((key_type, access))
+    }
+
+    // First password-protected page and whether reads are protected too;
+    // None if the chip has no password or PWD_AUTH already succeeded
+    fn protection(&self, state: &SimState) -> Option<(usize, bool)> {
+        let cfg0 = self.model.config_page()?;
+        if state.pwd_authenticated {
+            return None;
+        }
+        Some((
+            state.memory[cfg0 * 4 + 3] as usize,
+            state.memory[(cfg0 + 1) * 4] & 0x80 != 0,
+        ))
+    }
+
+    // Write-locked by the static (page 2) or dynamic lock bits
+    fn page_locked(&self, state: &SimState, page: usize) -> bool {
+        let lock = &state.memory[10..12];
+        match page {
+            3 => lock[0] & 0x08 != 0,
+            4..=7 => lock[0] & (1 << page) != 0,
+            8..=15 => lock[1] & (1 << (page - 8)) != 0,
+            _ => match self.model.dynamic_lock() {
+                Some((lock_page, pages_per_bit)) if (16..lock_page).contains(&page) => {
+                    let bit = (page - 16) / pages_per_bit;
+                    state.memory[lock_page * 4 + bit / 8] & (1 << (bit % 8)) != 0
+                }
+                _ => false,
+            },
+        }
+    }
+
+    fn read_protected(&self, state: &SimState, page: usize) -> bool {
+        self.protection(state)
+            .is_some_and(|(auth0, prot)| prot && page >= auth0)
+    }
+
+    // `length` bytes from `page` on, rolling over to page 0; PWD and PACK read as 00
+    fn read_pages(&self, state: &SimState, page: usize, length: usize) -> Vec<u8> {
+        let total = state.memory.len();
+        let hidden = self
+            .model
+            .config_page()
+            .map(|cfg0| (cfg0 + 2) * 4..(cfg0 + 4) * 4);
+        (0..length)
+            .map(|i| (page * 4 + i) % total)
+            .map(|at| {
+                if hidden.as_ref().is_some_and(|r| r.contains(&at)) {
+                    0x00
+                } else {
+                    state.memory[at]
+                }
+            })
+            .collect()
+    }
+
+    fn respond(data: &[u8], sw: [u8; 2]) -> Vec<u8> {
+        let mut resp = data.to_vec();
+        resp.extend_from_slice(&sw);
+        resp
+    }
+
+    fn get_data(&self, apdu: &[u8]) -> Vec<u8> {
+        // P1 = 00 is the UID; anything else (ATS) is not supported by these cards
+        if apdu[2] != 0x00 {
+            return Self::respond(&[], SW_FAILED);
+        }
+        match apdu[4] as usize {
+            0 => Self::respond(&self.uid, SW_SUCCESS),
+            le if le <= self.uid.len() => Self::respond(&self.uid[..le], SW_SUCCESS),
+            _ => Self::respond(&[], [0x6C, self.uid.len() as u8]),
+        }
+    }
+
+    // PN532 pass-through: FF 00 00 00 Lc D4 <cmd> ...
+    fn direct(&self, apdu: &[u8]) -> Vec<u8> {
+        if apdu.len() < 7 || apdu[5] != 0xD4 {
+            return Self::respond(&[], SW_FAILED);
+        }
+        let mut state = self.state.borrow_mut();
+        match apdu[6] {
+            // InListPassiveTarget: wakes a halted tag
+            0x4A => {
+                state.halted = false;
+                state.pwd_authenticated = false;
+                Self::respond(&[0xD5, 0x4B, 0x01, 0x01], SW_SUCCESS)
+            }
+            // InCommunicateThru
+            0x42 => {
+                let tag_cmd = &apdu[7..];
+                let answer = if state.halted || self.is_classic() {
+                    None
+                } else {
+                    match tag_cmd.first() {
+                        Some(0x60) => self.model.version().map(|v| v.to_vec()),
+                        // FAST_READ start end: only the chips that also have GET_VERSION
+                        Some(0x3A) if self.model.version().is_some() && tag_cmd.len() == 3 => {
+                            let (start, end) = (tag_cmd[1] as usize, tag_cmd[2] as usize);
+                            (start <= end
+                                && end < self.model.units()
+                                && !self.read_protected(&state, end))
+                            .then(|| self.read_pages(&state, start, (end - start + 1) * 4))
+                        }
+                        // PWD_AUTH: answers the PACK if the password matches
+                        Some(0x1B) if tag_cmd.len() == 5 && self.model.config_page().is_some() => {
+                            let cfg0 = self.model.config_page().unwrap_or_default();
+                            if state.memory[(cfg0 + 2) * 4..(cfg0 + 3) * 4] == tag_cmd[1..5] {
+                                state.pwd_authenticated = true;
+                                Some(state.memory[(cfg0 + 3) * 4..(cfg0 + 3) * 4 + 2].to_vec())
+                            } else {
+                                None
+                            }
+                        }
+                        // Ultralight C AUTHENTICATE part 1: AF + 8 bytes of ek(RndB)
+                        Some(0x1A) if self.model == SimModel::UltralightC => {
+                            Some(vec![0xAF, 0x5A, 0x17, 0x3C, 0x90, 0x01, 0xEE, 0x42, 0x7B])
+                        }
+                        _ => None,
+                    }
+                };
+                match answer {
+                    Some(data) => {
+                        let mut resp = vec![0xD5, 0x43, 0x00];
+                        resp.extend(data);
+                        Self::respond(&resp, SW_SUCCESS)
+                    }
+                    None => {
+                        // Timeout status, and the tag drops back to HALT
+                        state.halted = true;
+                        Self::respond(&[0xD5, 0x43, 0x01], SW_SUCCESS)
+                    }
+                }
+            }
+            _ => Self::respond(&[], SW_INS_NOT_SUPPORTED),
+        }
+    }
+
+    fn load_key(&self, apdu: &[u8]) -> Vec<u8> {
+        if apdu.len() != 11 || apdu[4] != 0x06 {
+            return Self::respond(&[], SW_WRONG_LENGTH);
+        }
+        let mut key = [0u8; 6];
+        key.copy_from_slice(&apdu[5..11]);
+        let mut state = self.state.borrow_mut();
+        state.loaded_key = Some(key);
+        state.key_loads += 1;
+        Self::respond(&[], SW_SUCCESS)
+    }
+
+    fn authenticate(&self, apdu: &[u8]) -> Vec<u8> {
+        let layout = match self.model.layout() {
+            Some(layout) if apdu.len() == 10 => layout,
+            _ => return Self::respond(&[], SW_FAILED),
+        };
+        let block = apdu[7];
+        let key_type = apdu[8];
+        let mut state = self.state.borrow_mut();
+        state.authenticated = None;
+
+        if block as usize >= self.model.units() {
+            return Self::respond(&[], SW_FAILED);
+        }
+        let sector = layout.sector_of(block);
+        let trailer = layout.trailer_block(sector) as usize * 16;
+        let expected = match key_type {
+            KEY_A => &state.memory[trailer..trailer + 6],
+            KEY_B => &state.memory[trailer + 10..trailer + 16],
+            _ => return Self::respond(&[], SW_FAILED),
+        };
+
+        match state.loaded_key {
+            Some(key) if key == expected => {
+                state.authenticated = Some((sector, key_type));
+                Self::respond(&[], SW_SUCCESS)
+            }
+            _ => Self::respond(&[], SW_FAILED),
+        }
+    }
+
+    fn read_binary(&self, apdu: &[u8]) -> Vec<u8> {
+        if apdu.len() != 5 {
+            return Self::respond(&[], SW_WRONG_LENGTH);
+        }
+        let unit = apdu[3] as usize;
+        let length = apdu[4] as usize;
+        let mut state = self.state.borrow_mut();
+
+        if state.halted {
+            return Self::respond(&[], SW_FAILED);
+        }
+        if unit >= self.model.units() {
+            return Self::respond(&[], SW_NOT_FOUND);
+        }
+
+        if let Some(layout) = self.model.layout() {
+            // In range, so at most block 255
+            let block = unit as u8;
+            if state.authenticated.map(|(sector, _)| sector) != Some(layout.sector_of(block)) {
+                return Self::respond(&[], SW_SECURITY_NOT_SATISFIED);
+            }
+            if length == 0 || length > 16 {
+                return Self::respond(&[], SW_WRONG_LENGTH);
+            }
+            // Data blocks follow the access bits; the trailer's access bits are always readable
+            if !layout.is_trailer(block) {
+                let allowed = Self::authenticated_key(&state, &layout, block).is_some_and(
+                    |(key_type, access)| {
+                        data_read(access.for_block(&layout, block)).allows(key_type)
+                    },
+                );
+                if !allowed {
+                    // A refused operation ends the authentication, as on a real card
+                    state.authenticated = None;
+                    return Self::respond(&[], SW_FAILED);
+                }
+            }
+            let mut bytes = state.memory[unit * 16..unit * 16 + 16].to_vec();
+            if layout.is_trailer(block) {
+                // Key A is never readable; Key B only when the access bits say so
+                bytes[0..6].fill(0x00);
+                let key_b_readable = AccessBits::decode(&[bytes[6], bytes[7], bytes[8]])
+                    .is_some_and(|access| access.trailer().trailer_key_b_readable());
+                if !key_b_readable {
+                    bytes[10..16].fill(0x00);
+                }
+            }
+            return Self::respond(&bytes[..length], SW_SUCCESS);
+        }
+
+        // NTAG READ returns 4 pages and rolls over to page 0 past the end
+        if length == 0 || length > 16 {
+            return Self::respond(&[], SW_WRONG_LENGTH);
+        }
+        if self.read_protected(&state, unit) {
+            state.halted = true;
+            return Self::respond(&[], SW_FAILED);
+        }
+        let data = self.read_pages(&state, unit, length);
+        Self::respond(&data, SW_SUCCESS)
+    }
+
+    fn update_binary(&self, apdu: &[u8]) -> Vec<u8> {
+        if apdu.len() < 5 || apdu.len() != 5 + apdu[4] as usize {
+            return Self::respond(&[], SW_WRONG_LENGTH);
+        }
+        let unit = apdu[3] as usize;
+        let data = &apdu[5..];
+        let size = self.model.unit_size();
+        let mut state = self.state.borrow_mut();
+
+        if state.halted {
+            return Self::respond(&[], SW_FAILED);
+        }
+        if unit >= self.model.units() {
+            return Self::respond(&[], SW_NOT_FOUND);
+        }
+        if data.len() != size {
+            return Self::respond(&[], SW_WRONG_LENGTH);
+        }
+
+        if let Some(layout) = self.model.layout() {
+            let block = unit as u8;
+            if state.authenticated.map(|(sector, _)| sector) != Some(layout.sector_of(block)) {
+                return Self::respond(&[], SW_SECURITY_NOT_SATISFIED);
+            }
+            let start = unit * 16;
+            let granted = Self::authenticated_key(&state, &layout, block);
+            if layout.is_trailer(block) {
+                // Keys and access bits (with the GPB) are granted separately
+                let (keys_ok, access_ok) = match granted {
+                    Some((key_type, access)) => (
+                        access.trailer().trailer_keys_write().allows(key_type),
+                        access.trailer().trailer_access_write().allows(key_type),
+                    ),
+                    None => (false, false),
+                };
+                if !keys_ok && !access_ok {
+                    state.authenticated = None;
+                    return Self::respond(&[], SW_FAILED);
+                }
+                if keys_ok {
+                    state.memory[start..start + 6].copy_from_slice(&data[0..6]);
+                    state.memory[start + 10..start + 16].copy_from_slice(&data[10..16]);
+                }
+                if access_ok {
+                    state.memory[start + 6..start + 10].copy_from_slice(&data[6..10]);
+                }
+                return Self::respond(&[], SW_SUCCESS);
+            }
+            let allowed = granted.is_some_and(|(key_type, access)| {
+                access
+                    .for_block(&layout, block)
+                    .data_write()
+                    .allows(key_type)
+            });
+            // Manufacturer block is read-only
+            if unit == 0 || !allowed {
+                state.authenticated = None;
+                return Self::respond(&[], SW_FAILED);
+            }
+        } else {
+            if self.page_locked(&state, unit)
+                || self
+                    .protection(&state)
+                    .is_some_and(|(auth0, _)| unit >= auth0)
+            {
+                state.halted = true;
+                return Self::respond(&[], SW_FAILED);
+            }
+            match unit {
+                // UID pages are read-only
+                0 | 1 => return Self::respond(&[], SW_FAILED),
+                // Lock bytes and CC are one-time-programmable: bits can only be set
+                2 | 3 => {
+                    let start = unit * size;
+                    let first = if unit == 2 { 2 } else { 0 };
+                    for (byte, &bits) in state.memory[start..start + size]
+                        .iter_mut()
+                        .zip(data)
+                        .skip(first)
+                    {
+                        *byte |= bits;
+                    }
+                    return Self::respond(&[], SW_SUCCESS);
+                }
+                // Dynamic lock bits are one-time-programmable too
+                _ if self
+                    .model
+                    .dynamic_lock()
+                    .is_some_and(|(lock_page, _)| unit == lock_page) =>
+                {
+                    for (byte, &bits) in state.memory[unit * size..unit * size + 3]
+                        .iter_mut()
+                        .zip(data)
+                    {
+                        *byte |= bits;
+                    }
+                    return Self::respond(&[], SW_SUCCESS);
+                }
+                _ => {}
+            }
+        }
+
+        match state.writes_kept {
+            Some(0) => return Self::respond(&[], SW_SUCCESS),
+            Some(n) => state.writes_kept = Some(n - 1),
+            None => {}
+        }
+        let start = unit * size;
+        state.memory[start..start + size].copy_from_slice(data);
+        Self::respond(&[], SW_SUCCESS)
+    }
+}
+
+impl CardTransport for SimulatedCard {
+    fn transmit(&self, apdu: &[u8]) -> Result<Vec<u8>, TransportError> {
+        if self.state.borrow().removed {
+            return Err(TransportError::CardRemoved);
+        }
+        self.state.borrow_mut().exchanges += 1;
+        if apdu.len() < 5 || apdu[0] != 0xFF {
+            return Ok(Self::respond(&[], SW_INS_NOT_SUPPORTED));
+        }
+        let resp = match apdu[1] {
+            0x00 => self.direct(apdu),
+            0xCA => self.get_data(apdu),
+            0x82 => self.load_key(apdu),
+            0x86 => self.authenticate(apdu),
+            0xB0 => self.read_binary(apdu),
+            0xD6 => self.update_binary(apdu),
+            _ => Self::respond(&[], SW_INS_NOT_SUPPORTED),
+        };
+        Ok(resp)
+    }
+}
+
+pub type TestResult = Result<(), Box<dyn std::error::Error>>;
+
+// Factory access bits FF 07 80: data A|B, trailer keys and access bits writable with Key A
+pub const TRANSPORT_ACCESS: AccessBits = AccessBits([
+    AccessCondition(0b000),
+    AccessCondition(0b000),
+    AccessCondition(0b000),
+    AccessCondition(0b001),
+]);
+
+// Key allowed to read a data block under `condition`; the service just tries its keys
+pub fn data_read(condition: AccessCondition) -> Access {
+    match condition.0 {
+        0b000 | 0b010 | 0b100 | 0b110 | 0b001 => Access::KeyAOrB,
+        0b011 | 0b101 => Access::KeyB,
+        _ => Access::Never,
+    }
+}
+
+// TLV holding one English Text record
+pub fn text_tlv(text: &str) -> Vec<u8> {
+    let record = NdefRecord::well_known(b"T", ndef::create_text_payload("en", text));
+    ndef::wrap_in_tlv(&ndef::encode_ndef_records(&[record]))
+}
+
+// Write `text` to `card` the way WRITE_DATA does; the kind its ATR reports
+pub fn write_text(
+    card: &SimulatedCard,
+    text: &str,
+) -> Result<CardKind, Box<dyn std::error::Error>> {
+    let kind = atr::parse_atr(&card.atr());
+    write_card(card, kind, &text_tlv(text), &WriteOptions::default())?;
+    Ok(kind)
+}
+
+// Factory card of `model` holding `text`, with its kind
+pub fn card_with_text(
+    model: SimModel,
+    text: &str,
+) -> Result<(SimulatedCard, CardKind), Box<dyn std::error::Error>> {
+    let card = match model.layout() {
+        Some(_) => SimulatedCard::mifare_classic(model),
+        None => SimulatedCard::ntag(model),
+    };
+    let kind = write_text(&card, text)?;
+    Ok((card, kind))
+}
+
+// write_card_reporting without progress reports
+pub fn write_card(
+    card: &dyn CardTransport,
+    kind: CardKind,
+    data: &[u8],
+    options: &WriteOptions,
+) -> Result<(), NfcError> {
+    cards::write_card_reporting(card, kind, data, options, &|_| {})
+}
+
+// The deployment secret, key dictionary and key cache are process-wide; tests that
+// touch cards or those settings hold this so they do not see each other's state
+static SERIAL: Mutex<()> = Mutex::new(());
+
+pub fn serial() -> MutexGuard<'static, ()> {
+    SERIAL.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+pub fn write_spec(json: &str) -> Result<WriteSpec, Box<dyn std::error::Error>> {
+    match serde_json::from_str::<IncomingMessage>(json)? {
+        IncomingMessage::WRITE_DATA(spec) => Ok(spec),
+        other => Err(format!("parsed as {:?}", other).into()),
+    }
+}
+
+// Give every sector of a 1K card `key` as both Key A and Key B
+pub fn rekey_all_sectors(card: &SimulatedCard, key: [u8; 6]) -> TestResult {
+    let layout = ClassicLayout::CLASSIC_1K;
+    let mut keys = KeyMap::default();
+    for sector in 0..layout.sectors {
+        let trailer = SectorTrailer {
+            key_a: key,
+            access: TRANSPORT_ACCESS,
+            gpb: 0x69,
+            key_b: key,
+        };
+        trailer::write_trailer(card, &layout, &mut keys, sector, &trailer)?;
+    }
+    Ok(())
+}
+
+// Re-key sector 1 of a factory 1K card with `data` as the condition of its data blocks
+pub fn provision_sector_1(
+    card: &SimulatedCard,
+    key_a: [u8; 6],
+    data: u8,
+    trailer_bits: u8,
+    key_b: [u8; 6],
+) -> TestResult {
+    let condition = AccessCondition(data);
+    let trailer = SectorTrailer {
+        key_a,
+        access: AccessBits([
+            condition,
+            condition,
+            condition,
+            AccessCondition(trailer_bits),
+        ]),
+        gpb: 0x69,
+        key_b,
+    };
+    let mut keys = KeyMap::default();
+    trailer::write_trailer(card, &ClassicLayout::CLASSIC_1K, &mut keys, 1, &trailer)?;
+    Ok(())
+}
+
+// Run `check` with `secret` as the deployment secret, then go back to none
+pub fn with_secret<T>(
+    secret: &[u8],
+    check: impl FnOnce() -> Result<T, Box<dyn std::error::Error>>,
+) -> Result<T, Box<dyn std::error::Error>> {
+    password::set_secret(Some(secret.to_vec()));
+    let result = check();
+    password::set_secret(None);
+    result
+}
@@ -0,0 +1,465 @@
+// src/trailer.rs
+// MIFARE Classic sector trailer: Key A (6) | access bits (3) | GPB (1) | Key B (6)
+//
+// The access bits hold C1/C2/C3 for four block groups (blocks 0, 1, 2 and the trailer;
+// in the 16-block sectors of a 4K card the groups are blocks 0-4, 5-9, 10-14 and 15),
+// each stored once inverted and once plain:
+//   byte 6: !C2[3..0] !C1[3..0]
+//   byte 7:  C1[3..0] !C3[3..0]
+//   byte 8:  C3[3..0]  C2[3..0]
+use crate::apdu;
+use crate::cards::{self, ClassicLayout};
+use crate::error::NfcError;
+use crate::keys::{KEY_A, KEY_B, KeyMap, SectorKeys};
+use crate::transport::CardTransport;
+
+// Which key may perform an operation
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    Never,
+    KeyA,
+    KeyB,
+    KeyAOrB,
+}
+
+impl Access {
+    pub fn allows(self, key_type: u8) -> bool {
+        match self {
+            Access::Never => false,
+            Access::KeyA => key_type == KEY_A,
+            Access::KeyB => key_type == KEY_B,
+            Access::KeyAOrB => true,
+        }
+    }
+}
+
+// C1 C2 C3 of one block group, as the value C1<<2 | C2<<1 | C3
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AccessCondition(pub u8);
+
+impl AccessCondition {
+    pub fn data_write(self) -> Access {
+        match self.0 {
+            0b000 => Access::KeyAOrB,
+            0b100 | 0b110 | 0b011 => Access::KeyB,
+            _ => Access::Never,
+        }
+    }
+
+    // Writing Key A / Key B when this is the trailer's condition
+    pub fn trailer_keys_write(self) -> Access {
+        match self.0 {
+            0b000 | 0b001 => Access::KeyA,
+            0b100 | 0b011 => Access::KeyB,
+            _ => Access::Never,
+        }
+    }
+
+    // Writing the access bits when this is the trailer's condition
+    pub fn trailer_access_write(self) -> Access {
+        match self.0 {
+            0b001 => Access::KeyA,
+            0b011 | 0b101 => Access::KeyB,
+            _ => Access::Never,
+        }
+    }
+
+    // Key B is plain data (readable with Key A) and cannot authenticate
+    pub fn trailer_key_b_readable(self) -> bool {
+        matches!(self.0, 0b000..=0b010)
+    }
+}
+
+// Access conditions of data groups 0-2 and of the trailer (index 3)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AccessBits(pub [AccessCondition; 4]);
+
+impl AccessBits {
+    // Permanently read-only: data readable with either key, nothing writable,
+    // access bits final (what LOCK_TAG writes)
+    pub const READ_ONLY: AccessBits = AccessBits([
+        AccessCondition(0b010),
+        AccessCondition(0b010),
+        AccessCondition(0b010),
+        AccessCondition(0b110),
+    ]);
+
+    // None if the inverted copies do not match
+    pub fn decode(bytes: &[u8; 3]) -> Option<Self> {
+        let c1 = bytes[1] >> 4;
+        let c2 = bytes[2] & 0x0F;
+        let c3 = bytes[2] >> 4;
+        if bytes[0] & 0x0F != !c1 & 0x0F
+            || bytes[0] >> 4 != !c2 & 0x0F
+            || bytes[1] & 0x0F != !c3 & 0x0F
+        {
+            return None;
+        }
+        let mut conditions = [AccessCondition(0); 4];
+        for (group, condition) in conditions.iter_mut().enumerate() {
+            let bit = |c: u8| (c >> group) & 1;
+            *condition = AccessCondition(bit(c1) << 2 | bit(c2) << 1 | bit(c3));
+        }
+        Some(AccessBits(conditions))
+    }
+
+    pub fn encode(&self) -> [u8; 3] {
+        let (mut c1, mut c2, mut c3) = (0u8, 0u8, 0u8);
+        for (group, condition) in self.0.iter().enumerate() {
+            c1 |= ((condition.0 >> 2) & 1) << group;
+            c2 |= ((condition.0 >> 1) & 1) << group;
+            c3 |= (condition.0 & 1) << group;
+        }
+        [
+            (!c2 & 0x0F) << 4 | (!c1 & 0x0F),
+            c1 << 4 | (!c3 & 0x0F),
+            c3 << 4 | c2,
+        ]
+    }
+
+    pub fn trailer(&self) -> AccessCondition {
+        self.0[3]
+    }
+
+    // No data block of the sector can be written with any key
+    pub fn data_read_only(&self) -> bool {
+        self.0[0..3]
+            .iter()
+            .all(|condition| condition.data_write() == Access::Never)
+    }
+
+    // Condition governing `block`, accounting for the 5-block groups of large sectors
+    pub fn for_block(&self, layout: &ClassicLayout, block: u8) -> AccessCondition {
+        let sector = layout.sector_of(block);
+        let offset = block - layout.first_block(sector);
+        let group = if layout.blocks_in_sector(sector) == 4 {
+            offset
+        } else {
+            (offset / 5).min(3)
+        };
+        self.0[group as usize]
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SectorTrailer {
+    pub key_a: [u8; 6],
+    pub access: AccessBits,
+    // General purpose byte (used by the MAD, otherwise free)
+    pub gpb: u8,
+    pub key_b: [u8; 6],
+}
+
+impl SectorTrailer {
+    // None if the access bits are inconsistent
+    pub fn from_bytes(block: &[u8; 16]) -> Option<Self> {
+        let mut key_a = [0u8; 6];
+        let mut key_b = [0u8; 6];
+        key_a.copy_from_slice(&block[0..6]);
+        key_b.copy_from_slice(&block[10..16]);
+        let access = AccessBits::decode(&[block[6], block[7], block[8]])?;
+        Some(SectorTrailer {
+            key_a,
+            access,
+            gpb: block[9],
+            key_b,
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; 16] {
+        let mut block = [0u8; 16];
+        block[0..6].copy_from_slice(&self.key_a);
+        block[6..9].copy_from_slice(&self.access.encode());
+        block[9] = self.gpb;
+        block[10..16].copy_from_slice(&self.key_b);
+        block
+    }
+
+    // Why writing this trailer would leave the sector impossible to reconfigure, if it would
+    pub fn lock_reason(&self) -> Option<&'static str> {
+        // Keys that cannot be written now can still be unlocked by changing the access bits,
+        // so only access bits that can never be written again are final
+        if self.access.trailer().trailer_access_write() == Access::Never {
+            return Some("access bits could never be changed again");
+        }
+        None
+    }
+}
+
+// Read the trailer of `sector`. Key A always reads back as zeros, and so does
+// Key B unless the access conditions make it readable.
+pub fn read_trailer(
+    card: &dyn CardTransport,
+    layout: &ClassicLayout,
+    keys: &mut KeyMap,
+    sector: u8,
+) -> Result<SectorTrailer, NfcError> {
+    let block = layout.trailer_block(sector);
+    cards::authenticate_sector(card, layout, keys, block, &[KEY_A, KEY_B])?;
+    let data = apdu::read_binary(card, block, 16)?;
+    let bytes: [u8; 16] = data
+        .as_slice()
+        .try_into()
+        .map_err(|_| NfcError::MalformedResponse { command: "Read" })?;
+    SectorTrailer::from_bytes(&bytes).ok_or(NfcError::InvalidAccessBits { sector })
+}
+
+// Rewrite the trailer of `sector`, refusing anything that would lock it for good.
+// The new keys replace the old ones in `keys`.
+pub fn write_trailer(
+    card: &dyn CardTransport,
+    layout: &ClassicLayout,
+    keys: &mut KeyMap,
+    sector: u8,
+    trailer: &SectorTrailer,
+) -> Result<(), NfcError> {
+    if let Some(reason) = trailer.lock_reason() {
+        return Err(NfcError::WouldLockSector { sector, reason });
+    }
+    cards::write_block_any_key(
+        card,
+        layout,
+        keys,
+        layout.trailer_block(sector),
+        &trailer.to_bytes(),
+    )?;
+    keys.record(sector, KEY_A, trailer.key_a);
+    if trailer.access.trailer().trailer_key_b_readable() {
+        // Key B is plain data under these access bits and cannot authenticate
+        keys.forget(sector, KEY_B);
+    } else {
+        keys.record(sector, KEY_B, trailer.key_b);
+    }
+    Ok(())
+}
+
+// Key of `key_type` for `sector`, from `keys` or by authenticating with the dictionary
+pub fn known_key(
+    card: &dyn CardTransport,
+    layout: &ClassicLayout,
+    keys: &mut KeyMap,
+    sector: u8,
+    key_type: u8,
+) -> Result<[u8; 6], NfcError> {
+    if let Some(key) = keys.get(sector, key_type) {
+        return Ok(key);
+    }
+    cards::authenticate_sector(
+        card,
+        layout,
+        keys,
+        layout.trailer_block(sector),
+        &[key_type],
+    )?;
+    keys.get(sector, key_type)
+        .ok_or(NfcError::AuthFailed { sector })
+}
+
+// Key type allowed to rewrite the access bits of `sector`; None if the sector is
+// read-only already. Fails for sectors whose data stays writable but whose
+// access bits can no longer change.
+pub fn lock_key_type(trailer: &SectorTrailer, sector: u8) -> Result<Option<u8>, NfcError> {
+    match trailer.access.trailer().trailer_access_write() {
+        Access::KeyA | Access::KeyAOrB => Ok(Some(KEY_A)),
+        Access::KeyB => Ok(Some(KEY_B)),
+        Access::Never if trailer.access.data_read_only() => Ok(None),
+        Access::Never => Err(NfcError::LockFailed(format!(
+            "sector {} access bits can no longer be changed",
+            sector
+        ))),
+    }
+}
+
+// Keys a trailer write with `key_type` overwrites and so must write back
+// unchanged: Key A and Key B as far as they cannot be read from `current`
+pub fn lock_keys(
+    card: &dyn CardTransport,
+    layout: &ClassicLayout,
+    keys: &mut KeyMap,
+    sector: u8,
+    current: &SectorTrailer,
+    key_type: u8,
+) -> Result<SectorKeys, NfcError> {
+    let condition = current.access.trailer();
+    let keys_written = condition.trailer_keys_write().allows(key_type);
+    let key_a = if keys_written {
+        Some(known_key(card, layout, keys, sector, KEY_A)?)
+    } else {
+        None
+    };
+    let key_b = if condition.trailer_key_b_readable() {
+        Some(current.key_b)
+    } else if keys_written {
+        Some(known_key(card, layout, keys, sector, KEY_B)?)
+    } else {
+        None
+    };
+    Ok(SectorKeys { key_a, key_b })
+}
+
+// Make `sector` read-only for good with READ_ONLY access bits, keeping its keys.
+// Unlike write_trailer this skips the lock guard: only for a confirmed LOCK_TAG.
+pub fn lock_sector(
+    card: &dyn CardTransport,
+    layout: &ClassicLayout,
+    keys: &mut KeyMap,
+    sector: u8,
+) -> Result<(), NfcError> {
+    let current = read_trailer(card, layout, keys, sector)?;
+    let Some(key_type) = lock_key_type(&current, sector)? else {
+        return Ok(());
+    };
+    let SectorKeys { key_a, key_b } = lock_keys(card, layout, keys, sector, &current, key_type)?;
+    let locked = SectorTrailer {
+        key_a: key_a.unwrap_or(current.key_a),
+        access: AccessBits::READ_ONLY,
+        gpb: current.gpb,
+        key_b: key_b.unwrap_or(current.key_b),
+    };
+
+    let block = layout.trailer_block(sector);
+    cards::authenticate_sector(card, layout, keys, block, &[key_type])?;
+    apdu::update_binary(card, block, &locked.to_bytes())?;
+    // Key B is no longer readable, so it now authenticates
+    if let Some(key_b) = key_b {
+        keys.record(sector, KEY_B, key_b);
+    }
+
+    if read_trailer(card, layout, keys, sector)?.access != AccessBits::READ_ONLY {
+        return Err(NfcError::LockFailed(format!(
+            "sector {} access bits did not change",
+            sector
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::apdu;
+    use crate::cards::ClassicLayout;
+    use crate::keys::KeyMap;
+    use crate::sim::{self, SimulatedCard, TestResult};
+
+    #[test]
+    fn trailer_access_bits_codec() -> TestResult {
+        assert_eq!(
+            AccessBits::decode(&[0xFF, 0x07, 0x80]),
+            Some(sim::TRANSPORT_ACCESS)
+        );
+        let ndef = AccessBits::decode(&[0x7F, 0x07, 0x88]).ok_or("7F 07 88 rejected")?;
+        assert_eq!(ndef.0.map(|c| c.0), [0b000, 0b000, 0b000, 0b011]);
+        let mad = AccessBits::decode(&[0x78, 0x77, 0x88]).ok_or("78 77 88 rejected")?;
+        assert_eq!(mad.0.map(|c| c.0), [0b100, 0b100, 0b100, 0b011]);
+        // Every combination survives an encode/decode round trip
+        for value in 0..4096u16 {
+            let bits = AccessBits(std::array::from_fn(|g| {
+                AccessCondition((value >> (g * 3)) as u8 & 7)
+            }));
+            assert_eq!(AccessBits::decode(&bits.encode()), Some(bits));
+        }
+        // A flipped bit breaks the inverted copy
+        assert_eq!(AccessBits::decode(&[0xFF, 0x07, 0x81]), None);
+        assert_eq!(AccessBits::decode(&[0xFF, 0x0F, 0x80]), None);
+        Ok(())
+    }
+
+    #[test]
+    fn trailer_access_conditions() -> TestResult {
+        let k1 = ClassicLayout::CLASSIC_1K;
+        let k4 = ClassicLayout::CLASSIC_4K;
+        let transport = sim::TRANSPORT_ACCESS;
+        assert_eq!(transport.for_block(&k1, 5).data_write(), Access::KeyAOrB);
+        assert_eq!(transport.trailer().trailer_access_write(), Access::KeyA);
+        assert!(transport.trailer().trailer_key_b_readable());
+
+        let mad = AccessBits::decode(&[0x78, 0x77, 0x88]).ok_or("78 77 88 rejected")?;
+        assert!(sim::data_read(mad.for_block(&k1, 1)).allows(KEY_A));
+        assert!(!mad.for_block(&k1, 1).data_write().allows(KEY_A));
+        assert!(mad.for_block(&k1, 1).data_write().allows(KEY_B));
+        assert_eq!(mad.trailer().trailer_keys_write(), Access::KeyB);
+        assert!(!mad.trailer().trailer_key_b_readable());
+
+        // 16-block sectors: groups of five blocks, then the trailer
+        let bits = AccessBits([
+            AccessCondition(0b000),
+            AccessCondition(0b010),
+            AccessCondition(0b111),
+            AccessCondition(0b001),
+        ]);
+        assert_eq!(bits.for_block(&k4, 132), AccessCondition(0b000));
+        assert_eq!(bits.for_block(&k4, 133), AccessCondition(0b010));
+        assert_eq!(sim::data_read(bits.for_block(&k4, 142)), Access::Never);
+        assert_eq!(bits.for_block(&k4, 143), AccessCondition(0b001));
+        Ok(())
+    }
+
+    #[test]
+    fn trailer_custom_keys() -> TestResult {
+        let _serial = sim::serial();
+        let card = SimulatedCard::mifare_classic_1k();
+        let mut keys = KeyMap::default();
+        let layout = ClassicLayout::CLASSIC_1K;
+        let custom = SectorTrailer {
+            key_a: [0xB0, 0xB1, 0xB2, 0xB3, 0xB4, 0xB5],
+            access: AccessBits::decode(&[0x7F, 0x07, 0x88]).ok_or("7F 07 88 rejected")?,
+            gpb: 0x69,
+            key_b: [0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0xFF],
+        };
+        write_trailer(&card, &layout, &mut keys, 2, &custom)?;
+
+        // The factory key no longer opens the sector, the new ones do
+        apdu::load_key(&card, &[0xFF; 6])?;
+        if apdu::authenticate(&card, 8, KEY_A).is_ok() {
+            return Err("factory key still accepted".into());
+        }
+        apdu::load_key(&card, &custom.key_b)?;
+        apdu::authenticate(&card, 8, KEY_B)?;
+
+        // Keys read back as zeros under these access bits
+        let read = read_trailer(&card, &layout, &mut keys, 2)?;
+        assert_eq!(read.access, custom.access);
+        assert_eq!(read.gpb, 0x69);
+        assert_eq!((read.key_a, read.key_b), ([0; 6], [0; 6]));
+        Ok(())
+    }
+
+    #[test]
+    fn trailer_lock_guard() -> TestResult {
+        let _serial = sim::serial();
+        let card = SimulatedCard::mifare_classic_1k();
+        let mut keys = KeyMap::default();
+        let layout = ClassicLayout::CLASSIC_1K;
+        let mut trailer = SectorTrailer::from_bytes(&[
+            0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x07, 0x80, 0x69, 0xFF, 0xFF, 0xFF, 0xFF,
+            0xFF, 0xFF,
+        ])
+        .ok_or("factory trailer rejected")?;
+        for condition in [0b000, 0b010, 0b100, 0b110, 0b111] {
+            trailer.access.0[3] = AccessCondition(condition);
+            match write_trailer(&card, &layout, &mut keys, 1, &trailer) {
+                Ok(()) => {
+                    return Err(format!("trailer condition {:03b} accepted", condition).into());
+                }
+                Err(e) => assert_eq!(e.code(), "WOULD_LOCK_SECTOR"),
+            }
+        }
+        assert_eq!(
+            read_trailer(&card, &layout, &mut keys, 1)?.access,
+            sim::TRANSPORT_ACCESS
+        );
+
+        // Inconsistent access bits already on the card are reported, not decoded
+        apdu::load_key(&card, &[0xFF; 6])?;
+        apdu::authenticate(&card, 7, KEY_A)?;
+        let mut corrupt = [0xFFu8; 16];
+        corrupt[6..10].copy_from_slice(&[0xFF, 0x07, 0x81, 0x69]);
+        apdu::update_binary(&card, 7, &corrupt)?;
+        match read_trailer(&card, &layout, &mut keys, 1) {
+            Ok(_) => return Err("corrupt access bits decoded".into()),
+            Err(e) => assert_eq!(e.code(), "INVALID_ACCESS_BITS"),
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,19 @@
+// src/transport.rs
+use crate::error::TransportError;
+
+// Anything that can exchange APDUs with a card.
+// The APDU layer only talks to this trait, so the read/write path can run
+// against a simulated card (see sim.rs) as well as a real PC/SC reader.
+pub trait CardTransport {
+    // Send one command APDU and return the full response, status word included
+    fn transmit(&self, apdu: &[u8]) -> Result<Vec<u8>, TransportError>;
+}
+
+impl CardTransport for pcsc::Card {
+    fn transmit(&self, apdu: &[u8]) -> Result<Vec<u8>, TransportError> {
+        let mut recv_buffer = [0u8; 256];
+        pcsc::Card::transmit(self, apdu, &mut recv_buffer)
+            .map(|resp| resp.to_vec())
+            .map_err(TransportError::from)
+    }
+}
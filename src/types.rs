@@ -1,33 +1,905 @@
 // src/types.rs
+use crate::atr::CardKind;
+use crate::cards::{NTAG_USER_START, Touched};
+use crate::dump::CardDump;
+use crate::error::NfcError;
+use crate::keys::KeyMap;
+use crate::ndef::{self, NdefRecord};
+use crate::password::ProtectMode;
 use serde::{Deserialize, Serialize};
+use std::time::Duration;
 
 // Messages sent TO the WebSocket client (Frontend)
+#[allow(non_camel_case_types)]
 #[derive(Serialize, Clone, Debug)]
 #[serde(tag = "type")]
 pub enum OutgoingMessage {
-    READER_STATUS { success: bool },
-    CARD_STATUS { success: bool, message: String },
-    DATA_READ_SUCCESS { data: String },
-    DATA_READ_ERROR { error: String },
-    DATA_WRITE_SUCCESS { message: String },
-    DATA_WRITE_ERROR { error: String },
-    READER_ERROR { error: String },
+    READER_STATUS {
+        success: bool,
+    },
+    CARD_STATUS {
+        success: bool,
+        message: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        uid: Option<String>,
+    },
+    // `data` is the first Text or URI record. READ_DATA can ask for the raw
+    // memory (hex) and every record as well, or for the UID only (no `data`).
+    DATA_READ_SUCCESS {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        data: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        uid: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        raw: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        records: Option<Vec<RecordReport>>,
+    },
+    DATA_READ_ERROR {
+        error: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        uid: Option<String>,
+        code: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        status_word: Option<String>,
+    },
+    DATA_WRITE_SUCCESS {
+        message: String,
+    },
+    // Stages of an armed WRITE_DATA; the write still ends with DATA_WRITE_SUCCESS / _ERROR
+    WRITE_PROGRESS {
+        stage: WriteStage,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        uid: Option<String>,
+        // Armed stage only: whether a card is already on the reader. That card is
+        // not written; it has to be taken off and tapped again.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        card_present: Option<bool>,
+    },
+    // Answer to CANCEL_WRITE: whether an armed write was waiting
+    WRITE_CANCELLED {
+        cancelled: bool,
+    },
+    DATA_WRITE_ERROR {
+        error: String,
+        code: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        status_word: Option<String>,
+    },
+    READER_ERROR {
+        error: String,
+    },
+    TAG_PROTECTION_SUCCESS {
+        message: String,
+    },
+    TAG_PROTECTION_ERROR {
+        error: String,
+        code: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        status_word: Option<String>,
+    },
+    // Answer to LOCK_TAG without a token: send LOCK_TAG again with `confirm: token`
+    LOCK_CONFIRMATION_REQUIRED {
+        uid: String,
+        token: String,
+        expires_in: u64,
+    },
+    TAG_LOCK_SUCCESS {
+        message: String,
+        uid: String,
+    },
+    // FORMAT_CARD / ERASE_CARD results: `blocks` on MIFARE Classic, `pages` on Type 2 tags
+    CARD_FORMAT_SUCCESS {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        uid: Option<String>,
+        card_type: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        blocks: Option<Vec<u8>>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pages: Option<Vec<u8>>,
+    },
+    CARD_FORMAT_ERROR {
+        error: String,
+        code: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        status_word: Option<String>,
+    },
+    CARD_ERASE_SUCCESS {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        uid: Option<String>,
+        card_type: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        blocks: Option<Vec<u8>>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pages: Option<Vec<u8>>,
+    },
+    CARD_ERASE_ERROR {
+        error: String,
+        code: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        status_word: Option<String>,
+    },
+    // DUMP_CARD result: the dump, and the same memory as a `.mfd` / `.bin` image in hex
+    CARD_DUMP_SUCCESS {
+        dump: CardDump,
+        image: String,
+    },
+    CARD_DUMP_ERROR {
+        error: String,
+        code: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        status_word: Option<String>,
+    },
+    CARD_RESTORE_SUCCESS {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        uid: Option<String>,
+        card_type: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        blocks: Option<Vec<u8>>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pages: Option<Vec<u8>>,
+    },
+    CARD_RESTORE_ERROR {
+        error: String,
+        code: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        status_word: Option<String>,
+    },
+    TAG_LOCK_ERROR {
+        error: String,
+        code: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        status_word: Option<String>,
+    },
+    // GET_KEY_REPORT result: which MIFARE Classic keys opened which sectors of the card
+    CARD_KEY_REPORT {
+        uid: String,
+        sectors: Vec<SectorKeyReport>,
+    },
+    CARD_KEY_REPORT_ERROR {
+        error: String,
+        code: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        status_word: Option<String>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SectorKeyReport {
+    pub sector: u8,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_a: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_b: Option<String>,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum WriteStage {
+    // Waiting for a card to be tapped
+    Armed,
+    CardDetected,
+    Writing,
+    Verifying,
+    Done,
+}
+
+// One NDEF record as sent to the frontend; `text` / `uri` for records we can decode
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct RecordReport {
+    pub tnf: u8,
+    pub record_type: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub id: String,
+    pub payload: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lang: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uri: Option<String>,
+}
+
+impl From<&NdefRecord> for RecordReport {
+    fn from(record: &NdefRecord) -> Self {
+        let (lang, text) = record.decode_text().unzip();
+        RecordReport {
+            tnf: record.tnf as u8,
+            record_type: String::from_utf8_lossy(&record.record_type).into_owned(),
+            id: hex::encode_upper(&record.id),
+            payload: hex::encode_upper(&record.payload),
+            lang,
+            text,
+            uri: record.decode_uri(),
+        }
+    }
+}
+
+pub fn sector_key_reports(keys: &KeyMap) -> Vec<SectorKeyReport> {
+    keys.sectors()
+        .map(|(sector, found)| SectorKeyReport {
+            sector,
+            key_a: found.key_a.map(hex::encode_upper),
+            key_b: found.key_b.map(hex::encode_upper),
+        })
+        .collect()
+}
+
+// `error`, `code` and `status_word` of the *_ERROR messages
+fn error_fields(err: &NfcError) -> (String, String, Option<String>) {
+    (
+        err.to_string(),
+        err.code().into(),
+        err.status_word().map(|sw| sw.to_string()),
+    )
+}
+
+impl OutgoingMessage {
+    pub fn read_error(err: &NfcError, uid: Option<String>) -> Self {
+        let (error, code, status_word) = error_fields(err);
+        OutgoingMessage::DATA_READ_ERROR {
+            error,
+            uid,
+            code,
+            status_word,
+        }
+    }
+
+    pub fn key_report(uid: &[u8], keys: &KeyMap) -> Self {
+        OutgoingMessage::CARD_KEY_REPORT {
+            uid: hex::encode_upper(uid),
+            sectors: sector_key_reports(keys),
+        }
+    }
+
+    pub fn write_error(err: &NfcError) -> Self {
+        let (error, code, status_word) = error_fields(err);
+        OutgoingMessage::DATA_WRITE_ERROR {
+            error,
+            code,
+            status_word,
+        }
+    }
+
+    // CARD_ERASE_SUCCESS when `erase`, CARD_FORMAT_SUCCESS otherwise
+    pub fn format_success(
+        erase: bool,
+        uid: Option<String>,
+        kind: CardKind,
+        touched: Touched,
+    ) -> Self {
+        let card_type = kind.to_string();
+        let (blocks, pages) = match touched {
+            Touched::Blocks(blocks) => (Some(blocks), None),
+            Touched::Pages(pages) => (None, Some(pages)),
+        };
+        if erase {
+            OutgoingMessage::CARD_ERASE_SUCCESS {
+                uid,
+                card_type,
+                blocks,
+                pages,
+            }
+        } else {
+            OutgoingMessage::CARD_FORMAT_SUCCESS {
+                uid,
+                card_type,
+                blocks,
+                pages,
+            }
+        }
+    }
+
+    // CARD_ERASE_ERROR when `erase`, CARD_FORMAT_ERROR otherwise
+    pub fn format_error(erase: bool, err: &NfcError) -> Self {
+        let (error, code, status_word) = error_fields(err);
+        if erase {
+            OutgoingMessage::CARD_ERASE_ERROR {
+                error,
+                code,
+                status_word,
+            }
+        } else {
+            OutgoingMessage::CARD_FORMAT_ERROR {
+                error,
+                code,
+                status_word,
+            }
+        }
+    }
+
+    pub fn restore_success(uid: Option<String>, kind: CardKind, touched: Touched) -> Self {
+        let (blocks, pages) = match touched {
+            Touched::Blocks(blocks) => (Some(blocks), None),
+            Touched::Pages(pages) => (None, Some(pages)),
+        };
+        OutgoingMessage::CARD_RESTORE_SUCCESS {
+            uid,
+            card_type: kind.to_string(),
+            blocks,
+            pages,
+        }
+    }
+
+    pub fn dump_error(err: &NfcError) -> Self {
+        let (error, code, status_word) = error_fields(err);
+        OutgoingMessage::CARD_DUMP_ERROR {
+            error,
+            code,
+            status_word,
+        }
+    }
+
+    pub fn restore_error(err: &NfcError) -> Self {
+        let (error, code, status_word) = error_fields(err);
+        OutgoingMessage::CARD_RESTORE_ERROR {
+            error,
+            code,
+            status_word,
+        }
+    }
+
+    pub fn key_report_error(err: &NfcError) -> Self {
+        let (error, code, status_word) = error_fields(err);
+        OutgoingMessage::CARD_KEY_REPORT_ERROR {
+            error,
+            code,
+            status_word,
+        }
+    }
+
+    pub fn lock_error(err: &NfcError) -> Self {
+        let (error, code, status_word) = error_fields(err);
+        OutgoingMessage::TAG_LOCK_ERROR {
+            error,
+            code,
+            status_word,
+        }
+    }
+
+    pub fn protection_error(err: &NfcError) -> Self {
+        let (error, code, status_word) = error_fields(err);
+        OutgoingMessage::TAG_PROTECTION_ERROR {
+            error,
+            code,
+            status_word,
+        }
+    }
 }
 
 // Messages received FROM the WebSocket client
+#[allow(non_camel_case_types)]
 #[derive(Deserialize, Debug)]
 #[serde(tag = "type")]
 pub enum IncomingMessage {
     GET_READER_STATUS,
-    WRITE_DATA { data_type: String, user_id: String },
+    WRITE_DATA(WriteSpec),
+    SET_TAG_PROTECTION(ProtectionSpec),
+    LOCK_TAG(LockSpec),
+    // Read the card on the reader now; always answered, repeats included
+    READ_DATA(ReadSpec),
+    // NFC Forum format: empty NDEF message plus CC (Type 2) or MAD and trailers (Classic)
+    FORMAT_CARD,
+    // Zero user memory, keeping CC, lock and configuration pages and sector trailers
+    ERASE_CARD,
+    // Every block / page the service can read, with the keys that opened them
+    DUMP_CARD,
+    RESTORE_CARD(RestoreSpec),
+    // Abort the armed WRITE_DATA waiting for a card
+    CANCEL_WRITE,
+    // Keys the service found for the card on the reader (plaintext, requester only)
+    GET_KEY_REPORT,
+}
+
+impl IncomingMessage {
+    pub fn into_command(self) -> NfcCommand {
+        match self {
+            IncomingMessage::GET_READER_STATUS => NfcCommand::CheckReaderStatus,
+            IncomingMessage::WRITE_DATA(spec) => NfcCommand::Write { spec },
+            IncomingMessage::SET_TAG_PROTECTION(spec) => NfcCommand::SetProtection { spec },
+            IncomingMessage::LOCK_TAG(spec) => NfcCommand::Lock { spec },
+            IncomingMessage::READ_DATA(spec) => NfcCommand::Read { spec },
+            IncomingMessage::FORMAT_CARD => NfcCommand::Format,
+            IncomingMessage::ERASE_CARD => NfcCommand::Erase,
+            IncomingMessage::DUMP_CARD => NfcCommand::Dump,
+            IncomingMessage::RESTORE_CARD(spec) => NfcCommand::Restore { spec },
+            IncomingMessage::CANCEL_WRITE => NfcCommand::CancelWrite,
+            IncomingMessage::GET_KEY_REPORT => NfcCommand::KeyReport,
+        }
+    }
+}
+
+// A client message as it arrives: any IncomingMessage, plus an optional
+// `request_id` (any JSON value) echoed on every answer to it
+#[derive(Deserialize, Debug)]
+pub struct ClientMessage {
+    #[serde(flatten)]
+    pub msg: IncomingMessage,
+    #[serde(default)]
+    pub request_id: Option<serde_json::Value>,
+}
+
+// Payload of WRITE_DATA. `data_type` selects the record kind:
+//   "text"  - Text record from `user_id`, language `lang` (default "en")
+//   "uri"   - URI record from `user_id`
+//   "mime"  - MIME record of `content_type` with `user_id` as the body
+//   "raw"   - `user_id` is a hex-encoded NDEF message written as-is
+//   "multi" - one record per entry of `records`
+// `nfc_forum` formats MIFARE Classic cards with a MAD first so phones can read them.
+// `verify` reads the card back after writing and checks the bytes and the decoded NDEF.
+// `arm` waits for the next card tapped on a reader instead of writing to the one already
+// there, for `timeout_secs` (default 30, at most 300), reporting WRITE_PROGRESS on the way.
+// The armed stage says whether a card is on the reader, since it must be tapped again.
+#[derive(Deserialize, Debug, Clone)]
+pub struct WriteSpec {
+    pub data_type: String,
+    #[serde(default)]
+    pub user_id: String,
+    #[serde(default)]
+    pub lang: Option<String>,
+    #[serde(default)]
+    pub content_type: Option<String>,
+    #[serde(default)]
+    pub records: Vec<RecordSpec>,
+    #[serde(default)]
+    pub nfc_forum: bool,
+    #[serde(default)]
+    pub verify: bool,
+    #[serde(default)]
+    pub arm: bool,
+    #[serde(default)]
+    pub timeout_secs: Option<u64>,
+}
+
+// Payload of READ_DATA. `reader` picks the reader by name (default: the first one
+// with a card). `raw` adds the user memory as hex, `records` every NDEF record;
+// `uid_only` answers with the UID without reading the card's memory.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct ReadSpec {
+    #[serde(default)]
+    pub reader: Option<String>,
+    #[serde(default)]
+    pub raw: bool,
+    #[serde(default)]
+    pub records: bool,
+    #[serde(default)]
+    pub uid_only: bool,
+}
+
+// Payload of RESTORE_CARD: a dump as sent by CARD_DUMP_SUCCESS, or a hex
+// `.mfd` / `.bin` image. The card must be the same type and size as the dumped one.
+#[derive(Deserialize, Debug, Clone)]
+pub struct RestoreSpec {
+    #[serde(default)]
+    pub dump: Option<CardDump>,
+    #[serde(default)]
+    pub image: Option<String>,
+}
+
+impl RestoreSpec {
+    pub fn to_dump(&self) -> Result<CardDump, NfcError> {
+        match (&self.dump, &self.image) {
+            (Some(dump), _) => Ok(dump.clone()),
+            (None, Some(image)) => {
+                let bytes = hex::decode(image)
+                    .map_err(|_| NfcError::InvalidDump("image is not valid hex".into()))?;
+                CardDump::from_image(&bytes).map_err(NfcError::InvalidDump)
+            }
+            (None, None) => Err(NfcError::InvalidDump("needs a dump or an image".into())),
+        }
+    }
+}
+
+// Payload of SET_TAG_PROTECTION (NTAG21x / Ultralight EV1). The tag's password and
+// PACK are derived from NFC_TAG_SECRET and its UID. `mode` is "off", "write" or
+// "read_write"; `start_page` is the first protected page (AUTH0), 4 = all user memory.
+#[derive(Deserialize, Debug, Clone)]
+pub struct ProtectionSpec {
+    pub mode: ProtectMode,
+    #[serde(default = "first_user_page")]
+    pub start_page: u8,
+}
+
+fn first_user_page() -> u8 {
+    NTAG_USER_START
+}
+
+// Payload of LOCK_TAG. Locking is permanent, so it takes two requests: the first,
+// without `confirm`, is answered with LOCK_CONFIRMATION_REQUIRED and a token; the
+// second carries that token back and locks the same card.
+#[derive(Deserialize, Debug, Clone)]
+pub struct LockSpec {
+    #[serde(default)]
+    pub confirm: Option<String>,
+}
+
+// One entry of a "multi" write
+#[derive(Deserialize, Debug, Clone)]
+pub struct RecordSpec {
+    pub data_type: String,
+    #[serde(default)]
+    pub value: String,
+    #[serde(default)]
+    pub lang: Option<String>,
+    #[serde(default)]
+    pub content_type: Option<String>,
+}
+
+impl WriteSpec {
+    pub const DEFAULT_ARM_TIMEOUT: Duration = Duration::from_secs(30);
+    pub const MAX_ARM_TIMEOUT: Duration = Duration::from_secs(300);
+
+    // How long an armed write waits for a card
+    pub fn arm_timeout(&self) -> Duration {
+        self.timeout_secs
+            .map_or(Self::DEFAULT_ARM_TIMEOUT, Duration::from_secs)
+            .min(Self::MAX_ARM_TIMEOUT)
+    }
+
+    // Build the NDEF message bytes (without TLV wrapping) for this write
+    pub fn to_ndef_message(&self) -> Result<Vec<u8>, NfcError> {
+        match self.data_type.as_str() {
+            "raw" => {
+                let bytes = hex::decode(self.user_id.trim())
+                    .map_err(|e| NfcError::InvalidWriteData(format!("raw hex: {}", e)))?;
+                // Refuse to put something that is not NDEF inside an NDEF TLV
+                ndef::parse_ndef_message(&bytes).map_err(NfcError::InvalidWriteData)?;
+                Ok(bytes)
+            }
+            "multi" => {
+                if self.records.is_empty() {
+                    return Err(NfcError::InvalidWriteData(
+                        "multi write without records".into(),
+                    ));
+                }
+                let records = self
+                    .records
+                    .iter()
+                    .map(|r| build_record(&r.data_type, &r.value, &r.lang, &r.content_type))
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(ndef::encode_ndef_records(&records))
+            }
+            other => {
+                let record = build_record(other, &self.user_id, &self.lang, &self.content_type)?;
+                // user_id defaults to empty for multi writes; a single record needs one
+                if self.user_id.is_empty() {
+                    return Err(NfcError::InvalidWriteData(format!(
+                        "{} write without user_id",
+                        other
+                    )));
+                }
+                Ok(ndef::encode_ndef_records(&[record]))
+            }
+        }
+    }
+
+    // Check a card read back after writing `message`: the NDEF TLV must hold the same
+    // message, and single text/uri records must decode to `user_id` again
+    pub fn check_read_back(&self, raw: &[u8], message: &[u8]) -> Result<(), NfcError> {
+        let stored = ndef::find_ndef_tlv(raw).map_err(NfcError::VerifyFailed)?;
+        if stored != message {
+            return Err(NfcError::VerifyFailed(
+                "NDEF message read back differs".into(),
+            ));
+        }
+        if matches!(self.data_type.as_str(), "text" | "uri") {
+            let content = ndef::decode_ndef_content(raw).map_err(NfcError::VerifyFailed)?;
+            if content != self.user_id {
+                return Err(NfcError::VerifyFailed(format!(
+                    "read back '{}' instead of '{}'",
+                    content, self.user_id
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn build_record(
+    data_type: &str,
+    value: &str,
+    lang: &Option<String>,
+    content_type: &Option<String>,
+) -> Result<NdefRecord, NfcError> {
+    match data_type {
+        "text" => {
+            let lang = lang.as_deref().unwrap_or("en");
+            if lang.is_empty() || lang.len() > 0x3F || !lang.is_ascii() {
+                return Err(NfcError::InvalidWriteData(format!(
+                    "invalid language code '{}'",
+                    lang
+                )));
+            }
+            Ok(NdefRecord::well_known(
+                b"T",
+                ndef::create_text_payload(lang, value),
+            ))
+        }
+        "uri" => Ok(NdefRecord::well_known(
+            b"U",
+            ndef::create_uri_record_payload(value),
+        )),
+        "mime" => match content_type.as_deref() {
+            Some(ct) if !ct.is_empty() && ct.len() <= 0xFF && ct.is_ascii() => {
+                Ok(NdefRecord::mime(ct, value.as_bytes().to_vec()))
+            }
+            _ => Err(NfcError::InvalidWriteData(
+                "mime record needs a content_type".into(),
+            )),
+        },
+        other => Err(NfcError::UnsupportedDataType(other.to_string())),
+    }
+}
+
+// Connection a command came from, and the request_id it carried
+#[derive(Debug, Clone, PartialEq)]
+pub struct Origin {
+    pub client: u64,
+    pub request_id: Option<serde_json::Value>,
+}
+
+// Command queued for the NFC thread
+#[derive(Debug)]
+pub struct NfcRequest {
+    pub origin: Origin,
+    pub cmd: NfcCommand,
+}
+
+// Message from the NFC thread: an answer `to` the connection that sent the
+// command, or (`to` None) a hardware event for every connection
+#[derive(Debug, Clone)]
+pub struct NfcEvent {
+    pub to: Option<Origin>,
+    pub msg: OutgoingMessage,
+}
+
+// Wire form of an answer: the message with the request's `request_id`
+#[derive(Serialize)]
+struct Answer<'a> {
+    #[serde(flatten)]
+    msg: &'a OutgoingMessage,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<&'a serde_json::Value>,
+}
+
+impl NfcEvent {
+    pub fn is_for(&self, client: u64) -> bool {
+        self.to
+            .as_ref()
+            .is_none_or(|origin| origin.client == client)
+    }
+
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string(&Answer {
+            msg: &self.msg,
+            request_id: self
+                .to
+                .as_ref()
+                .and_then(|origin| origin.request_id.as_ref()),
+        })
+    }
 }
 
 // Internal commands sent from WS Server -> NFC Thread
 #[derive(Debug)]
 pub enum NfcCommand {
-    Write { user_id: String },
+    Write { spec: WriteSpec },
+    Read { spec: ReadSpec },
+    SetProtection { spec: ProtectionSpec },
+    Lock { spec: LockSpec },
+    Format,
+    Erase,
+    Dump,
+    Restore { spec: RestoreSpec },
+    CancelWrite,
+    KeyReport,
     CheckReaderStatus,
 }
 
-pub const CARD_TYPE_MIFARE_1K: &str = "6a"; // MIFARE Classic 1K
-pub const CARD_TYPE_NTAG: &str = "68"; // NTAG215/Ultralight
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ndef;
+    use crate::password::ProtectMode;
+    use crate::sim::{TestResult, write_spec};
+
+    #[test]
+    fn write_data_types() -> TestResult {
+        let spec = write_spec(
+            r#"{"type":"WRITE_DATA","data_type":"text","user_id":"Bonjour","lang":"fr"}"#,
+        )?;
+        let records = ndef::parse_ndef_message(&spec.to_ndef_message()?)?;
+        assert_eq!(
+            records[0].decode_text(),
+            Some(("fr".to_string(), "Bonjour".to_string())),
+        );
+
+        let spec = write_spec(
+            r#"{"type":"WRITE_DATA","data_type":"mime","user_id":"{}","content_type":"application/json"}"#,
+        )?;
+        let records = ndef::parse_ndef_message(&spec.to_ndef_message()?)?;
+        assert_eq!(records[0].tnf, ndef::Tnf::MediaType);
+        assert_eq!(records[0].record_type.clone(), b"application/json".to_vec());
+
+        let spec =
+            write_spec(r#"{"type":"WRITE_DATA","data_type":"raw","user_id":"D101045402656E41"}"#)?;
+        assert_eq!(
+            spec.to_ndef_message()?,
+            vec![0xD1, 0x01, 0x04, 0x54, 0x02, 0x65, 0x6E, 0x41]
+        );
+
+        let spec = write_spec(
+            r#"{"type":"WRITE_DATA","data_type":"multi","records":[
+                {"data_type":"uri","value":"https://example.com/u/7"},
+                {"data_type":"text","value":"EMP-7"}
+            ]}"#,
+        )?;
+        let tlv = ndef::wrap_in_tlv(&spec.to_ndef_message()?);
+        let records = ndef::parse_ndef_message(ndef::find_ndef_tlv(&tlv)?)?;
+        assert_eq!(records.len(), 2);
+        assert_eq!(
+            records[0].decode_uri(),
+            Some("https://example.com/u/7".to_string())
+        );
+        assert_eq!(
+            records[1].decode_text(),
+            Some(("en".to_string(), "EMP-7".to_string()))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn write_data_rejects() -> TestResult {
+        let cases = [
+            (
+                r#"{"type":"WRITE_DATA","data_type":"vcard","user_id":"x"}"#,
+                "UNSUPPORTED_DATA_TYPE",
+            ),
+            (
+                r#"{"type":"WRITE_DATA","data_type":"mime","user_id":"x"}"#,
+                "INVALID_WRITE_DATA",
+            ),
+            (
+                r#"{"type":"WRITE_DATA","data_type":"raw","user_id":"zz"}"#,
+                "INVALID_WRITE_DATA",
+            ),
+            (
+                r#"{"type":"WRITE_DATA","data_type":"raw","user_id":"0102"}"#,
+                "INVALID_WRITE_DATA",
+            ),
+            (
+                r#"{"type":"WRITE_DATA","data_type":"multi"}"#,
+                "INVALID_WRITE_DATA",
+            ),
+            (
+                r#"{"type":"WRITE_DATA","data_type":"text"}"#,
+                "INVALID_WRITE_DATA",
+            ),
+            (
+                r#"{"type":"WRITE_DATA","data_type":"uri","user_id":""}"#,
+                "INVALID_WRITE_DATA",
+            ),
+            (
+                r#"{"type":"WRITE_DATA","data_type":"mime","content_type":"text/plain"}"#,
+                "INVALID_WRITE_DATA",
+            ),
+            (
+                r#"{"type":"WRITE_DATA","data_type":"multi","records":[{"data_type":"raw","value":"00"}]}"#,
+                "UNSUPPORTED_DATA_TYPE",
+            ),
+        ];
+        for (json, code) in cases {
+            match write_spec(json)?.to_ndef_message() {
+                Ok(_) => return Err(format!("accepted {}", json).into()),
+                Err(e) => assert_eq!(e.code(), code),
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn request_id_routing() -> TestResult {
+        let parsed: ClientMessage =
+            serde_json::from_str(r#"{"type":"GET_READER_STATUS","request_id":"tab-2/17"}"#)?;
+        assert_eq!(parsed.request_id, Some(serde_json::json!("tab-2/17")));
+        assert!(matches!(
+            parsed.msg.into_command(),
+            NfcCommand::CheckReaderStatus
+        ));
+        // Payload fields come through next to it, whatever JSON type the id is
+        let parsed: ClientMessage = serde_json::from_str(
+            r#"{"type":"SET_TAG_PROTECTION","mode":"write","start_page":16,"request_id":42}"#,
+        )?;
+        assert_eq!(parsed.request_id, Some(serde_json::json!(42)));
+        let NfcCommand::SetProtection { spec } = parsed.msg.into_command() else {
+            return Err("SET_TAG_PROTECTION not parsed".into());
+        };
+        assert_eq!((spec.mode, spec.start_page), (ProtectMode::Write, 16));
+        let parsed: ClientMessage = serde_json::from_str(r#"{"type":"FORMAT_CARD"}"#)?;
+        assert_eq!(parsed.request_id, None);
+        let parsed: ClientMessage =
+            serde_json::from_str(r#"{"type":"GET_KEY_REPORT","request_id":3}"#)?;
+        assert!(matches!(parsed.msg.into_command(), NfcCommand::KeyReport));
+
+        // Answers go to the asking connection only, with its request_id
+        let answer = NfcEvent {
+            to: Some(Origin {
+                client: 7,
+                request_id: Some(serde_json::json!("tab-2/17")),
+            }),
+            msg: OutgoingMessage::DATA_WRITE_SUCCESS {
+                message: "written".into(),
+            },
+        };
+        assert_eq!((answer.is_for(7), answer.is_for(8)), (true, false));
+        let json: serde_json::Value = serde_json::from_str(&answer.to_json()?)?;
+        assert_eq!(json["type"].as_str(), Some("DATA_WRITE_SUCCESS"));
+        assert_eq!(json["request_id"].as_str(), Some("tab-2/17"));
+        assert_eq!(json["message"].as_str(), Some("written"));
+
+        // Hardware events reach everyone, unchanged
+        let event = NfcEvent {
+            to: None,
+            msg: OutgoingMessage::READER_STATUS { success: true },
+        };
+        assert_eq!((event.is_for(7), event.is_for(8)), (true, true));
+        assert_eq!(
+            event.to_json()?,
+            r#"{"type":"READER_STATUS","success":true}"#.to_string()
+        );
+        // A command without a request_id is still answered privately
+        let answer = NfcEvent {
+            to: Some(Origin {
+                client: 7,
+                request_id: None,
+            }),
+            ..event
+        };
+        assert_eq!((answer.is_for(7), answer.is_for(8)), (true, false));
+        assert_eq!(
+            answer.to_json()?,
+            r#"{"type":"READER_STATUS","success":true}"#.to_string()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn armed_write_options() -> TestResult {
+        let spec = write_spec(r#"{"type":"WRITE_DATA","data_type":"text","user_id":"EMP-9"}"#)?;
+        assert!(!spec.arm);
+        let spec =
+            write_spec(r#"{"type":"WRITE_DATA","data_type":"text","user_id":"EMP-9","arm":true}"#)?;
+        assert_eq!(
+            (spec.arm, spec.arm_timeout()),
+            (true, WriteSpec::DEFAULT_ARM_TIMEOUT)
+        );
+        let spec = write_spec(
+            r#"{"type":"WRITE_DATA","data_type":"text","user_id":"EMP-9","arm":true,"timeout_secs":10}"#,
+        )?;
+        assert_eq!(spec.arm_timeout(), std::time::Duration::from_secs(10));
+        let spec = write_spec(
+            r#"{"type":"WRITE_DATA","data_type":"text","user_id":"EMP-9","arm":true,"timeout_secs":86400}"#,
+        )?;
+        assert_eq!(spec.arm_timeout(), WriteSpec::MAX_ARM_TIMEOUT);
+
+        let parsed: ClientMessage =
+            serde_json::from_str(r#"{"type":"CANCEL_WRITE","request_id":"cancel-1"}"#)?;
+        assert!(matches!(parsed.msg.into_command(), NfcCommand::CancelWrite));
+        let msg = OutgoingMessage::WRITE_PROGRESS {
+            stage: WriteStage::CardDetected,
+            uid: Some("04A1B2C3D4E5F6".into()),
+            card_present: None,
+        };
+        assert_eq!(
+            serde_json::to_string(&msg)?,
+            r#"{"type":"WRITE_PROGRESS","stage":"card_detected","uid":"04A1B2C3D4E5F6"}"#
+                .to_string(),
+        );
+        let msg = OutgoingMessage::WRITE_CANCELLED { cancelled: false };
+        assert_eq!(
+            serde_json::to_string(&msg)?,
+            r#"{"type":"WRITE_CANCELLED","cancelled":false}"#.to_string()
+        );
+        Ok(())
+    }
+}
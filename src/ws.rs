@@ -1,20 +1,24 @@
-// src/ws_server.rs
-use crate::types::{IncomingMessage, NfcCommand, OutgoingMessage};
+// src/ws.rs
+use crate::types::{ClientMessage, NfcEvent, NfcRequest, Origin};
 use crossbeam_channel::Sender;
 use futures::{SinkExt, StreamExt};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 use tokio::sync::broadcast;
 use warp::Filter;
 
+// Connection ids, so answers go back only to the connection that asked
+static NEXT_CLIENT: AtomicU64 = AtomicU64::new(1);
+
 pub async fn start_server(
-    nfc_cmd_tx: Sender<NfcCommand>,
-    mut nfc_event_rx: tokio::sync::broadcast::Receiver<OutgoingMessage>,
+    nfc_cmd_tx: Sender<NfcRequest>,
+    mut nfc_event_rx: tokio::sync::broadcast::Receiver<NfcEvent>,
 ) {
     // Shared Broadcast Channel for WS Clients
-    let (ws_tx, _) = broadcast::channel::<OutgoingMessage>(32);
+    let (ws_tx, _) = broadcast::channel::<NfcEvent>(32);
     let ws_tx = Arc::new(ws_tx);
 
-    // 1. Task to forward NFC Events -> All WS Clients
+    // 1. Task to forward NFC Events -> All WS Clients (each keeps its own answers)
     let ws_tx_clone = ws_tx.clone();
     tokio::spawn(async move {
         while let Ok(msg) = nfc_event_rx.recv().await {
@@ -43,16 +47,20 @@ pub async fn start_server(
 
 async fn handle_connection(
     ws: warp::ws::WebSocket,
-    nfc_cmd_tx: Sender<NfcCommand>,
-    ws_tx: Arc<broadcast::Sender<OutgoingMessage>>,
+    nfc_cmd_tx: Sender<NfcRequest>,
+    ws_tx: Arc<broadcast::Sender<NfcEvent>>,
 ) {
+    let client = NEXT_CLIENT.fetch_add(1, Ordering::Relaxed);
     let (mut client_ws_tx, mut client_ws_rx) = ws.split();
     let mut rx_broadcast = ws_tx.subscribe();
 
     // Spawn task to send Broadcasts -> Client
     tokio::spawn(async move {
-        while let Ok(msg) = rx_broadcast.recv().await {
-            let json = serde_json::to_string(&msg).unwrap();
+        while let Ok(event) = rx_broadcast.recv().await {
+            if !event.is_for(client) {
+                continue;
+            }
+            let json = event.to_json().unwrap();
             if client_ws_tx
                 .send(warp::ws::Message::text(json))
                 .await
@@ -65,21 +73,18 @@ async fn handle_connection(
 
     // Handle incoming messages from Client
     while let Some(result) = client_ws_rx.next().await {
-        if let Ok(msg) = result {
-            if msg.is_text() {
-                if let Ok(text) = msg.to_str() {
-                    if let Ok(parsed) = serde_json::from_str::<IncomingMessage>(text) {
-                        match parsed {
-                            IncomingMessage::GET_READER_STATUS => {
-                                let _ = nfc_cmd_tx.send(NfcCommand::CheckReaderStatus);
-                            }
-                            IncomingMessage::WRITE_DATA { user_id, .. } => {
-                                let _ = nfc_cmd_tx.send(NfcCommand::Write { user_id });
-                            }
-                        }
-                    }
-                }
-            }
+        if let Ok(msg) = result
+            && msg.is_text()
+            && let Ok(text) = msg.to_str()
+            && let Ok(parsed) = serde_json::from_str::<ClientMessage>(text)
+        {
+            let _ = nfc_cmd_tx.send(NfcRequest {
+                origin: Origin {
+                    client,
+                    request_id: parsed.request_id,
+                },
+                cmd: parsed.msg.into_command(),
+            });
         }
     }
 }